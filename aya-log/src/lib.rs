@@ -120,7 +120,7 @@ impl BpfLogger {
                 let mut buffers = vec![BytesMut::with_capacity(LOG_BUF_CAPACITY); 10];
 
                 loop {
-                    let Events { read, lost: _ } = buf.read_events(&mut buffers).await.unwrap();
+                    let Events { read, .. } = buf.read_events(&mut buffers).await.unwrap();
 
                     for buf in buffers.iter().take(read) {
                         log_buf(buf.as_ref(), &*log).unwrap();
@@ -11,3 +11,6 @@
 int AYA_PERF_EVENT_IOC_ENABLE = PERF_EVENT_IOC_ENABLE;
 int AYA_PERF_EVENT_IOC_DISABLE = PERF_EVENT_IOC_DISABLE;
 int AYA_PERF_EVENT_IOC_SET_BPF = PERF_EVENT_IOC_SET_BPF;
+int AYA_PERF_EVENT_IOC_REFRESH = PERF_EVENT_IOC_REFRESH;
+int AYA_PERF_EVENT_IOC_RESET = PERF_EVENT_IOC_RESET;
+int AYA_PERF_EVENT_IOC_ID = PERF_EVENT_IOC_ID;
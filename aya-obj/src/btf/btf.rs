@@ -235,6 +235,15 @@ impl BtfFeatures {
     }
 }
 
+/// The layout of a BTF struct, returned by [`Btf::struct_layout`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StructLayout {
+    /// The size of the struct, in bytes.
+    pub size: usize,
+    /// The name and byte offset of each member.
+    pub members: Vec<(String, usize)>,
+}
+
 /// Bpf Type Format metadata.
 ///
 /// BTF is a kind of debug metadata that allows eBPF programs compiled against one kernel version
@@ -433,6 +442,29 @@ impl Btf {
         })
     }
 
+    /// Returns the layout of the struct with the given name.
+    ///
+    /// Bitfield and anonymous members aren't included in [`StructLayout::members`].
+    pub fn struct_layout(&self, name: &str) -> Result<StructLayout, BtfError> {
+        let type_id = self.id_by_type_name_kind(name, BtfKind::Struct)?;
+        let s = match self.type_by_id(type_id)? {
+            BtfType::Struct(s) => s,
+            _ => return Err(BtfError::UnexpectedBtfType { type_id }),
+        };
+        let mut members = Vec::new();
+        for member in &s.members {
+            let name = self.string_at(member.name_offset)?;
+            if name.is_empty() || s.member_bit_field_size(member) != 0 {
+                continue;
+            }
+            members.push((name.into_owned(), s.member_bit_offset(member) / 8));
+        }
+        Ok(StructLayout {
+            size: s.size as usize,
+            members,
+        })
+    }
+
     pub(crate) fn type_size(&self, root_type_id: u32) -> Result<usize, BtfError> {
         let mut type_id = root_type_id;
         let mut n_elems = 1;
@@ -1613,6 +1645,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_struct_layout() {
+        let mut btf = Btf::new();
+        let int_name = btf.add_string("int");
+        let int_type_id = btf.add_type(BtfType::Int(Int::new(int_name, 4, IntEncoding::Signed, 0)));
+        let a = btf.add_string("a");
+        let b = btf.add_string("b");
+        let name_offset = btf.add_string("event");
+        btf.add_type(BtfType::Struct(Struct::new(
+            name_offset,
+            vec![
+                BtfMember {
+                    name_offset: a,
+                    btf_type: int_type_id,
+                    offset: 0,
+                },
+                BtfMember {
+                    name_offset: b,
+                    btf_type: int_type_id,
+                    offset: 64,
+                },
+            ],
+            12,
+        )));
+
+        assert_eq!(
+            btf.struct_layout("event").unwrap(),
+            StructLayout {
+                size: 12,
+                members: vec![("a".to_owned(), 0), ("b".to_owned(), 8)],
+            }
+        );
+        assert_matches!(
+            btf.struct_layout("missing"),
+            Err(BtfError::UnknownBtfTypeName { .. })
+        );
+    }
+
     #[test]
     fn test_sanitize_float() {
         let mut btf = Btf::new();
@@ -2187,3 +2187,6 @@ pub type _bindgen_ty_159 = ::core::ffi::c_uint;
 pub const AYA_PERF_EVENT_IOC_ENABLE: ::core::ffi::c_int = 9216;
 pub const AYA_PERF_EVENT_IOC_DISABLE: ::core::ffi::c_int = 9217;
 pub const AYA_PERF_EVENT_IOC_SET_BPF: ::core::ffi::c_int = 1074013192;
+pub const AYA_PERF_EVENT_IOC_REFRESH: ::core::ffi::c_int = 9218;
+pub const AYA_PERF_EVENT_IOC_RESET: ::core::ffi::c_int = 9219;
+pub const AYA_PERF_EVENT_IOC_ID: ::core::ffi::c_int = -2146950137;
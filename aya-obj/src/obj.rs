@@ -47,6 +47,8 @@ pub struct Features {
     bpf_cookie: bool,
     cpumap_prog_id: bool,
     devmap_prog_id: bool,
+    ring_buf: bool,
+    map_batch: bool,
     btf: Option<BtfFeatures>,
 }
 
@@ -61,6 +63,8 @@ impl Features {
         bpf_cookie: bool,
         cpumap_prog_id: bool,
         devmap_prog_id: bool,
+        ring_buf: bool,
+        map_batch: bool,
         btf: Option<BtfFeatures>,
     ) -> Self {
         Self {
@@ -71,6 +75,8 @@ impl Features {
             bpf_cookie,
             cpumap_prog_id,
             devmap_prog_id,
+            ring_buf,
+            map_batch,
             btf,
         }
     }
@@ -110,6 +116,16 @@ impl Features {
         self.devmap_prog_id
     }
 
+    /// Returns whether ring buffer maps are supported.
+    pub fn ring_buf(&self) -> bool {
+        self.ring_buf
+    }
+
+    /// Returns whether batched map operations, like `BPF_MAP_LOOKUP_BATCH`, are supported.
+    pub fn map_batch(&self) -> bool {
+        self.map_batch
+    }
+
     /// If BTF is supported, returns which BTF features are supported.
     pub fn btf(&self) -> Option<&BtfFeatures> {
         self.btf.as_ref()
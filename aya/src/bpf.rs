@@ -22,6 +22,7 @@ use thiserror::Error;
 use crate::{
     generated::{
         bpf_map_type, bpf_map_type::*, AYA_PERF_EVENT_IOC_DISABLE, AYA_PERF_EVENT_IOC_ENABLE,
+        AYA_PERF_EVENT_IOC_ID, AYA_PERF_EVENT_IOC_REFRESH, AYA_PERF_EVENT_IOC_RESET,
         AYA_PERF_EVENT_IOC_SET_BPF,
     },
     maps::{Map, MapData, MapError},
@@ -39,9 +40,9 @@ use crate::{
         bpf_load_btf, is_bpf_cookie_supported, is_bpf_global_data_supported,
         is_btf_datasec_supported, is_btf_decl_tag_supported, is_btf_enum64_supported,
         is_btf_float_supported, is_btf_func_global_supported, is_btf_func_supported,
-        is_btf_supported, is_btf_type_tag_supported, is_perf_link_supported,
-        is_probe_read_kernel_supported, is_prog_id_supported, is_prog_name_supported,
-        retry_with_verifier_logs,
+        is_btf_supported, is_btf_type_tag_supported, is_map_batch_supported,
+        is_perf_link_supported, is_probe_read_kernel_supported, is_prog_id_supported,
+        is_prog_name_supported, is_ring_buf_supported, retry_with_verifier_logs,
     },
     util::{bytes_of, bytes_of_slice, page_size, possible_cpus, POSSIBLE_CPUS},
 };
@@ -51,6 +52,9 @@ pub(crate) const BPF_OBJ_NAME_LEN: usize = 16;
 pub(crate) const PERF_EVENT_IOC_ENABLE: c_int = AYA_PERF_EVENT_IOC_ENABLE;
 pub(crate) const PERF_EVENT_IOC_DISABLE: c_int = AYA_PERF_EVENT_IOC_DISABLE;
 pub(crate) const PERF_EVENT_IOC_SET_BPF: c_int = AYA_PERF_EVENT_IOC_SET_BPF;
+pub(crate) const PERF_EVENT_IOC_REFRESH: c_int = AYA_PERF_EVENT_IOC_REFRESH;
+pub(crate) const PERF_EVENT_IOC_RESET: c_int = AYA_PERF_EVENT_IOC_RESET;
+pub(crate) const PERF_EVENT_IOC_ID: c_int = AYA_PERF_EVENT_IOC_ID;
 
 /// Marker trait for types that can safely be converted to and from byte slices.
 pub unsafe trait Pod: Copy + 'static {}
@@ -96,6 +100,8 @@ fn detect_features() -> Features {
         is_bpf_cookie_supported(),
         is_prog_id_supported(BPF_MAP_TYPE_CPUMAP),
         is_prog_id_supported(BPF_MAP_TYPE_DEVMAP),
+        is_ring_buf_supported(),
+        is_map_batch_supported(),
         btf,
     );
     debug!("BPF Feature Detection: {:#?}", f);
@@ -103,6 +109,17 @@ fn detect_features() -> Features {
 }
 
 /// Returns a reference to the detected BPF features.
+///
+/// The features are probed once, by loading tiny programs and creating tiny maps, and the
+/// results are cached for the lifetime of the process.
+///
+/// # Examples
+///
+/// ```no_run
+/// if !aya::features().ring_buf() {
+///     eprintln!("ring buffers need linux 5.8 or later, falling back to perf buffers");
+/// }
+/// ```
 pub fn features() -> &'static Features {
     &FEATURES
 }
@@ -711,33 +728,11 @@ impl<'a> BpfLoader<'a> {
 
 fn parse_map(data: (String, MapData)) -> Result<(String, Map), BpfError> {
     let (name, map) = data;
-    let map_type = bpf_map_type::try_from(map.obj().map_type()).map_err(MapError::from)?;
-    let map = match map_type {
-        BPF_MAP_TYPE_ARRAY => Map::Array(map),
-        BPF_MAP_TYPE_PERCPU_ARRAY => Map::PerCpuArray(map),
-        BPF_MAP_TYPE_PROG_ARRAY => Map::ProgramArray(map),
-        BPF_MAP_TYPE_HASH => Map::HashMap(map),
-        BPF_MAP_TYPE_LRU_HASH => Map::LruHashMap(map),
-        BPF_MAP_TYPE_PERCPU_HASH => Map::PerCpuHashMap(map),
-        BPF_MAP_TYPE_LRU_PERCPU_HASH => Map::PerCpuLruHashMap(map),
-        BPF_MAP_TYPE_PERF_EVENT_ARRAY => Map::PerfEventArray(map),
-        BPF_MAP_TYPE_RINGBUF => Map::RingBuf(map),
-        BPF_MAP_TYPE_SOCKHASH => Map::SockHash(map),
-        BPF_MAP_TYPE_SOCKMAP => Map::SockMap(map),
-        BPF_MAP_TYPE_BLOOM_FILTER => Map::BloomFilter(map),
-        BPF_MAP_TYPE_LPM_TRIE => Map::LpmTrie(map),
-        BPF_MAP_TYPE_STACK => Map::Stack(map),
-        BPF_MAP_TYPE_STACK_TRACE => Map::StackTraceMap(map),
-        BPF_MAP_TYPE_QUEUE => Map::Queue(map),
-        BPF_MAP_TYPE_CPUMAP => Map::CpuMap(map),
-        BPF_MAP_TYPE_DEVMAP => Map::DevMap(map),
-        BPF_MAP_TYPE_DEVMAP_HASH => Map::DevMapHash(map),
-        BPF_MAP_TYPE_XSKMAP => Map::XskMap(map),
-        m => {
-            warn!("The map {name} is of type {:#?} which is currently unsupported in Aya, use `allow_unsupported_maps()` to load it anyways", m);
-            Map::Unsupported(map)
-        }
-    };
+    let map = Map::from_map_data(map)?;
+    if let Map::Unsupported(map) = &map {
+        let map_type = bpf_map_type::try_from(map.obj().map_type()).map_err(MapError::from)?;
+        warn!("The map {name} is of type {:#?} which is currently unsupported in Aya, use `allow_unsupported_maps()` to load it anyways", map_type);
+    }
 
     Ok((name, map))
 }
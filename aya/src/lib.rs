@@ -94,3 +94,4 @@ pub use obj::btf::{Btf, BtfError};
 pub use object::Endianness;
 #[doc(hidden)]
 pub use sys::netlink_set_link_up;
+pub use sys::{set_syscall_backend, SyscallBackend};
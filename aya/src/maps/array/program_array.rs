@@ -2,9 +2,12 @@
 
 use std::{
     borrow::{Borrow, BorrowMut},
+    io,
     os::fd::{AsFd as _, AsRawFd as _, RawFd},
 };
 
+use libc::EINVAL;
+
 use crate::{
     maps::{check_bounds, check_kv_size, MapData, MapError, MapKeys},
     programs::ProgramFd,
@@ -71,6 +74,14 @@ impl<T: BorrowMut<MapData>> ProgramArray<T> {
     ///
     /// When an eBPF program calls `bpf_tail_call(ctx, prog_array, index)`, control
     /// flow will jump to `program`.
+    ///
+    /// # Errors
+    ///
+    /// All the programs of an array must have the same type, and be compatible with the
+    /// programs that use the array. The kernel rejects other programs with `EINVAL`, in which
+    /// case [`MapError::ProgramTypeMismatch`] is returned. `EINVAL` is the only signal the kernel
+    /// gives, and it's returned for flags other than `BPF_ANY` (0) too, so the error is only
+    /// reported when `flags` is 0.
     pub fn set(&mut self, index: u32, program: &ProgramFd, flags: u64) -> Result<(), MapError> {
         let data = self.inner.borrow_mut();
         check_bounds(data, index)?;
@@ -78,12 +89,8 @@ impl<T: BorrowMut<MapData>> ProgramArray<T> {
         let prog_fd = program.as_fd();
         let prog_fd = prog_fd.as_raw_fd();
 
-        bpf_map_update_elem(fd, Some(&index), &prog_fd, flags).map_err(|(_, io_error)| {
-            SyscallError {
-                call: "bpf_map_update_elem",
-                io_error,
-            }
-        })?;
+        bpf_map_update_elem(fd, Some(&index), &prog_fd, flags)
+            .map_err(|(_, io_error)| set_error(index, flags, io_error))?;
         Ok(())
     }
 
@@ -107,3 +114,112 @@ impl<T: BorrowMut<MapData>> ProgramArray<T> {
             })
     }
 }
+
+// Program arrays only accept BPF_ANY, and return EINVAL for anything else. They return EINVAL
+// for programs that don't match the array too, which can only be told apart when the flags are
+// valid.
+fn set_error(index: u32, flags: u64, io_error: io::Error) -> MapError {
+    if flags == 0 && io_error.raw_os_error() == Some(EINVAL) {
+        MapError::ProgramTypeMismatch { index, io_error }
+    } else {
+        SyscallError {
+            call: "bpf_map_update_elem",
+            io_error,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use assert_matches::assert_matches;
+    use libc::EPERM;
+
+    use super::*;
+    use crate::{
+        bpf_map_def,
+        generated::{bpf_cmd, bpf_map_type::BPF_MAP_TYPE_PROG_ARRAY},
+        obj::{self, maps::LegacyMap, BpfSectionKind},
+        sys::{override_syscall, Syscall},
+    };
+
+    fn new_map() -> MapData {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_CREATE,
+                ..
+            } => Ok(1337),
+            call => panic!("unexpected syscall {:?}", call),
+        });
+        MapData::create(
+            obj::Map::Legacy(LegacyMap {
+                def: bpf_map_def {
+                    map_type: BPF_MAP_TYPE_PROG_ARRAY as u32,
+                    key_size: 4,
+                    value_size: 4,
+                    max_entries: 4,
+                    ..Default::default()
+                },
+                section_index: 0,
+                section_kind: BpfSectionKind::Maps,
+                data: Vec::new(),
+                symbol_index: None,
+            }),
+            "foo",
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_set_program_type_mismatch() {
+        let mut map = new_map();
+        let mut prog_array = ProgramArray::new(&mut map).unwrap();
+        let program = ProgramFd(File::open("/dev/null").unwrap().into());
+
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                ..
+            } => Err((-1, io::Error::from_raw_os_error(EINVAL))),
+            call => panic!("unexpected syscall {:?}", call),
+        });
+        assert_matches!(
+            prog_array.set(1, &program, 0),
+            Err(MapError::ProgramTypeMismatch { index: 1, .. })
+        );
+        // EINVAL is returned for invalid flags too
+        assert_matches!(
+            prog_array.set(1, &program, 4),
+            Err(MapError::SyscallError(SyscallError {
+                call: "bpf_map_update_elem",
+                ..
+            }))
+        );
+    }
+
+    #[test]
+    fn test_set_error() {
+        assert_matches!(
+            set_error(1, 0, io::Error::from_raw_os_error(EINVAL)),
+            MapError::ProgramTypeMismatch { index: 1, .. }
+        );
+        // invalid flags
+        assert_matches!(
+            set_error(1, 4, io::Error::from_raw_os_error(EINVAL)),
+            MapError::SyscallError(SyscallError {
+                call: "bpf_map_update_elem",
+                ..
+            })
+        );
+        assert_matches!(
+            set_error(1, 0, io::Error::from_raw_os_error(EPERM)),
+            MapError::SyscallError(SyscallError {
+                call: "bpf_map_update_elem",
+                ..
+            })
+        );
+    }
+}
@@ -7,7 +7,7 @@ use std::{
 use crate::{
     maps::{check_kv_size, hash_map, IterableMap, MapData, MapError, MapIter, MapKeys},
     sys::{bpf_map_lookup_elem, SyscallError},
-    Pod,
+    Pod, FEATURES,
 };
 
 /// A hash map that can be shared between eBPF programs and user space.
@@ -67,6 +67,24 @@ impl<T: Borrow<MapData>, K: Pod, V: Pod> HashMap<T, K, V> {
         MapIter::new(self)
     }
 
+    /// Returns a copy of all the key-value pairs, in arbitrary order.
+    ///
+    /// The entries are read `batch_size` at a time with `BPF_MAP_LOOKUP_BATCH`, which is much
+    /// faster than [`iter`](Self::iter) for large maps. On kernels older than 5.6, which don't
+    /// support batched lookups, this falls back to reading the entries one at a time.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MapError::SyscallError`] if a lookup fails. A bucket of the hash map holding
+    /// more than `batch_size` entries can't be read in one batch, so the batch is grown for it.
+    pub fn get_batch(&self, batch_size: usize) -> Result<Vec<(K, V)>, MapError> {
+        if FEATURES.map_batch() {
+            hash_map::get_batch(self.inner.borrow(), batch_size)
+        } else {
+            self.iter().collect()
+        }
+    }
+
     /// An iterator visiting all keys in arbitrary order. The iterator element
     /// type is `Result<K, MapError>`.
     pub fn keys(&self) -> MapKeys<'_, K> {
@@ -85,6 +103,22 @@ impl<T: BorrowMut<MapData>, K: Pod, V: Pod> HashMap<T, K, V> {
         hash_map::insert(self.inner.borrow_mut(), key.borrow(), value.borrow(), flags)
     }
 
+    /// Inserts key-value pairs into the map.
+    ///
+    /// The entries are inserted with a single `BPF_MAP_UPDATE_BATCH` syscall. On kernels older
+    /// than 5.6, which don't support batched updates, this falls back to inserting the entries
+    /// one at a time.
+    pub fn insert_batch(&mut self, entries: &[(K, V)], flags: u64) -> Result<(), MapError> {
+        let map = self.inner.borrow_mut();
+        if FEATURES.map_batch() {
+            hash_map::insert_batch(map, entries, flags)
+        } else {
+            entries
+                .iter()
+                .try_for_each(|(key, value)| hash_map::insert(map, key, value, flags))
+        }
+    }
+
     /// Removes a key from the map.
     pub fn remove(&mut self, key: &K) -> Result<(), MapError> {
         hash_map::remove(self.inner.borrow_mut(), key)
@@ -106,7 +140,7 @@ mod tests {
     use std::{ffi::c_long, io};
 
     use assert_matches::assert_matches;
-    use libc::{EFAULT, ENOENT};
+    use libc::{EFAULT, ENOENT, ENOSPC};
 
     use super::{
         super::test_utils::{self, new_map},
@@ -557,4 +591,160 @@ mod tests {
         assert_matches!(iter.next(), Some(Ok((30, 300))));
         assert_matches!(iter.next(), None);
     }
+
+    fn lookup_batch(attr: &mut bpf_attr) -> SysResult<c_long> {
+        let u = unsafe { &mut attr.batch };
+        assert_eq!(u.count, 2);
+        let keys = u.keys as *mut u32;
+        let values = u.values as *mut u32;
+        let in_batch = match u.in_batch as *const u32 {
+            p if p.is_null() => 0,
+            p => unsafe { *p },
+        };
+        let entries: &[(u32, u32)] = match in_batch {
+            0 => &[(10, 100), (20, 200)],
+            1 => &[(30, 300)],
+            _ => return sys_error(EFAULT),
+        };
+        for (i, (key, value)) in entries.iter().enumerate() {
+            unsafe {
+                *keys.add(i) = *key;
+                *values.add(i) = *value;
+            }
+        }
+        u.count = entries.len() as u32;
+        unsafe { *(u.out_batch as *mut u32) = in_batch + 1 };
+
+        if in_batch == 1 {
+            sys_error(ENOENT)
+        } else {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    // Syscall overrides are performing integer-to-pointer conversions, which
+    // should be done with `ptr::from_exposed_addr` in Rust nightly, but we have
+    // to support stable as well.
+    #[cfg_attr(miri, ignore)]
+    fn test_get_batch() {
+        let map = new_map(new_obj_map());
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => lookup_batch(attr),
+            _ => sys_error(EFAULT),
+        });
+
+        let items = hash_map::get_batch::<u32, u32>(&map, 2).unwrap();
+        assert_eq!(&items, &[(10, 100), (20, 200), (30, 300)])
+    }
+
+    #[test]
+    // Syscall overrides are performing integer-to-pointer conversions, which
+    // should be done with `ptr::from_exposed_addr` in Rust nightly, but we have
+    // to support stable as well.
+    #[cfg_attr(miri, ignore)]
+    fn test_get_batch_large_bucket() {
+        let map = new_map(new_obj_map());
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => {
+                // all the entries are in the same bucket
+                let u = unsafe { &mut attr.batch };
+                if u.count < 3 {
+                    return sys_error(ENOSPC);
+                }
+                assert_eq!(u.count, 4);
+                let keys = u.keys as *mut u32;
+                let values = u.values as *mut u32;
+                for (i, (key, value)) in [(10, 100), (20, 200), (30, 300)].iter().enumerate() {
+                    unsafe {
+                        *keys.add(i) = *key;
+                        *values.add(i) = *value;
+                    }
+                }
+                u.count = 3;
+                sys_error(ENOENT)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let items = hash_map::get_batch::<u32, u32>(&map, 1).unwrap();
+        assert_eq!(&items, &[(10, 100), (20, 200), (30, 300)])
+    }
+
+    #[test]
+    // Syscall overrides are performing integer-to-pointer conversions, which
+    // should be done with `ptr::from_exposed_addr` in Rust nightly, but we have
+    // to support stable as well.
+    #[cfg_attr(miri, ignore)]
+    fn test_hash_map_get_batch() {
+        let map = new_map(new_obj_map());
+        // the batched lookups and the fallback return the same entries, whichever the feature
+        // detection picks
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => lookup_batch(attr),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_GET_NEXT_KEY,
+                attr,
+            } => get_next_key(attr),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_ELEM,
+                attr,
+            } => lookup_elem(attr),
+            _ => sys_error(EFAULT),
+        });
+        let hm = HashMap::<_, u32, u32>::new(&map).unwrap();
+
+        let items = hm.get_batch(2).unwrap();
+        assert_eq!(&items, &[(10, 100), (20, 200), (30, 300)])
+    }
+
+    #[test]
+    // Syscall overrides are performing integer-to-pointer conversions, which
+    // should be done with `ptr::from_exposed_addr` in Rust nightly, but we have
+    // to support stable as well.
+    #[cfg_attr(miri, ignore)]
+    fn test_insert_batch() {
+        let map = new_map(new_obj_map());
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_BATCH,
+                attr,
+            } => {
+                let u = unsafe { &attr.batch };
+                let count = u.count as usize;
+                let keys = unsafe { std::slice::from_raw_parts(u.keys as *const u32, count) };
+                let values = unsafe { std::slice::from_raw_parts(u.values as *const u32, count) };
+                assert_eq!((keys, values), (&[1, 2][..], &[42, 43][..]));
+                Ok(0)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        assert!(hash_map::insert_batch::<u32, u32>(&map, &[(1, 42), (2, 43)], 0).is_ok());
+    }
+
+    #[test]
+    fn test_hash_map_insert_batch() {
+        let mut map = new_map(new_obj_map());
+        // like for get_batch, the batched update and the fallback both succeed
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_BATCH | bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                ..
+            } => Ok(0),
+            _ => sys_error(EFAULT),
+        });
+        let mut hm = HashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.insert_batch(&[(1, 42), (2, 43)], 0).is_ok());
+    }
 }
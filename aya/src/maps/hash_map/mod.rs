@@ -1,9 +1,14 @@
 //! Hash map types.
 use std::os::fd::AsFd as _;
 
+use libc::ENOSPC;
+
 use crate::{
     maps::MapError,
-    sys::{bpf_map_delete_elem, bpf_map_update_elem, SyscallError},
+    sys::{
+        bpf_map_delete_elem, bpf_map_lookup_batch, bpf_map_update_batch, bpf_map_update_elem,
+        SyscallError,
+    },
     Pod,
 };
 
@@ -31,6 +36,73 @@ pub(crate) fn insert<K: Pod, V: Pod>(
     Ok(())
 }
 
+pub(crate) fn insert_batch<K: Pod, V: Pod>(
+    map: &MapData,
+    entries: &[(K, V)],
+    flags: u64,
+) -> Result<(), MapError> {
+    let fd = map.fd().as_fd();
+    let (keys, values): (Vec<_>, Vec<_>) = entries.iter().copied().unzip();
+    bpf_map_update_batch(fd, &keys, &values, flags).map_err(|(_, io_error)| SyscallError {
+        call: "bpf_map_update_batch",
+        io_error,
+    })?;
+
+    Ok(())
+}
+
+pub(crate) fn get_batch<K: Pod, V: Pod>(
+    map: &MapData,
+    batch_size: usize,
+) -> Result<Vec<(K, V)>, MapError> {
+    let fd = map.fd().as_fd();
+    let max_entries = map.obj.max_entries() as usize;
+    // a batch of 0 entries would never reach the end of the map
+    let mut batch_size = batch_size.max(1);
+    let mut keys = Vec::with_capacity(batch_size);
+    let mut values = Vec::with_capacity(batch_size);
+    let mut entries = Vec::new();
+    // hash maps use the index of the next bucket to read as batch token
+    let mut in_batch = None;
+    loop {
+        let mut out_batch = 0u32;
+        let result = bpf_map_lookup_batch(
+            fd,
+            in_batch.as_ref(),
+            &mut out_batch,
+            &mut keys.spare_capacity_mut()[..batch_size],
+            &mut values.spare_capacity_mut()[..batch_size],
+            0,
+        );
+        let (count, done) = match result {
+            // The next bucket holds more than `batch_size` entries, and nothing was read. Retry
+            // it with a larger batch, up to the size of the map that no bucket can exceed.
+            Err((_, io_error))
+                if io_error.raw_os_error() == Some(ENOSPC) && batch_size < max_entries =>
+            {
+                batch_size = batch_size.saturating_mul(2).min(max_entries);
+                keys.reserve(batch_size);
+                values.reserve(batch_size);
+                continue;
+            }
+            result => result.map_err(|(_, io_error)| SyscallError {
+                call: "bpf_map_lookup_batch",
+                io_error,
+            })?,
+        };
+        // Safety: the kernel wrote the first `count` keys and values.
+        unsafe {
+            keys.set_len(count);
+            values.set_len(count);
+        }
+        entries.extend(keys.drain(..).zip(values.drain(..)));
+        if done {
+            return Ok(entries);
+        }
+        in_batch = Some(out_batch);
+    }
+}
+
 pub(crate) fn remove<K: Pod>(map: &MapData, key: &K) -> Result<(), MapError> {
     let fd = map.fd().as_fd();
     bpf_map_delete_elem(fd, key)
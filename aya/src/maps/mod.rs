@@ -65,7 +65,7 @@ use obj::maps::InvalidMapTypeError;
 use thiserror::Error;
 
 use crate::{
-    generated::bpf_map_info,
+    generated::{bpf_map_info, bpf_map_type, bpf_map_type::*},
     obj::{self, parse_map_info, BpfSectionKind},
     pin::PinError,
     sys::{
@@ -172,6 +172,16 @@ pub enum MapError {
     #[error("the program is not loaded")]
     ProgramNotLoaded,
 
+    /// The program can't be stored in a program array
+    #[error("the program at index {index} doesn't match the type of the other programs of the array: {io_error}")]
+    ProgramTypeMismatch {
+        /// Index the program was stored at
+        index: u32,
+        /// The error returned by the kernel
+        #[source]
+        io_error: io::Error,
+    },
+
     /// Syscall failed
     #[error(transparent)]
     SyscallError(#[from] SyscallError),
@@ -222,7 +232,7 @@ impl AsFd for MapFd {
 /// Raises a warning about rlimit. Should be used only if creating a map was not
 /// successful.
 fn maybe_warn_rlimit() {
-    let mut limit = std::mem::MaybeUninit::<rlimit>::uninit();
+    let mut limit = mem::MaybeUninit::<rlimit>::uninit();
     let ret = unsafe { getrlimit(RLIMIT_MEMLOCK, limit.as_mut_ptr()) };
     if ret == 0 {
         let limit = unsafe { limit.assume_init() };
@@ -301,6 +311,58 @@ pub enum Map {
 }
 
 impl Map {
+    /// Wraps a map in the variant matching its type.
+    ///
+    /// Maps of a type that aya doesn't support are wrapped in [`Map::Unsupported`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MapError::InvalidMapType`] if the type of the map is not a known
+    /// `bpf_map_type`.
+    pub fn from_map_data(map: MapData) -> Result<Self, MapError> {
+        let map_type = bpf_map_type::try_from(map.obj.map_type())?;
+        Ok(match map_type {
+            BPF_MAP_TYPE_ARRAY => Self::Array(map),
+            BPF_MAP_TYPE_PERCPU_ARRAY => Self::PerCpuArray(map),
+            BPF_MAP_TYPE_PROG_ARRAY => Self::ProgramArray(map),
+            BPF_MAP_TYPE_HASH => Self::HashMap(map),
+            BPF_MAP_TYPE_LRU_HASH => Self::LruHashMap(map),
+            BPF_MAP_TYPE_PERCPU_HASH => Self::PerCpuHashMap(map),
+            BPF_MAP_TYPE_LRU_PERCPU_HASH => Self::PerCpuLruHashMap(map),
+            BPF_MAP_TYPE_PERF_EVENT_ARRAY => Self::PerfEventArray(map),
+            BPF_MAP_TYPE_RINGBUF => Self::RingBuf(map),
+            BPF_MAP_TYPE_SOCKHASH => Self::SockHash(map),
+            BPF_MAP_TYPE_SOCKMAP => Self::SockMap(map),
+            BPF_MAP_TYPE_BLOOM_FILTER => Self::BloomFilter(map),
+            BPF_MAP_TYPE_LPM_TRIE => Self::LpmTrie(map),
+            BPF_MAP_TYPE_STACK => Self::Stack(map),
+            BPF_MAP_TYPE_STACK_TRACE => Self::StackTraceMap(map),
+            BPF_MAP_TYPE_QUEUE => Self::Queue(map),
+            BPF_MAP_TYPE_CPUMAP => Self::CpuMap(map),
+            BPF_MAP_TYPE_DEVMAP => Self::DevMap(map),
+            BPF_MAP_TYPE_DEVMAP_HASH => Self::DevMapHash(map),
+            BPF_MAP_TYPE_XSKMAP => Self::XskMap(map),
+            _ => Self::Unsupported(map),
+        })
+    }
+
+    /// Loads a map pinned in bpffs, for example by another process.
+    ///
+    /// The map can then be converted to the typed map matching its type.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use aya::maps::{Map, PerfEventArray};
+    ///
+    /// let map = Map::from_pin("/sys/fs/bpf/events")?;
+    /// let mut perf_array = PerfEventArray::try_from(map)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn from_pin<P: AsRef<Path>>(path: P) -> Result<Self, MapError> {
+        MapData::from_pin(path).and_then(Self::from_map_data)
+    }
+
     /// Returns the low level map type.
     fn map_type(&self) -> u32 {
         match self {
@@ -669,9 +731,8 @@ impl MapData {
     ///
     /// # Errors
     ///
+    /// Returns a [`PinError::AlreadyExists`] if something is already pinned at the path.
     /// Returns a [`PinError::SyscallError`] if the underlying syscall fails.
-    /// This may also happen if the path already exists, in which case the wrapped
-    /// [`std::io::Error`] kind will be [`std::io::ErrorKind::AlreadyExists`].
     /// Returns a [`PinError::InvalidPinPath`] if the path provided cannot be
     /// converted to a [`CString`].
     ///
@@ -697,9 +758,18 @@ impl MapData {
                 error,
             }
         })?;
-        bpf_pin_object(fd.as_fd(), &path_string).map_err(|(_, io_error)| SyscallError {
-            call: "BPF_OBJ_PIN",
-            io_error,
+        bpf_pin_object(fd.as_fd(), &path_string).map_err(|(_, io_error)| {
+            if io_error.kind() == io::ErrorKind::AlreadyExists {
+                PinError::AlreadyExists {
+                    path: path.to_path_buf(),
+                }
+            } else {
+                SyscallError {
+                    call: "BPF_OBJ_PIN",
+                    io_error,
+                }
+                .into()
+            }
         })?;
         Ok(())
     }
@@ -1084,6 +1154,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_from_pin() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_OBJ_GET,
+                ..
+            } => Ok(42),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_OBJ_GET_INFO_BY_FD,
+                attr,
+            } => {
+                let info = unsafe { &mut *(attr.info.info as *mut bpf_map_info) };
+                info.type_ = BPF_MAP_TYPE_PERF_EVENT_ARRAY as u32;
+                Ok(0)
+            }
+            _ => Err((-1, io::Error::from_raw_os_error(EFAULT))),
+        });
+
+        assert_matches!(
+            Map::from_pin("/sys/fs/bpf/events"),
+            Ok(Map::PerfEventArray(map)) => assert_eq!(map.fd().as_fd().as_raw_fd(), 42)
+        );
+    }
+
+    #[test]
+    fn test_pin_already_exists() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_CREATE,
+                ..
+            } => Ok(42),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_OBJ_PIN,
+                ..
+            } => Err((-1, io::Error::from_raw_os_error(libc::EEXIST))),
+            _ => Err((-1, io::Error::from_raw_os_error(EFAULT))),
+        });
+
+        let map = MapData::create(new_obj_map(), "foo", None).unwrap();
+        assert_matches!(
+            map.pin("/sys/fs/bpf/foo"),
+            Err(PinError::AlreadyExists { path }) => assert_eq!(path, Path::new("/sys/fs/bpf/foo"))
+        );
+    }
+
     #[test]
     fn test_create() {
         override_syscall(|call| match call {
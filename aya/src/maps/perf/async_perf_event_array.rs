@@ -1,6 +1,10 @@
 use std::{
     borrow::{Borrow, BorrowMut},
+    future::poll_fn,
+    ops::Range,
     path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
 };
 
 // See https://doc.rust-lang.org/cargo/reference/features.html#mutually-exclusive-features.
@@ -10,11 +14,12 @@ use std::{
 #[cfg(all(not(feature = "async_tokio"), feature = "async_std"))]
 use async_io::Async;
 use bytes::BytesMut;
+use futures::Stream;
 #[cfg(feature = "async_tokio")]
 use tokio::io::unix::AsyncFd;
 
 use crate::maps::{
-    perf::{Events, PerfBufferError, PerfEventArray, PerfEventArrayBuffer},
+    perf::{Events, PerfBufferError, PerfEventArray, PerfEventArrayBuffer, PerfEventConfig},
     MapData, MapError, PinError,
 };
 
@@ -99,9 +104,21 @@ impl<T: BorrowMut<MapData>> AsyncPerfEventArray<T> {
         &mut self,
         index: u32,
         page_count: Option<usize>,
+    ) -> Result<AsyncPerfEventArrayBuffer<T>, PerfBufferError> {
+        self.open_with_config(index, page_count, &PerfEventConfig::default())
+    }
+
+    /// Opens the perf buffer at the given index, using the given perf event configuration.
+    ///
+    /// See [`PerfEventArray::open_with_config`].
+    pub fn open_with_config(
+        &mut self,
+        index: u32,
+        page_count: Option<usize>,
+        config: &PerfEventConfig,
     ) -> Result<AsyncPerfEventArrayBuffer<T>, PerfBufferError> {
         let Self { perf_map } = self;
-        let buf = perf_map.open(index, page_count)?;
+        let buf = perf_map.open_with_config(index, page_count, config)?;
         #[cfg(feature = "async_tokio")]
         let buf = AsyncFd::new(buf)?;
         #[cfg(all(not(feature = "async_tokio"), feature = "async_std"))]
@@ -158,25 +175,74 @@ impl<T: BorrowMut<MapData>> AsyncPerfEventArrayBuffer<T> {
         &mut self,
         buffers: &mut [BytesMut],
     ) -> Result<Events, PerfBufferError> {
+        poll_fn(|cx| self.poll_read_events(cx, buffers)).await
+    }
+
+    /// Converts the buffer into a [`Stream`] of the data of its samples.
+    ///
+    /// The stream reads events in batches of up to `out_bufs.len()` samples with
+    /// [`read_events`](Self::read_events), and yields the data of each sample, split off the
+    /// output buffer it was read into. The number of events lost so far is available with
+    /// [`PerfEventStream::lost`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `out_bufs` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "async_tokio")]
+    /// # async fn try_main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::perf::AsyncPerfEventArray;
+    /// use bytes::BytesMut;
+    /// use futures::StreamExt as _;
+    ///
+    /// let mut perf_array = AsyncPerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
+    /// let buf = perf_array.open(0, None)?;
+    /// let mut samples = buf.into_stream(vec![BytesMut::with_capacity(1024); 16]);
+    /// while let Some(data) = samples.next().await {
+    ///     println!("{:?}", data?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_stream(self, out_bufs: Vec<BytesMut>) -> PerfEventStream<T> {
+        assert!(!out_bufs.is_empty(), "out_bufs must not be empty");
+        PerfEventStream {
+            buf: self,
+            out_bufs,
+            filled: 0..0,
+            lost: 0,
+        }
+    }
+
+    // Waits for the buffer to be readable and reads events, retrying after spurious wakeups.
+    fn poll_read_events(
+        &mut self,
+        cx: &mut Context<'_>,
+        buffers: &mut [BytesMut],
+    ) -> Poll<Result<Events, PerfBufferError>> {
         let Self { buf } = self;
         loop {
             #[cfg(feature = "async_tokio")]
-            let mut guard = buf.readable_mut().await?;
+            let mut guard = ready!(buf.poll_read_ready_mut(cx))?;
             #[cfg(feature = "async_tokio")]
             let buf = guard.get_inner_mut();
 
             #[cfg(all(not(feature = "async_tokio"), feature = "async_std"))]
             let buf = {
                 if !buf.get_ref().readable() {
-                    buf.readable().await?;
+                    ready!(buf.poll_readable(cx))?;
                 }
                 unsafe { buf.get_mut() }
             };
 
             let events = buf.read_events(buffers)?;
-            const EMPTY: Events = Events { read: 0, lost: 0 };
+            const EMPTY: Events = Events::new(0, 0);
             if events != EMPTY {
-                break Ok(events);
+                break Poll::Ready(Ok(events));
             }
 
             #[cfg(feature = "async_tokio")]
@@ -184,3 +250,45 @@ impl<T: BorrowMut<MapData>> AsyncPerfEventArrayBuffer<T> {
         }
     }
 }
+
+/// A [`Stream`] of the data of the samples of a perf buffer.
+///
+/// Returned by [`AsyncPerfEventArrayBuffer::into_stream`].
+pub struct PerfEventStream<T: BorrowMut<MapData>> {
+    buf: AsyncPerfEventArrayBuffer<T>,
+    out_bufs: Vec<BytesMut>,
+    filled: Range<usize>,
+    lost: usize,
+}
+
+impl<T: BorrowMut<MapData>> PerfEventStream<T> {
+    /// Returns the number of events lost since the stream was created.
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+}
+
+impl<T: BorrowMut<MapData>> Stream for PerfEventStream<T> {
+    type Item = Result<BytesMut, PerfBufferError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Self {
+            buf,
+            out_bufs,
+            filled,
+            lost,
+        } = self.get_mut();
+        loop {
+            if let Some(i) = filled.next() {
+                return Poll::Ready(Some(Ok(out_bufs[i].split())));
+            }
+            match ready!(buf.poll_read_events(cx, out_bufs)) {
+                Ok(events) => {
+                    *lost = lost.saturating_add(events.lost);
+                    *filled = 0..events.read;
+                }
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
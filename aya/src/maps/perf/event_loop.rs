@@ -0,0 +1,173 @@
+use std::{
+    borrow::BorrowMut,
+    io,
+    os::fd::{AsFd as _, AsRawFd as _, OwnedFd},
+    sync::Arc,
+};
+
+use bytes::BytesMut;
+use libc::{pollfd, POLLERR, POLLHUP, POLLIN, POLLNVAL};
+
+use crate::{
+    maps::{
+        perf::{
+            wakeup::{eventfd, eventfd_signal, poll},
+            PerfBufferError, PerfEventArray, PerfEventArrayBuffer,
+        },
+        MapData,
+    },
+    util::online_cpus,
+};
+
+// The number of events read from a buffer with each read_events() call.
+const BATCH_SIZE: usize = 16;
+
+/// A ready made loop that drains one perf buffer per online CPU.
+///
+/// Returned by [`PerfEventArray::event_loop`]. See [`PerfEventLoop::run_forever`].
+pub struct PerfEventLoop<T> {
+    buffers: Vec<(u32, PerfEventArrayBuffer<T>)>,
+    stop: Arc<OwnedFd>,
+}
+
+/// Stops a running [`PerfEventLoop`].
+///
+/// Returned by [`PerfEventLoop::stop_handle`]. It can be cloned and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct StopHandle {
+    stop: Arc<OwnedFd>,
+}
+
+impl StopHandle {
+    /// Makes [`PerfEventLoop::run_forever`] return once it's done with the buffers it's
+    /// currently draining.
+    pub fn stop(&self) -> io::Result<()> {
+        eventfd_signal(self.stop.as_fd())
+    }
+}
+
+impl<T: BorrowMut<MapData>> PerfEventArray<T> {
+    /// Opens a perf buffer for each online CPU and returns a loop draining them.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut event_loop = perf_array.event_loop(None)?;
+    ///
+    /// // stop after a minute
+    /// let stop = event_loop.stop_handle();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(std::time::Duration::from_secs(60));
+    ///     stop.stop().unwrap();
+    /// });
+    ///
+    /// event_loop.run_forever(
+    ///     |cpu_id, data| println!("cpu {cpu_id}: {data:?}"),
+    ///     |cpu_id, lost| eprintln!("cpu {cpu_id}: lost {lost} events"),
+    /// )?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn event_loop(
+        &mut self,
+        page_count: Option<usize>,
+    ) -> Result<PerfEventLoop<T>, PerfBufferError> {
+        let buffers = online_cpus()?
+            .into_iter()
+            .map(|cpu_id| Ok((cpu_id, self.open(cpu_id, page_count)?)))
+            .collect::<Result<Vec<_>, PerfBufferError>>()?;
+        Ok(PerfEventLoop {
+            buffers,
+            stop: Arc::new(eventfd()?),
+        })
+    }
+}
+
+impl<T: BorrowMut<MapData>> PerfEventLoop<T> {
+    /// Returns a handle that can be used to stop the loop.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            stop: self.stop.clone(),
+        }
+    }
+
+    /// Polls the buffers and drains them until stopped.
+    ///
+    /// `on_sample` is called with the CPU id and the data of each event, `on_loss` with the CPU
+    /// id and the number of events lost whenever the kernel reports lost events.
+    ///
+    /// The buffers whose event was closed or failed are drained one last time and then no longer
+    /// polled.
+    ///
+    /// Returns `Ok(())` once [`StopHandle::stop`] has been called.
+    pub fn run_forever(
+        &mut self,
+        mut on_sample: impl FnMut(u32, &[u8]),
+        mut on_loss: impl FnMut(u32, u64),
+    ) -> Result<(), PerfBufferError> {
+        let mut fds = self
+            .buffers
+            .iter()
+            .map(|(_, buf)| buf.as_raw_fd())
+            .chain([self.stop.as_raw_fd()])
+            .map(|fd| pollfd {
+                fd,
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        let mut out_bufs = (0..BATCH_SIZE)
+            .map(|_| BytesMut::with_capacity(1024))
+            .collect::<Vec<_>>();
+
+        loop {
+            poll(&mut fds, -1)?;
+            let (stop, buffer_fds) = fds.split_last_mut().unwrap();
+            if stop.revents != 0 {
+                return Ok(());
+            }
+            for ((cpu_id, buf), fd) in self.buffers.iter_mut().zip(buffer_fds) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                while buf.readable() {
+                    let events = buf.read_events(&mut out_bufs)?;
+                    if events.lost > 0 {
+                        on_loss(*cpu_id, events.lost as u64);
+                    }
+                    for data in events.filled(&mut out_bufs) {
+                        on_sample(*cpu_id, data);
+                    }
+                }
+                // these are reported until the fd is closed, so poll() would keep returning
+                if fd.revents & (POLLHUP | POLLERR | POLLNVAL) != 0 {
+                    fd.fd = -1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_stop() {
+        let mut event_loop = PerfEventLoop::<MapData> {
+            buffers: Vec::new(),
+            stop: Arc::new(eventfd().unwrap()),
+        };
+        event_loop.stop_handle().stop().unwrap();
+        event_loop
+            .run_forever(
+                |_, _| panic!("unexpected sample"),
+                |_, _| panic!("unexpected loss"),
+            )
+            .unwrap();
+    }
+}
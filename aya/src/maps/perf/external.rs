@@ -0,0 +1,139 @@
+use std::{
+    marker::PhantomData,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    maps::perf::{
+        perf_buffer::{EventFd, PerfBuffer},
+        Events, PerfBufferError, PerfEventConfig, Sample,
+    },
+    util::page_size,
+};
+
+/// A perf buffer reading the ring of a perf event opened outside of aya.
+///
+/// This reuses the ring reading of
+/// [`PerfEventArrayBuffer`](crate::maps::perf::PerfEventArrayBuffer) for perf events managed by
+/// other code, for example events that a profiler opened with `perf_event_open(2)` with a
+/// specific hardware configuration. The buffer isn't stored in a
+/// [`PerfEventArray`](crate::maps::PerfEventArray): it receives the records the kernel writes
+/// for the event itself.
+///
+/// The event is left in the state it was in: the buffer doesn't enable it when it's created.
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn open_cycles_event() -> std::os::fd::OwnedFd { unimplemented!() }
+/// use aya::maps::perf::{ExternalPerfBuffer, PerfEventConfig};
+/// use bytes::BytesMut;
+///
+/// // an event whose samples only include the raw data, like the default configuration
+/// let fd = open_cycles_event();
+/// let mut buf = ExternalPerfBuffer::new(fd, 16, &PerfEventConfig::new())?;
+/// let mut out_bufs = [BytesMut::with_capacity(1024)];
+/// let events = buf.read_events(&mut out_bufs)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct ExternalPerfBuffer<'fd> {
+    buf: PerfBuffer,
+    _fd: PhantomData<BorrowedFd<'fd>>,
+}
+
+impl ExternalPerfBuffer<'static> {
+    /// Maps the ring of the perf event `fd`, taking ownership of it.
+    ///
+    /// `config` must describe the attributes the event was opened with. Its sample format is
+    /// used to decode the records, and its [`read_only`](PerfEventConfig::read_only) and
+    /// [`overwrite`](PerfEventConfig::overwrite) settings select how the ring is mapped and
+    /// read. The page count is the number of data pages, and must be a power of two.
+    ///
+    /// The event is disabled and `fd` is closed when the buffer is dropped.
+    pub fn new(
+        fd: OwnedFd,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        Self::from_fd(EventFd::Owned(fd), page_count, config)
+    }
+}
+
+impl<'fd> ExternalPerfBuffer<'fd> {
+    /// Maps the ring of the perf event `fd`, without taking ownership of it.
+    ///
+    /// This works like [`new`](ExternalPerfBuffer::new), but the event is neither disabled nor
+    /// closed when the buffer is dropped, so the code that opened it keeps managing it. A
+    /// perf event can only have one ring, so mapping an event that already has one fails with
+    /// [`PerfBufferError::MMapError`] unless the sizes match.
+    pub fn borrowed(
+        fd: BorrowedFd<'fd>,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        Self::from_fd(EventFd::Borrowed(fd.as_raw_fd()), page_count, config)
+    }
+
+    fn from_fd(
+        fd: EventFd,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        Ok(Self {
+            buf: PerfBuffer::from_fd(fd, page_size(), page_count, config)?,
+            _fd: PhantomData,
+        })
+    }
+
+    /// Returns true if the buffer contains events that haven't been read.
+    pub fn readable(&self) -> bool {
+        self.buf.readable()
+    }
+
+    /// Returns the number of bytes of records that haven't been read.
+    pub fn available_bytes(&self) -> usize {
+        self.buf.available_bytes()
+    }
+
+    /// Reads events, see
+    /// [`PerfEventArrayBuffer::read_events`](crate::maps::perf::PerfEventArrayBuffer::read_events).
+    pub fn read_events(&mut self, out_bufs: &mut [BytesMut]) -> Result<Events, PerfBufferError> {
+        self.buf.read_events(out_bufs)
+    }
+
+    /// Reads samples, see
+    /// [`PerfEventArrayBuffer::read_samples`](crate::maps::perf::PerfEventArrayBuffer::read_samples).
+    pub fn read_samples(&mut self, samples: &mut [Sample]) -> Result<Events, PerfBufferError> {
+        self.buf.read_samples(samples)
+    }
+
+    /// Reads all the events without copying them, see
+    /// [`PerfEventArrayBuffer::read_events_borrowed`](crate::maps::perf::PerfEventArrayBuffer::read_events_borrowed).
+    pub fn read_events_borrowed(
+        &mut self,
+        f: impl FnMut(&[u8]),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_borrowed(f)
+    }
+
+    /// Discards all the events that haven't been read, see
+    /// [`PerfEventArrayBuffer::discard_events`](crate::maps::perf::PerfEventArrayBuffer::discard_events).
+    pub fn discard_events(&mut self) -> Result<Events, PerfBufferError> {
+        self.buf.discard_events()
+    }
+}
+
+impl AsFd for ExternalPerfBuffer<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.buf.as_fd()
+    }
+}
+
+impl AsRawFd for ExternalPerfBuffer<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.buf.as_raw_fd()
+    }
+}
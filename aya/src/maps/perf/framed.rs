@@ -0,0 +1,210 @@
+use std::mem::{size_of, MaybeUninit};
+
+use crate::maps::perf::{
+    perf_buffer::{Ring, Sampled},
+    Events, PerfBufferError,
+};
+
+const LEN_SIZE: usize = size_of::<u32>();
+
+/// Return type of
+/// [`PerfEventArrayBuffer::read_events_framed`](crate::maps::perf::PerfEventArrayBuffer::read_events_framed).
+#[derive(Debug)]
+pub struct FramedEvents<'a> {
+    /// The number of events read and lost.
+    pub events: Events,
+    /// The part of the region that was written to.
+    pub data: &'a mut [u8],
+}
+
+impl FramedEvents<'_> {
+    /// Returns an iterator over the data of the samples written to the region.
+    pub fn frames(&self) -> Frames<'_> {
+        Frames { data: self.data }
+    }
+}
+
+/// An iterator over length-prefixed samples, see [`FramedEvents::frames`].
+#[derive(Debug)]
+pub struct Frames<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Frames<'a> {
+    /// Creates an iterator over a region filled by
+    /// [`PerfEventArrayBuffer::read_events_framed`](crate::maps::perf::PerfEventArrayBuffer::read_events_framed).
+    ///
+    /// Each frame is a native endian `u32` length followed by that many bytes of sample data.
+    /// The iterator stops at the first truncated frame.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (len, rest) = split_at_checked(self.data, LEN_SIZE)?;
+        let len = u32::from_ne_bytes(len.try_into().unwrap()) as usize;
+        let (frame, rest) = split_at_checked(rest, len)?;
+        self.data = rest;
+        Some(frame)
+    }
+}
+
+fn split_at_checked(data: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    (mid <= data.len()).then(|| data.split_at(mid))
+}
+
+impl Ring {
+    pub(super) fn read_events_framed<'a>(
+        &mut self,
+        region: &'a mut [MaybeUninit<u8>],
+    ) -> Result<FramedEvents<'a>, PerfBufferError> {
+        let mut len = 0;
+        let mut too_large = None;
+
+        let events = self.consume_samples(|payload| {
+            // leave the sample in the ring if it doesn't fit, for the next read
+            let frame = &mut region[len..];
+            if frame.len() < LEN_SIZE + payload.len() {
+                too_large = Some(LEN_SIZE + payload.len());
+                return Ok::<_, PerfBufferError>(Sampled::Left);
+            }
+            let (prefix, data) = frame.split_at_mut(LEN_SIZE);
+            for (dst, src) in prefix.iter_mut().zip((payload.len() as u32).to_ne_bytes()) {
+                dst.write(src);
+            }
+            for (dst, src) in data.iter_mut().zip(payload) {
+                dst.write(*src);
+            }
+            len += LEN_SIZE + payload.len();
+            Ok(Sampled::Read)
+        })?;
+
+        // a sample that doesn't fit in the whole region would otherwise never be read
+        if let (
+            Some(frame_size),
+            Events {
+                read: 0, lost: 0, ..
+            },
+        ) = (too_large, &events)
+        {
+            return Err(PerfBufferError::RegionTooSmall {
+                frame_size,
+                region_size: region.len(),
+            });
+        }
+
+        // Safety: the first `len` bytes of the region have been written above.
+        let data = unsafe { &mut *(&mut region[..len] as *mut [MaybeUninit<u8>] as *mut [u8]) };
+        Ok(FramedEvents { events, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of_val;
+
+    use assert_matches::assert_matches;
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::{
+        generated::{perf_event_header, perf_event_mmap_page, perf_event_type::PERF_RECORD_SAMPLE},
+        maps::perf::{BranchSampleType, ReadFormat, SampleType},
+    };
+
+    fn ring(records: &[&[u8]], mem: &mut [u64]) -> Ring {
+        let page_size = size_of::<perf_event_mmap_page>();
+        let base = mem.as_mut_ptr() as *mut u8;
+        let mut offset = 0;
+        for data in records {
+            let size = (size_of::<perf_event_header>() + LEN_SIZE + data.len() + 7) & !7;
+            let mut record = Vec::new();
+            record.extend_from_slice(&(PERF_RECORD_SAMPLE as u32).to_ne_bytes());
+            record.extend_from_slice(&0u16.to_ne_bytes());
+            record.extend_from_slice(&(size as u16).to_ne_bytes());
+            record.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+            record.extend_from_slice(data);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    record.as_ptr(),
+                    base.add(page_size + offset),
+                    record.len(),
+                )
+            };
+            offset += size;
+        }
+        let header = base as *mut perf_event_mmap_page;
+        unsafe { (*header).data_head = offset as u64 };
+        Ring::new(
+            header,
+            size_of_val(mem) - page_size,
+            page_size,
+            SampleType::RAW,
+            ReadFormat::empty(),
+            BranchSampleType::empty(),
+        )
+    }
+
+    #[test]
+    fn test_read_events_framed() {
+        let mut mem = vec![0u64; (size_of::<perf_event_mmap_page>() + 256) / 8];
+        let mut ring = ring(&[b"abcd", b"ef", b"ghijkl"], &mut mem);
+
+        // room for the first two frames only
+        let mut region = [MaybeUninit::uninit(); 15];
+        let framed = ring.read_events_framed(&mut region).unwrap();
+        assert_eq!(framed.events, Events::new(2, 0));
+        assert_eq!(framed.data.len(), 14);
+        assert_eq!(
+            framed.frames().collect::<Vec<_>>(),
+            [&b"abcd"[..], &b"ef"[..]]
+        );
+
+        // the third sample was left in the ring
+        assert!(ring.readable());
+        let framed = ring.read_events_framed(&mut region).unwrap();
+        assert_eq!(framed.events, Events::new(1, 0));
+        assert_eq!(Frames::new(framed.data).collect::<Vec<_>>(), [b"ghijkl"]);
+        assert!(!ring.readable());
+    }
+
+    #[test]
+    fn test_read_events_framed_region_too_small() {
+        let mut mem = vec![0u64; (size_of::<perf_event_mmap_page>() + 256) / 8];
+        let mut ring = ring(&[b"abcd"], &mut mem);
+
+        let mut region = [MaybeUninit::uninit(); 7];
+        assert_matches!(
+            ring.read_events_framed(&mut region),
+            Err(PerfBufferError::RegionTooSmall {
+                frame_size: 8,
+                region_size: 7,
+            })
+        );
+
+        // the sample was left in the ring
+        let mut region = [MaybeUninit::uninit(); 8];
+        let framed = ring.read_events_framed(&mut region).unwrap();
+        assert_eq!(framed.frames().collect::<Vec<_>>(), [b"abcd"]);
+    }
+
+    #[test]
+    fn test_read_empty_sample() {
+        let mut mem = vec![0u64; (size_of::<perf_event_mmap_page>() + 256) / 8];
+        let mut framed_ring = ring(&[b"", b"ab"], &mut mem);
+        let mut region = [MaybeUninit::uninit(); 16];
+        let framed = framed_ring.read_events_framed(&mut region).unwrap();
+        assert_eq!(framed.events, Events::new(2, 0));
+        assert_eq!(framed.frames().collect::<Vec<_>>(), [&b""[..], &b"ab"[..]]);
+
+        let mut mem = vec![0u64; (size_of::<perf_event_mmap_page>() + 256) / 8];
+        let mut ring = ring(&[b""], &mut mem);
+        let mut out_bufs = [BytesMut::new()];
+        assert_eq!(ring.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert!(out_bufs[0].is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use std::mem::size_of;
+
+use thiserror::Error;
+
+use crate::{obj::btf::StructLayout, Btf, BtfError};
+
+/// Error returned by [`check_sample_layout`].
+#[derive(Error, Debug)]
+pub enum SampleLayoutError {
+    /// Loading the layout of the type from BTF failed.
+    #[error(transparent)]
+    Btf(#[from] BtfError),
+
+    /// The sizes of the Rust and BTF types differ.
+    #[error("`{type_name}` is {btf_size} bytes in BTF but {rust_size} bytes in Rust")]
+    SizeMismatch {
+        /// The name of the BTF type.
+        type_name: String,
+        /// The size of the BTF type.
+        btf_size: usize,
+        /// The size of the Rust type.
+        rust_size: usize,
+    },
+
+    /// A field of the Rust type doesn't exist in the BTF type.
+    #[error("`{type_name}` has no field `{field}` in BTF")]
+    UnknownField {
+        /// The name of the BTF type.
+        type_name: String,
+        /// The name of the field.
+        field: String,
+    },
+
+    /// A field is at a different offset in the Rust and BTF types.
+    #[error("`{type_name}.{field}` is at offset {btf_offset} in BTF but {rust_offset} in Rust")]
+    OffsetMismatch {
+        /// The name of the BTF type.
+        type_name: String,
+        /// The name of the field.
+        field: String,
+        /// The offset of the field in the BTF type.
+        btf_offset: usize,
+        /// The offset of the field in the Rust type.
+        rust_offset: usize,
+    },
+}
+
+/// Checks that the layout of `T` matches the BTF struct named `type_name`.
+///
+/// This catches drift between the `#[repr(C)]` type used to decode samples and the struct the
+/// eBPF program sends, for example when fields are reordered on one side only. The size of `T`
+/// is compared with the size of the BTF struct, and `fields` lists the name and byte offset of
+/// the fields of `T` to compare with the members of the BTF struct.
+///
+/// # Examples
+///
+/// ```no_run
+/// use aya::{maps::perf::check_sample_layout, Btf, Endianness};
+///
+/// #[repr(C)]
+/// struct Event {
+///     pid: u32,
+///     len: u32,
+///     ts: u64,
+/// }
+///
+/// let btf = Btf::parse_file("events.btf", Endianness::default())?;
+/// check_sample_layout::<Event>(&btf, "event", &[("pid", 0), ("len", 4), ("ts", 8)])?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn check_sample_layout<T>(
+    btf: &Btf,
+    type_name: &str,
+    fields: &[(&str, usize)],
+) -> Result<(), SampleLayoutError> {
+    let layout = btf.struct_layout(type_name)?;
+    check_layout(&layout, type_name, size_of::<T>(), fields)
+}
+
+fn check_layout(
+    layout: &StructLayout,
+    type_name: &str,
+    rust_size: usize,
+    fields: &[(&str, usize)],
+) -> Result<(), SampleLayoutError> {
+    if layout.size != rust_size {
+        return Err(SampleLayoutError::SizeMismatch {
+            type_name: type_name.to_owned(),
+            btf_size: layout.size,
+            rust_size,
+        });
+    }
+    for &(field, rust_offset) in fields {
+        let btf_offset = layout
+            .members
+            .iter()
+            .find_map(|(name, offset)| (name == field).then_some(*offset))
+            .ok_or_else(|| SampleLayoutError::UnknownField {
+                type_name: type_name.to_owned(),
+                field: field.to_owned(),
+            })?;
+        if btf_offset != rust_offset {
+            return Err(SampleLayoutError::OffsetMismatch {
+                type_name: type_name.to_owned(),
+                field: field.to_owned(),
+                btf_offset,
+                rust_offset,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_check_layout() {
+        let layout = StructLayout {
+            size: 16,
+            members: vec![
+                ("pid".to_owned(), 0),
+                ("len".to_owned(), 4),
+                ("ts".to_owned(), 8),
+            ],
+        };
+        let fields = [("pid", 0), ("len", 4), ("ts", 8)];
+        check_layout(&layout, "event", 16, &fields).unwrap();
+
+        assert_matches!(
+            check_layout(&layout, "event", 12, &fields),
+            Err(SampleLayoutError::SizeMismatch {
+                btf_size: 16,
+                rust_size: 12,
+                ..
+            })
+        );
+        assert_matches!(
+            check_layout(&layout, "event", 16, &[("len", 0), ("pid", 4)]),
+            Err(SampleLayoutError::OffsetMismatch { field, btf_offset: 4, rust_offset: 0, .. }) if field == "len"
+        );
+        assert_matches!(
+            check_layout(&layout, "event", 16, &[("tid", 0)]),
+            Err(SampleLayoutError::UnknownField { field, .. }) if field == "tid"
+        );
+    }
+}
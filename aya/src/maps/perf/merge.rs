@@ -0,0 +1,138 @@
+use std::{
+    borrow::BorrowMut,
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    mem,
+};
+
+use crate::maps::{
+    perf::{PerfBufferError, PerfEventArrayBuffer, Sample},
+    MapData,
+};
+
+// The number of samples read from a buffer with each read_samples() call.
+const BATCH_SIZE: usize = 16;
+
+// The maximum number of read_samples() calls per buffer, so that a buffer that fills up as fast
+// as it's read doesn't keep the others waiting.
+const MAX_BATCHES: usize = 64;
+
+/// Drains the buffers and returns their samples in timestamp order.
+///
+/// Each buffer is individually ordered, but ordering samples across CPUs requires their
+/// timestamps, so the buffers must be opened with [`SampleType::TIME`](crate::maps::perf::SampleType::TIME)
+/// set. The samples that are ready in each buffer are read, then merged by
+/// [`Sample::time`]. Samples with equal timestamps are returned in the order of `buffers`. At
+/// most 1024 samples are read from each buffer, the ones left are read by the next call.
+///
+/// The iterator yields the [`index`](PerfEventArrayBuffer::index) of the buffer each sample was
+/// read from along with the sample.
+///
+/// # Errors
+///
+/// A buffer that fails to be read stops being drained, and the error is yielded after all the
+/// samples, once per failed buffer.
+///
+/// # Examples
+///
+/// ```no_run
+/// # let mut bpf = aya::Bpf::load(&[])?;
+/// use aya::{
+///     maps::perf::{merge_sorted, PerfEventArray, PerfEventConfig, SampleType},
+///     util::online_cpus,
+/// };
+///
+/// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+/// let mut config = PerfEventConfig::new();
+/// config.sample_type(SampleType::TIME | SampleType::RAW);
+/// let mut buffers = online_cpus()?
+///     .into_iter()
+///     .map(|cpu_id| perf_array.open_with_config(cpu_id, None, &config))
+///     .collect::<Result<Vec<_>, _>>()?;
+///
+/// for result in merge_sorted(&mut buffers) {
+///     let (cpu_id, sample) = result?;
+///     println!("{} cpu {cpu_id}: {:?}", sample.time, sample.data);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn merge_sorted<T: BorrowMut<MapData>>(
+    buffers: &mut [PerfEventArrayBuffer<T>],
+) -> impl Iterator<Item = Result<(u32, Sample), PerfBufferError>> {
+    let mut batch = (0..BATCH_SIZE)
+        .map(|_| Sample::default())
+        .collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    let queues = buffers
+        .iter_mut()
+        .map(|buf| {
+            let mut queue = VecDeque::new();
+            for _ in 0..MAX_BATCHES {
+                if !buf.readable() {
+                    break;
+                }
+                let events = match buf.read_samples(&mut batch) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        errors.push(e);
+                        break;
+                    }
+                };
+                if events.read == 0 {
+                    break;
+                }
+                queue.extend(batch[..events.read].iter_mut().map(mem::take));
+            }
+            (buf.index(), queue)
+        })
+        .collect::<Vec<_>>();
+    merge(queues).map(Ok).chain(errors.into_iter().map(Err))
+}
+
+// Merges the queues, each individually sorted by time, in a single sorted sequence.
+fn merge(mut queues: Vec<(u32, VecDeque<Sample>)>) -> impl Iterator<Item = (u32, Sample)> {
+    let mut heap = queues
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (_, queue))| Some(Reverse((queue.front()?.time, i))))
+        .collect::<BinaryHeap<_>>();
+    std::iter::from_fn(move || {
+        let Reverse((_, i)) = heap.pop()?;
+        let (index, queue) = &mut queues[i];
+        let sample = queue.pop_front()?;
+        if let Some(next) = queue.front() {
+            heap.push(Reverse((next.time, i)));
+        }
+        Some((*index, sample))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(times: &[u64]) -> VecDeque<Sample> {
+        times
+            .iter()
+            .map(|&time| Sample {
+                time,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let merged = merge(vec![
+            (0, queue(&[1, 4, 4, 9])),
+            (1, queue(&[])),
+            (2, queue(&[2, 4, 10])),
+        ])
+        .map(|(index, sample)| (index, sample.time))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            [(0, 1), (2, 2), (0, 4), (0, 4), (2, 4), (0, 9), (2, 10)]
+        );
+    }
+}
@@ -5,11 +5,31 @@
 #[cfg(any(feature = "async_tokio", feature = "async_std"))]
 #[cfg_attr(docsrs, doc(cfg(any(feature = "async_tokio", feature = "async_std"))))]
 mod async_perf_event_array;
+mod event_loop;
+mod external;
+mod framed;
+mod layout;
+mod merge;
 mod perf_buffer;
 mod perf_event_array;
+mod pool;
+mod records;
+mod replay;
+mod sample;
+mod wakeup;
 
 #[cfg(any(feature = "async_tokio", feature = "async_std"))]
 #[cfg_attr(docsrs, doc(cfg(any(feature = "async_tokio", feature = "async_std"))))]
 pub use async_perf_event_array::*;
+pub use event_loop::*;
+pub use external::*;
+pub use framed::*;
+pub use layout::*;
+pub use merge::*;
 pub use perf_buffer::*;
 pub use perf_event_array::*;
+pub use pool::*;
+pub use records::*;
+pub use replay::*;
+pub use sample::*;
+pub use wakeup::*;
@@ -1,29 +1,69 @@
 use std::{
+    borrow::Cow,
+    cell::Cell,
     ffi::c_void,
-    io, mem,
+    fmt::{self, Write as _},
+    io, iter,
+    mem::{self, size_of, MaybeUninit},
+    ops::{Add, AddAssign, ControlFlow, Deref, DerefMut, Range},
     os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
     ptr, slice,
-    sync::atomic::{self, AtomicPtr, Ordering},
+    sync::{
+        atomic::{self, AtomicPtr, Ordering},
+        mpsc::{SendError, Sender},
+    },
+    thread,
 };
 
 use bytes::BytesMut;
-use libc::{munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+use libc::{
+    c_int, getrlimit, pid_t, rlim_t, rlimit, EPERM, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE,
+    RLIMIT_MEMLOCK, RLIM_INFINITY,
+};
+use object::{Endian as _, Endianness};
 use thiserror::Error;
 
 use crate::{
     generated::{
-        perf_event_header, perf_event_mmap_page,
-        perf_event_type::{PERF_RECORD_LOST, PERF_RECORD_SAMPLE},
+        perf_event_attr, perf_event_header, perf_event_mmap_page,
+        perf_event_type::{
+            PERF_RECORD_LOST, PERF_RECORD_SAMPLE, PERF_RECORD_THROTTLE, PERF_RECORD_UNTHROTTLE,
+        },
+        perf_sw_ids::PERF_COUNT_SW_BPF_OUTPUT,
+        perf_type_id::PERF_TYPE_SOFTWARE,
+    },
+    maps::{
+        perf::{BranchSampleType, ReadFormat, Record, Sample, SampleType},
+        MapError,
+    },
+    programs::perf_event::PerfTypeId,
+    sys::{
+        mmap, munmap, perf_event_ioctl, perf_event_ioctl_id, perf_event_open_attr,
+        perf_event_open_attr_output, SysResult,
     },
-    sys::{mmap, perf_event_ioctl, perf_event_open_bpf, SysResult},
-    PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE,
+    PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE, PERF_EVENT_IOC_REFRESH, PERF_EVENT_IOC_RESET,
 };
 
+pub(super) const HEADER_SIZE: usize = size_of::<perf_event_header>();
+
+// The samples returned by read_events_coalesced(), along with their repeat counts.
+pub(crate) type Coalesced = Vec<(Vec<u8>, usize)>;
+
+// The version of perf_event_mmap_page the ring decoding is written against. The kernel reports the
+// oldest version its layout is compatible with in compat_version.
+const SUPPORTED_RING_VERSION: u32 = 0;
+
 /// Perf buffer error.
 #[derive(Error, Debug)]
 pub enum PerfBufferError {
     /// the page count value passed to [`PerfEventArray::open`](crate::maps::PerfEventArray::open) is invalid.
-    #[error("invalid page count {page_count}, the value must be a power of two")]
+    ///
+    /// The page count is the number of data pages of the buffer, which doesn't include the
+    /// metadata page mapped in front of them, and must be a power of two.
+    #[error(
+        "invalid page count {page_count}, the value must be a power of two number of data pages, \
+         excluding the metadata page"
+    )]
     InvalidPageCount {
         /// the page count
         page_count: usize,
@@ -45,6 +85,21 @@ pub enum PerfBufferError {
         io_error: io::Error,
     },
 
+    /// `mmap`-ping the buffer failed because it would exceed the locked memory limit.
+    ///
+    /// The memory of perf buffers is charged to `kernel.perf_event_mlock_kb` and then to
+    /// `RLIMIT_MEMLOCK`, unless the process has `CAP_PERFMON` or `CAP_SYS_ADMIN`.
+    #[error(
+        "mapping a {size} bytes perf buffer exceeds the locked memory limit (RLIMIT_MEMLOCK is \
+        {limit} bytes), use smaller buffers or raise the limit"
+    )]
+    MemlockLimitExceeded {
+        /// the size of the mapping, including the metadata page
+        size: usize,
+        /// the `RLIMIT_MEMLOCK` soft limit
+        limit: rlim_t,
+    },
+
     /// The `PERF_EVENT_IOC_ENABLE` ioctl failed
     #[error("PERF_EVENT_IOC_ENABLE failed: {io_error}")]
     PerfEventEnableError {
@@ -53,6 +108,107 @@ pub enum PerfBufferError {
         io_error: io::Error,
     },
 
+    /// A record has a size that is smaller than its header or that extends past the written
+    /// records, which means that the ring is corrupted.
+    ///
+    /// The records that couldn't be read are discarded, since the start of the next record
+    /// can't be found, so the following reads start over from the newest records.
+    ///
+    /// This is also returned for a sample whose fields, like the raw data or the call chain,
+    /// extend past the end of its record, for example when replaying a corrupted dump. Only that
+    /// sample is discarded then.
+    #[error("invalid perf record size {size} at offset {offset}")]
+    InvalidEventSize {
+        /// the offset of the record in the ring
+        offset: usize,
+        /// the size of the record
+        size: u16,
+    },
+
+    /// The samples have no raw data, since the buffer wasn't opened with [`SampleType::RAW`].
+    ///
+    /// Returned by the methods that read the raw data of the samples, like
+    /// [`read_events`](crate::maps::perf::PerfEventArrayBuffer::read_events). Samples without
+    /// raw data can be read with
+    /// [`read_samples`](crate::maps::perf::PerfEventArrayBuffer::read_samples).
+    #[error("the samples have no raw data, the buffer wasn't opened with SampleType::RAW")]
+    NoRawData,
+
+    /// The next sample doesn't fit in the region passed to
+    /// [`read_events_framed`](crate::maps::perf::PerfEventArrayBuffer::read_events_framed), even
+    /// when it's empty.
+    ///
+    /// The sample is left in the buffer, so it can be read with a larger region.
+    #[error("a frame of {frame_size} bytes doesn't fit in a region of {region_size} bytes")]
+    RegionTooSmall {
+        /// the size of the frame of the sample, including its length prefix
+        frame_size: usize,
+        /// the size of the region
+        region_size: usize,
+    },
+
+    /// The `PERF_EVENT_IOC_DISABLE` ioctl failed
+    #[error("PERF_EVENT_IOC_DISABLE failed: {io_error}")]
+    PerfEventDisableError {
+        #[source]
+        /// the source of this error
+        io_error: io::Error,
+    },
+
+    /// The kernel uses a version of the ring layout that isn't supported.
+    #[error(
+        "perf ring version {kernel_version} isn't compatible with the supported version {supported}"
+    )]
+    IncompatibleRingVersion {
+        /// the `compat_version` reported by the kernel
+        kernel_version: u32,
+        /// the version supported by aya
+        supported: u32,
+    },
+
+    /// The memory of the buffer couldn't be mapped again after a failed
+    /// [`resize`](crate::maps::perf::PerfEventArrayBuffer::resize).
+    ///
+    /// The buffer is closed: its event stays disabled and it reads as empty. A later resize that
+    /// succeeds makes it usable again.
+    #[error(
+        "the perf buffer was closed, its memory couldn't be mapped again after a failed resize"
+    )]
+    Closed {
+        /// the error returned when mapping the memory again
+        #[source]
+        error: Box<Self>,
+    },
+
+    /// A buffer is already open at the given index of the perf event array.
+    #[error("a perf buffer is already open at index {index}")]
+    IndexAlreadyOccupied {
+        /// the index
+        index: u32,
+    },
+
+    /// The index is out of the bounds of the perf event array.
+    #[error("index {index} is out of bounds, the perf event array has {max_entries} entries")]
+    IndexOutOfBounds {
+        /// the index
+        index: u32,
+        /// the number of entries of the perf event array
+        max_entries: u32,
+    },
+
+    /// The memory budget passed to
+    /// [`PerfEventArray::open_all_within_budget`](crate::maps::PerfEventArray::open_all_within_budget)
+    /// doesn't fit a buffer of a single page, plus its metadata page, for each CPU.
+    #[error(
+        "a budget of {total_bytes} bytes is too small for a perf buffer on each of the {cpus} CPUs"
+    )]
+    BudgetTooSmall {
+        /// the memory budget, in bytes
+        total_bytes: usize,
+        /// the number of CPUs
+        cpus: usize,
+    },
+
     /// `read_events()` was called with no output buffers.
     #[error("read_events() was called with no output buffers")]
     NoBuffers,
@@ -74,425 +230,3357 @@ pub enum PerfBufferError {
     IOError(#[from] io::Error),
 }
 
+/// Perf error, spanning setting up the map and opening and reading its buffers.
+///
+/// Both [`MapError`] and [`PerfBufferError`] convert into it, so functions that convert a map
+/// into a [`PerfEventArray`](crate::maps::PerfEventArray), open buffers and read them can use
+/// `?` throughout:
+///
+/// ```no_run
+/// # let mut bpf = aya::Bpf::load(&[])?;
+/// use aya::maps::{perf::PerfError, PerfEventArray};
+/// use bytes::BytesMut;
+///
+/// fn drain(bpf: &mut aya::Bpf) -> Result<usize, PerfError> {
+///     let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+///     let mut buf = perf_array.open(0, None)?;
+///     let mut out_bufs = [BytesMut::with_capacity(1024)];
+///     Ok(buf.read_events(&mut out_bufs)?.read)
+/// }
+/// # drain(&mut bpf)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Error, Debug)]
+pub enum PerfError {
+    /// A map error occurred.
+    #[error(transparent)]
+    Map(#[from] MapError),
+
+    /// A perf buffer error occurred.
+    #[error(transparent)]
+    Buffer(#[from] PerfBufferError),
+}
+
 /// Return type of `read_events()`.
+///
+/// More fields may be added in the future. To build values by hand, for example to test code
+/// consuming them, use [`Events::new`].
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct Events {
     /// The number of events read.
     pub read: usize,
     /// The number of events lost.
     pub lost: usize,
+    /// An estimate of the number of samples left in the buffer after the read.
+    ///
+    /// Only computed by
+    /// [`PerfEventArrayBuffer::for_each_sample`](crate::maps::perf::PerfEventArrayBuffer::for_each_sample),
+    /// zero otherwise.
+    pub remaining: usize,
+    /// The number of `PERF_RECORD_THROTTLE` records read.
+    ///
+    /// The kernel throttles a sampling event when it produces samples faster than
+    /// `perf_event_max_sample_rate` allows, which usually means that the sampling rate is too
+    /// high.
+    pub throttled: usize,
+    /// The number of `PERF_RECORD_UNTHROTTLE` records read.
+    ///
+    /// Counted like [`throttled`](Self::throttled).
+    pub unthrottled: usize,
 }
 
-#[derive(Debug)]
-pub(crate) struct PerfBuffer {
-    buf: AtomicPtr<perf_event_mmap_page>,
-    size: usize,
-    page_size: usize,
-    fd: OwnedFd,
+impl Events {
+    /// Creates a value with the given number of events read and lost.
+    pub const fn new(read: usize, lost: usize) -> Self {
+        Self {
+            read,
+            lost,
+            remaining: 0,
+            throttled: 0,
+            unthrottled: 0,
+        }
+    }
+
+    /// Returns the buffers that were filled by the read that returned these events.
+    ///
+    /// `buffers` must be the slice passed to `read_events()` or `read_samples()`. The returned
+    /// slice can be handed to a parallel iterator, like rayon's `par_chunks_mut()`.
+    pub fn filled<'a, B>(&self, buffers: &'a mut [B]) -> &'a mut [B] {
+        &mut buffers[..self.read]
+    }
+
+    /// Processes the filled buffers in parallel.
+    ///
+    /// The filled buffers are split in chunks of at most `chunk_size` buffers, and `f` is called
+    /// with each chunk on a separate scoped thread. Returns once all the chunks have been
+    /// processed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn par_chunks<B: Send>(
+        &self,
+        buffers: &mut [B],
+        chunk_size: usize,
+        f: impl Fn(&mut [B]) + Sync,
+    ) {
+        let f = &f;
+        thread::scope(|s| {
+            for chunk in self.filled(buffers).chunks_mut(chunk_size) {
+                let _: thread::ScopedJoinHandle<'_, ()> = s.spawn(move || f(chunk));
+            }
+        });
+    }
 }
 
-impl PerfBuffer {
-    pub(crate) fn open(
-        cpu_id: u32,
-        page_size: usize,
-        page_count: usize,
-    ) -> Result<Self, PerfBufferError> {
-        if !page_count.is_power_of_two() {
-            return Err(PerfBufferError::InvalidPageCount { page_count });
-        }
+/// Adds the counts of two reads, for example to total the reads of the buffers of all CPUs.
+impl Add for Events {
+    type Output = Self;
 
-        let fd = perf_event_open_bpf(cpu_id as i32)
-            .map_err(|(_, io_error)| PerfBufferError::OpenError { io_error })?;
-        let size = page_size * page_count;
-        let buf = unsafe {
-            mmap(
-                ptr::null_mut(),
-                size + page_size,
-                PROT_READ | PROT_WRITE,
-                MAP_SHARED,
-                fd.as_fd(),
-                0,
-            )
-        };
-        if buf == MAP_FAILED {
-            return Err(PerfBufferError::MMapError {
-                io_error: io::Error::last_os_error(),
-            });
-        }
+    fn add(mut self, rhs: Self) -> Self {
+        self += rhs;
+        self
+    }
+}
 
-        let perf_buf = Self {
-            buf: AtomicPtr::new(buf as *mut perf_event_mmap_page),
-            fd,
-            size,
-            page_size,
-        };
+impl AddAssign for Events {
+    fn add_assign(&mut self, rhs: Self) {
+        let Self {
+            read,
+            lost,
+            remaining,
+            throttled,
+            unthrottled,
+        } = rhs;
+        self.read = self.read.saturating_add(read);
+        self.lost = self.lost.saturating_add(lost);
+        self.remaining = self.remaining.saturating_add(remaining);
+        self.throttled = self.throttled.saturating_add(throttled);
+        self.unthrottled = self.unthrottled.saturating_add(unthrottled);
+    }
+}
 
-        perf_event_ioctl(perf_buf.fd.as_fd(), PERF_EVENT_IOC_ENABLE, 0)
-            .map_err(|(_, io_error)| PerfBufferError::PerfEventEnableError { io_error })?;
+impl iter::Sum for Events {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::new(0, 0), Add::add)
+    }
+}
 
-        Ok(perf_buf)
+impl Events {
+    // Counts a record consumed by a reader.
+    fn count(&mut self, record: &RecordKind) {
+        match record {
+            RecordKind::Sample => self.read = self.read.saturating_add(1),
+            RecordKind::Lost(Lost { count, .. }) => {
+                self.lost = self
+                    .lost
+                    .saturating_add(usize::try_from(*count).unwrap_or(usize::MAX))
+            }
+            RecordKind::Throttle(_) => self.throttled = self.throttled.saturating_add(1),
+            RecordKind::Unthrottle => self.unthrottled = self.unthrottled.saturating_add(1),
+            RecordKind::Other => {}
+        }
     }
+}
 
-    pub(crate) fn readable(&self) -> bool {
-        let header = self.buf.load(Ordering::SeqCst);
-        let head = unsafe { (*header).data_head } as usize;
-        let tail = unsafe { (*header).data_tail } as usize;
-        head != tail
+// The type of a record, decoded by Ring::record_kind().
+pub(super) enum RecordKind {
+    Sample,
+    Lost(Lost),
+    Throttle(Throttle),
+    Unthrottle,
+    // skipped by the readers
+    Other,
+}
+
+// What a reader did with a sample handed to it by Ring::consume_samples().
+pub(super) enum Sampled {
+    // the sample is read, and the walk goes on
+    Read,
+    // the sample is read, and the walk stops after it
+    Last,
+    // the sample is left in the ring, and the walk stops
+    Left,
+}
+
+/// A `PERF_RECORD_THROTTLE` record, emitted when the kernel throttles a sampling event because
+/// it produces too many samples.
+///
+/// See [`PerfEventArrayBuffer::read_events_throttled`](crate::maps::perf::PerfEventArrayBuffer::read_events_throttled).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Throttle {
+    /// The time the event was throttled at.
+    pub time: u64,
+    /// The id of the throttled event.
+    pub id: u64,
+}
+
+/// A `PERF_RECORD_LOST` record, emitted when the kernel drops samples because the buffer is
+/// full.
+///
+/// See [`PerfEventArrayBuffer::read_events_lost`](crate::maps::perf::PerfEventArrayBuffer::read_events_lost).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lost {
+    /// The id of the perf event that lost the samples.
+    pub id: u64,
+    /// The number of samples lost.
+    pub count: u64,
+}
+
+/// Return type of
+/// [`PerfEventArrayBuffer::read_events_timeout`](crate::maps::perf::PerfEventArrayBuffer::read_events_timeout).
+#[derive(Debug, PartialEq, Eq)]
+pub enum TimedEvents {
+    /// The timeout elapsed without the buffer becoming readable.
+    TimedOut,
+    /// The buffer became readable and was read.
+    Read(Events),
+}
+
+/// Configuration of the perf event backing a perf buffer.
+///
+/// By default perf buffers are backed by a `PERF_COUNT_SW_BPF_OUTPUT` software event, which
+/// receives the data sent by eBPF programs with `bpf_perf_event_output()`.
+///
+/// See [`PerfEventArray::open_with_config`](crate::maps::PerfEventArray::open_with_config).
+#[derive(Clone)]
+pub struct PerfEventConfig {
+    attr: perf_event_attr,
+    read_only: bool,
+}
+
+impl PerfEventConfig {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
+        attr.size = size_of::<perf_event_attr>() as u32;
+        attr.type_ = PERF_TYPE_SOFTWARE as u32;
+        attr.config = PERF_COUNT_SW_BPF_OUTPUT as u64;
+        attr.sample_type = SampleType::default().bits();
+        attr.__bindgen_anon_1.sample_period = 1;
+        attr.__bindgen_anon_2.wakeup_events = 1;
+        Self {
+            attr,
+            read_only: false,
+        }
     }
 
-    pub(crate) fn read_events(
-        &mut self,
-        buffers: &mut [BytesMut],
-    ) -> Result<Events, PerfBufferError> {
-        if buffers.is_empty() {
-            return Err(PerfBufferError::NoBuffers);
+    /// Creates a configuration from a `perf_event_attr` built by the caller.
+    ///
+    /// This allows backing the buffer with any event that `perf_event_open(2)` accepts, like a
+    /// hardware counter or a tracepoint, with fields that the other methods don't expose. The
+    /// attributes are used as is, so `size`, the sampling period and the wakeup settings must be
+    /// set by the caller. The sample format is read from `sample_type`, `read_format` and
+    /// `branch_sample_type` to decode the records.
+    pub fn from_attr(attr: perf_event_attr) -> Self {
+        Self {
+            attr,
+            read_only: false,
         }
-        let header = self.buf.load(Ordering::SeqCst);
-        let base = header as usize + self.page_size;
+    }
 
-        let mut events = Events { read: 0, lost: 0 };
-        let mut buf_n = 0;
+    /// Returns the `perf_event_attr` passed to `perf_event_open(2)`.
+    pub fn attr(&self) -> &perf_event_attr {
+        &self.attr
+    }
 
-        let fill_buf = |start_off, base, mmap_size, out_buf: &mut [u8]| {
-            let len = out_buf.len();
+    /// Sets the event to sample.
+    ///
+    /// The possible values and encoding of `config` depend on `perf_type`, see
+    /// [`PerfEvent::attach`](crate::programs::perf_event::PerfEvent::attach).
+    pub fn event(&mut self, perf_type: PerfTypeId, config: u64) -> &mut Self {
+        self.attr.type_ = perf_type as u32;
+        self.attr.config = config;
+        self
+    }
 
-            let end = (start_off + len) % mmap_size;
-            let start = start_off % mmap_size;
+    /// Sets `config1`, an extension of `config` used by some raw PMU events.
+    pub fn config1(&mut self, config1: u64) -> &mut Self {
+        self.attr.__bindgen_anon_3.config1 = config1;
+        self
+    }
 
-            if start < end {
-                out_buf.copy_from_slice(unsafe {
-                    slice::from_raw_parts((base + start) as *const u8, len)
-                });
-            } else {
-                let size = mmap_size - start;
-                unsafe {
-                    out_buf[..size]
-                        .copy_from_slice(slice::from_raw_parts((base + start) as *const u8, size));
-                    out_buf[size..]
-                        .copy_from_slice(slice::from_raw_parts(base as *const u8, len - size));
-                }
-            }
-        };
+    /// Sets `config2`, a further extension of `config` used by some raw PMU events.
+    pub fn config2(&mut self, config2: u64) -> &mut Self {
+        self.attr.__bindgen_anon_4.config2 = config2;
+        self
+    }
 
-        let read_event = |event_start, event_type, base, buf: &mut BytesMut| {
-            let sample_size = match event_type {
-                x if x == PERF_RECORD_SAMPLE as u32 || x == PERF_RECORD_LOST as u32 => {
-                    let mut size = [0u8; mem::size_of::<u32>()];
-                    fill_buf(
-                        event_start + mem::size_of::<perf_event_header>(),
-                        base,
-                        self.size,
-                        &mut size,
-                    );
-                    u32::from_ne_bytes(size)
-                }
-                _ => return Ok(None),
-            } as usize;
+    /// Sets the clock used for the timestamps of the samples, like `libc::CLOCK_MONOTONIC` or
+    /// `libc::CLOCK_BOOTTIME`.
+    ///
+    /// This allows correlating the [`SampleType::TIME`] timestamps with other sources that use
+    /// the same clock.
+    pub fn clockid(&mut self, clockid: libc::clockid_t) -> &mut Self {
+        self.attr.set_use_clockid(1);
+        self.attr.clockid = clockid;
+        self
+    }
 
-            let sample_start =
-                (event_start + mem::size_of::<perf_event_header>() + mem::size_of::<u32>())
-                    % self.size;
+    /// Sets the fields included in each sample.
+    ///
+    /// The default is [`SampleType::RAW`]. Samples that include other fields can be read with
+    /// [`PerfEventArrayBuffer::read_samples`](crate::maps::perf::PerfEventArrayBuffer::read_samples).
+    /// The other readers only return the raw data of the samples, so they fail with
+    /// [`PerfBufferError::NoRawData`] when [`SampleType::RAW`] isn't set.
+    pub fn sample_type(&mut self, sample_type: SampleType) -> &mut Self {
+        self.attr.sample_type = sample_type.bits();
+        self
+    }
 
-            match event_type {
-                x if x == PERF_RECORD_SAMPLE as u32 => {
-                    buf.clear();
-                    buf.reserve(sample_size);
-                    unsafe { buf.set_len(sample_size) };
+    /// Sets the counter values included when [`SampleType::READ`] is set.
+    pub fn read_format(&mut self, read_format: ReadFormat) -> &mut Self {
+        self.attr.read_format = read_format.bits();
+        self
+    }
+
+    /// Sets the branches recorded when [`SampleType::BRANCH_STACK`] is set.
+    pub fn branch_sample_type(&mut self, branch_sample_type: BranchSampleType) -> &mut Self {
+        self.attr.branch_sample_type = branch_sample_type.bits();
+        self
+    }
 
-                    fill_buf(sample_start, base, self.size, buf);
+    /// Takes a sample every `period` occurrences of the event.
+    ///
+    /// The default is 1, which samples every occurrence. This replaces a frequency set with
+    /// [`sample_freq`](Self::sample_freq).
+    pub fn sample_period(&mut self, period: u64) -> &mut Self {
+        self.attr.set_freq(0);
+        self.attr.__bindgen_anon_1.sample_period = period;
+        self
+    }
 
-                    Ok(Some((1, 0)))
-                }
-                x if x == PERF_RECORD_LOST as u32 => {
-                    let mut count = [0u8; mem::size_of::<u64>()];
-                    fill_buf(
-                        event_start + mem::size_of::<perf_event_header>() + mem::size_of::<u64>(),
-                        base,
-                        self.size,
-                        &mut count,
-                    );
-                    Ok(Some((0, u64::from_ne_bytes(count) as usize)))
-                }
-                _ => Ok(None),
-            }
-        };
+    /// Takes `freq` samples per second, letting the kernel adjust the period to the event rate.
+    ///
+    /// This only makes sense for events that count something, like hardware counters, and
+    /// replaces a period set with [`sample_period`](Self::sample_period).
+    pub fn sample_freq(&mut self, freq: u64) -> &mut Self {
+        self.attr.set_freq(1);
+        self.attr.__bindgen_anon_1.sample_freq = freq;
+        self
+    }
 
-        let head = unsafe { (*header).data_head } as usize;
-        let mut tail = unsafe { (*header).data_tail } as usize;
-        while head != tail {
-            if buf_n == buffers.len() {
-                break;
-            }
+    /// Makes the buffer readable once it holds at least `bytes` bytes of records.
+    ///
+    /// By default the file descriptor becomes readable, and waiting readers are woken up, on
+    /// every sample. A watermark batches the wakeups, which greatly reduces the overhead of
+    /// polling high frequency events, at the cost of latency: records are only seen once the
+    /// watermark is reached, so pair it with a poll timeout when events may be sparse. The
+    /// watermark should be smaller than the buffer.
+    pub fn wakeup_watermark(&mut self, bytes: u32) -> &mut Self {
+        self.attr.set_watermark(1);
+        self.attr.__bindgen_anon_2.wakeup_watermark = bytes;
+        self
+    }
 
-            let buf = &mut buffers[buf_n];
+    /// Maps the buffer read only.
+    ///
+    /// By default buffers are mapped readable and writable, since releasing the records that
+    /// have been read requires writing the read position to the metadata page. Some sandboxes
+    /// don't allow writable mappings of perf event file descriptors, in which case the buffer
+    /// can be mapped read only and the read position is only tracked by the buffer.
+    ///
+    /// This changes how the kernel handles a full buffer: since it can't know what has been
+    /// read, it overwrites the oldest records instead of dropping the new ones. No lost records
+    /// are emitted, and a reader that falls more than a buffer behind loses the records it
+    /// hasn't read, as their boundaries can't be recovered: the next read starts over from the
+    /// newest position. Records are also overwritten while they're read when the buffer is
+    /// nearly full, so size the buffer generously for the event rate.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
 
-            let event_start = tail % self.size;
-            let event =
-                unsafe { ptr::read_unaligned((base + event_start) as *const perf_event_header) };
-            let event_size = event.size as usize;
-
-            match read_event(event_start, event.type_, base, buf) {
-                Ok(Some((read, lost))) => {
-                    if read > 0 {
-                        buf_n += 1;
-                        events.read += read;
-                    }
-                    events.lost += lost;
-                }
-                Ok(None) => { /* skip unknown event type */ }
-                Err(e) => {
-                    // we got an error and we didn't process any events, propagate the error
-                    // and give the caller a chance to increase buffers
-                    atomic::fence(Ordering::SeqCst);
-                    unsafe { (*header).data_tail = tail as u64 };
-                    return Err(e);
-                }
-            }
-            tail += event_size;
-        }
+    /// Makes the buffer keep the newest records when it's full, instead of the oldest.
+    ///
+    /// By default the kernel drops new records while the buffer is full, and reports them as
+    /// lost. In overwrite mode it overwrites the oldest records instead, which suits always-on
+    /// profilers that only look at the most recent activity. This sets `write_backward`, so that
+    /// the kernel writes the records backward from `data_head` and the newest ones can always be
+    /// found, and maps the buffer [read only](Self::read_only). Requires Linux 4.7 or later.
+    ///
+    /// The read methods still return the records oldest first, starting from the oldest record
+    /// that hasn't been overwritten. No lost records are emitted. [`Records`] iterates over the
+    /// records newest first, and committing a cursor also discards the older records that
+    /// haven't been iterated over. Records can be overwritten while they're read when events
+    /// keep coming in, so disable the event first for a consistent snapshot.
+    ///
+    /// [`Records`]: crate::maps::perf::Records
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.attr.set_write_backward(overwrite.into());
+        self
+    }
 
-        atomic::fence(Ordering::SeqCst);
-        unsafe { (*header).data_tail = tail as u64 };
+    fn get_sample_type(&self) -> SampleType {
+        SampleType::from_bits_retain(self.attr.sample_type)
+    }
 
-        Ok(events)
+    fn get_read_format(&self) -> ReadFormat {
+        ReadFormat::from_bits_retain(self.attr.read_format)
+    }
+
+    fn get_branch_sample_type(&self) -> BranchSampleType {
+        BranchSampleType::from_bits_retain(self.attr.branch_sample_type)
     }
 }
 
-impl AsRawFd for PerfBuffer {
-    fn as_raw_fd(&self) -> RawFd {
-        self.fd.as_raw_fd()
+impl Default for PerfEventConfig {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl AsFd for PerfBuffer {
-    fn as_fd(&self) -> BorrowedFd<'_> {
-        self.fd.as_fd()
+impl fmt::Debug for PerfEventConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { attr, read_only } = self;
+        f.debug_struct("PerfEventConfig")
+            .field("type", &attr.type_)
+            .field("config", &attr.config)
+            .field("config1", unsafe { &attr.__bindgen_anon_3.config1 })
+            .field("config2", unsafe { &attr.__bindgen_anon_4.config2 })
+            .field(
+                "clockid",
+                &(attr.use_clockid() != 0).then_some(attr.clockid),
+            )
+            .field("sample_type", &self.get_sample_type())
+            .field("read_format", &self.get_read_format())
+            .field("branch_sample_type", &self.get_branch_sample_type())
+            .field(
+                "sample_period",
+                &(attr.freq() == 0).then_some(unsafe { attr.__bindgen_anon_1.sample_period }),
+            )
+            .field(
+                "sample_freq",
+                &(attr.freq() != 0).then_some(unsafe { attr.__bindgen_anon_1.sample_freq }),
+            )
+            .field(
+                "wakeup_watermark",
+                &(attr.watermark() != 0)
+                    .then_some(unsafe { attr.__bindgen_anon_2.wakeup_watermark }),
+            )
+            .field("read_only", read_only)
+            .field("overwrite", &(attr.write_backward() != 0))
+            .finish()
     }
 }
 
-impl Drop for PerfBuffer {
+/// Keeps the perf event of a buffer enabled until dropped.
+///
+/// Returned by
+/// [`PerfEventArrayBuffer::enabled_scope`](crate::maps::perf::PerfEventArrayBuffer::enabled_scope).
+/// The event is disabled when the guard goes out of scope, including on early returns and
+/// panics.
+#[derive(Debug)]
+#[must_use = "the event is disabled as soon as the guard is dropped"]
+pub struct EnableGuard<'a> {
+    fd: BorrowedFd<'a>,
+}
+
+impl Drop for EnableGuard<'_> {
     fn drop(&mut self) {
-        unsafe {
-            let _: SysResult<_> = perf_event_ioctl(self.fd.as_fd(), PERF_EVENT_IOC_DISABLE, 0);
-            munmap(
-                self.buf.load(Ordering::SeqCst) as *mut c_void,
-                self.size + self.page_size,
-            );
-        }
+        let _: SysResult<_> = perf_event_ioctl(self.fd, PERF_EVENT_IOC_DISABLE, 0);
+    }
+}
+
+// Returns the RLIMIT_MEMLOCK soft limit, or None when it's unlimited or can't be read.
+fn memlock_limit() -> Option<rlim_t> {
+    let mut limit = MaybeUninit::<rlimit>::uninit();
+    if unsafe { getrlimit(RLIMIT_MEMLOCK, limit.as_mut_ptr()) } != 0 {
+        return None;
     }
+    let limit = unsafe { limit.assume_init() };
+    (limit.rlim_cur != RLIM_INFINITY).then_some(limit.rlim_cur)
+}
+
+// Overwrites the whole capacity of `buf` with zeros, so that no data from a previous read
+// lingers past the end of the next one.
+pub(super) fn zero_capacity(buf: &mut BytesMut) {
+    buf.clear();
+    buf.resize(buf.capacity(), 0);
+    buf.clear();
+}
+
+/// Lifetime counters of a perf buffer.
+///
+/// Returned by
+/// [`PerfEventArrayBuffer::stats`](crate::maps::perf::PerfEventArrayBuffer::stats) and
+/// [`PerfEventArrayBuffer::stats_and_reset`](crate::maps::perf::PerfEventArrayBuffer::stats_and_reset).
+/// The counters cover every record consumed from the buffer, whichever method consumed it, and
+/// saturate instead of wrapping around.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    /// The number of samples consumed.
+    pub read: u64,
+    /// The number of events the kernel reported as lost.
+    pub lost: u64,
+    /// The number of bytes consumed, including the record headers.
+    pub bytes: u64,
 }
 
+// A perf ring: a perf_event_mmap_page followed by `size` bytes of data, starting `page_size`
+// bytes after the control page. The memory is owned by the containing type.
 #[derive(Debug)]
-#[repr(C)]
-struct Sample {
-    header: perf_event_header,
-    size: u32,
+pub(crate) struct Ring {
+    buf: AtomicPtr<perf_event_mmap_page>,
+    size: usize,
+    page_size: usize,
+    pub(super) sample_type: SampleType,
+    pub(super) read_format: ReadFormat,
+    pub(super) branch_sample_type: BranchSampleType,
+    pub(super) zero_on_reuse: bool,
+    // The byte order of the records, only different from the native one for replayed dumps.
+    pub(super) endianness: Endianness,
+    stats: BufferStats,
+    // The read position of rings mapped read only, which can't write data_tail back.
+    read_only_tail: Option<usize>,
+    // Whether the kernel writes the records backward, see PerfEventConfig::overwrite().
+    pub(super) backward: bool,
+    // Holds the samples that wrap around the end of the ring when they're borrowed. It grows to
+    // the largest such sample and is reused across reads.
+    scratch: Vec<u8>,
 }
 
-#[repr(C)]
 #[derive(Debug)]
-struct LostSamples {
-    header: perf_event_header,
-    id: u64,
-    count: u64,
+pub(crate) struct PerfBuffer {
+    ring: Ring,
+    fd: EventFd,
+    // The memory of the empty ring of a closed buffer, see PerfBufferError::Closed. The ring
+    // isn't mapped then.
+    closed: Option<Box<[u64]>>,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{fmt::Debug, mem};
+// The file descriptor of the perf event of a buffer. Borrowed descriptors belong to the code
+// that opened the event, which must keep them open for as long as the buffer exists. The event
+// is neither disabled nor closed when the buffer is dropped.
+#[derive(Debug)]
+pub(super) enum EventFd {
+    Owned(OwnedFd),
+    Borrowed(RawFd),
+}
 
-    use assert_matches::assert_matches;
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Owned(fd) => fd.as_fd(),
+            // Safety: the owner of the descriptor keeps it open while the buffer exists.
+            Self::Borrowed(fd) => unsafe { BorrowedFd::borrow_raw(*fd) },
+        }
+    }
+}
 
-    use super::*;
-    use crate::{
-        generated::perf_event_mmap_page,
-        sys::{override_syscall, Syscall, TEST_MMAP_RET},
-    };
+impl Deref for PerfBuffer {
+    type Target = Ring;
 
-    const PAGE_SIZE: usize = 4096;
-    union MMappedBuf {
-        mmap_page: perf_event_mmap_page,
-        data: [u8; PAGE_SIZE * 2],
+    fn deref(&self) -> &Ring {
+        &self.ring
     }
+}
 
-    fn fake_mmap(buf: &MMappedBuf) {
-        override_syscall(|call| match call {
-            Syscall::PerfEventOpen { .. } | Syscall::PerfEventIoctl { .. } => Ok(42),
-            call => panic!("unexpected syscall: {:?}", call),
-        });
-        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = buf as *const _ as *mut _);
+impl DerefMut for PerfBuffer {
+    fn deref_mut(&mut self) -> &mut Ring {
+        &mut self.ring
+    }
+}
+
+impl PerfBuffer {
+    pub(crate) fn open(
+        cpu_id: u32,
+        page_size: usize,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        Self::open_for(-1, cpu_id as i32, page_size, page_count, config)
+    }
+
+    // Opens a buffer for the event of the given process or thread on the given CPU, with the
+    // pid and cpu semantics of perf_event_open(2).
+    pub(crate) fn open_for(
+        pid: pid_t,
+        cpu: c_int,
+        page_size: usize,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        if !page_count.is_power_of_two() {
+            return Err(PerfBufferError::InvalidPageCount { page_count });
+        }
+
+        let fd = perf_event_open_attr(config.attr, pid, cpu)
+            .map_err(|(_, io_error)| PerfBufferError::OpenError { io_error })?;
+        let perf_buf = Self::from_fd(EventFd::Owned(fd), page_size, page_count, config)?;
+
+        perf_event_ioctl(perf_buf.fd.as_fd(), PERF_EVENT_IOC_ENABLE, 0)
+            .map_err(|(_, io_error)| PerfBufferError::PerfEventEnableError { io_error })?;
+
+        Ok(perf_buf)
+    }
+
+    // Maps the ring of an already opened perf event, whose attributes match `config`.
+    pub(super) fn from_fd(
+        fd: EventFd,
+        page_size: usize,
+        page_count: usize,
+        config: &PerfEventConfig,
+    ) -> Result<Self, PerfBufferError> {
+        if !page_count.is_power_of_two() {
+            return Err(PerfBufferError::InvalidPageCount { page_count });
+        }
+
+        let size = page_size * page_count;
+        // backward rings are only overwritten when they're mapped read only
+        let backward = config.attr.write_backward() != 0;
+        let read_only = config.read_only || backward;
+        let buf = mmap_ring(fd.as_fd(), size, page_size, read_only)?;
+
+        let mut perf_buf = Self {
+            ring: Ring::new(
+                buf,
+                size,
+                page_size,
+                config.get_sample_type(),
+                config.get_read_format(),
+                config.get_branch_sample_type(),
+            ),
+            fd,
+            closed: None,
+        };
+        if read_only {
+            perf_buf.ring.read_only_tail = Some(0);
+        }
+        perf_buf.ring.backward = backward;
+
+        let compat_version = unsafe {
+            ptr::read_volatile(ptr::addr_of!(
+                (*(buf as *const perf_event_mmap_page)).compat_version
+            ))
+        };
+        if compat_version > SUPPORTED_RING_VERSION {
+            return Err(PerfBufferError::IncompatibleRingVersion {
+                kernel_version: compat_version,
+                supported: SUPPORTED_RING_VERSION,
+            });
+        }
+
+        Ok(perf_buf)
     }
 
-    #[test]
-    fn test_invalid_page_count() {
-        assert_matches!(
-            PerfBuffer::open(1, PAGE_SIZE, 0),
-            Err(PerfBufferError::InvalidPageCount { .. })
-        );
-        assert_matches!(
-            PerfBuffer::open(1, PAGE_SIZE, 3),
-            Err(PerfBufferError::InvalidPageCount { .. })
-        );
-        assert_matches!(
-            PerfBuffer::open(1, PAGE_SIZE, 5),
-            Err(PerfBufferError::InvalidPageCount { .. })
-        );
+    // Replaces the ring with a new one of `page_count` pages, keeping the perf event open.
+    pub(crate) fn resize(&mut self, page_count: usize) -> Result<(), PerfBufferError> {
+        if !page_count.is_power_of_two() {
+            return Err(PerfBufferError::InvalidPageCount { page_count });
+        }
+
+        let fd = self.fd.as_fd();
+        let page_size = self.ring.page_size;
+        let old_size = self.ring.size;
+        let size = page_size * page_count;
+        let read_only = self.ring.read_only_tail.is_some();
+
+        // The kernel allows a single ring per event, so the old one must be unmapped before the
+        // new one can be mapped. The event is disabled in between so that it doesn't write to a
+        // ring that's going away.
+        let _: SysResult<_> = perf_event_ioctl(fd, PERF_EVENT_IOC_DISABLE, 0);
+        if self.closed.take().is_none() {
+            unsafe {
+                munmap(
+                    self.ring.buf.load(Ordering::SeqCst) as *mut c_void,
+                    old_size + page_size,
+                )
+            };
+        }
+        // the new ring starts empty
+        if let Some(tail) = &mut self.ring.read_only_tail {
+            *tail = 0;
+        }
+        let result = match mmap_ring(fd, size, page_size, read_only) {
+            Ok(buf) => {
+                self.ring.buf.store(buf, Ordering::SeqCst);
+                self.ring.size = size;
+                Ok(())
+            }
+            // map a ring of the old size again so that the buffer stays usable
+            Err(e) => match mmap_ring(fd, old_size, page_size, read_only) {
+                Ok(buf) => {
+                    self.ring.buf.store(buf, Ordering::SeqCst);
+                    Err(e)
+                }
+                Err(error) => {
+                    // close the buffer, leaving the event disabled and an empty ring of a single
+                    // page in place of the mapped one
+                    let mut closed =
+                        vec![0u64; 2 * page_size / size_of::<u64>()].into_boxed_slice();
+                    self.ring
+                        .buf
+                        .store(closed.as_mut_ptr().cast(), Ordering::SeqCst);
+                    self.ring.size = page_size;
+                    self.closed = Some(closed);
+                    return Err(PerfBufferError::Closed {
+                        error: Box::new(error),
+                    });
+                }
+            },
+        };
+
+        perf_event_ioctl(fd, PERF_EVENT_IOC_ENABLE, 0)
+            .map_err(|(_, io_error)| PerfBufferError::PerfEventEnableError { io_error })?;
+
+        result
+    }
+
+    pub(crate) fn open_output(
+        &self,
+        cpu_id: u32,
+        config: &PerfEventConfig,
+    ) -> Result<OwnedFd, PerfBufferError> {
+        perf_event_open_attr_output(config.attr, cpu_id as i32, self.fd.as_fd())
+            .map_err(|(_, io_error)| PerfBufferError::OpenError { io_error })
+    }
+
+    pub(crate) fn enable(&self) -> Result<(), PerfBufferError> {
+        perf_event_ioctl(self.fd.as_fd(), PERF_EVENT_IOC_ENABLE, 0)
+            .map(|_| ())
+            .map_err(|(_, io_error)| PerfBufferError::PerfEventEnableError { io_error })
+    }
+
+    pub(crate) fn disable(&self) -> Result<(), PerfBufferError> {
+        perf_event_ioctl(self.fd.as_fd(), PERF_EVENT_IOC_DISABLE, 0)
+            .map(|_| ())
+            .map_err(|(_, io_error)| PerfBufferError::PerfEventDisableError { io_error })
+    }
+
+    pub(crate) fn enabled_scope(&self) -> Result<EnableGuard<'_>, PerfBufferError> {
+        self.enable()?;
+        Ok(EnableGuard {
+            fd: self.fd.as_fd(),
+        })
+    }
+
+    pub(crate) fn event_id(&self) -> io::Result<u64> {
+        perf_event_ioctl_id(self.fd.as_fd()).map_err(|(_, io_error)| io_error)
+    }
+
+    pub(crate) fn reset(&self) -> io::Result<()> {
+        perf_event_ioctl(self.fd.as_fd(), PERF_EVENT_IOC_RESET, 0)
+            .map(|_| ())
+            .map_err(|(_, io_error)| io_error)
+    }
+
+    pub(crate) fn refresh(&self, count: u32) -> io::Result<()> {
+        let count =
+            c_int::try_from(count).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        perf_event_ioctl(self.fd.as_fd(), PERF_EVENT_IOC_REFRESH, count)
+            .map(|_| ())
+            .map_err(|(_, io_error)| io_error)
+    }
+}
+
+impl Ring {
+    pub(super) fn new(
+        buf: *mut perf_event_mmap_page,
+        size: usize,
+        page_size: usize,
+        sample_type: SampleType,
+        read_format: ReadFormat,
+        branch_sample_type: BranchSampleType,
+    ) -> Self {
+        Self {
+            buf: AtomicPtr::new(buf),
+            size,
+            page_size,
+            sample_type,
+            read_format,
+            branch_sample_type,
+            zero_on_reuse: false,
+            endianness: Endianness::default(),
+            stats: BufferStats::default(),
+            read_only_tail: None,
+            backward: false,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_zero_on_reuse(&mut self, zero_on_reuse: bool) {
+        self.zero_on_reuse = zero_on_reuse;
+    }
+
+    pub(crate) fn stats(&self) -> BufferStats {
+        self.stats
+    }
+
+    pub(crate) fn stats_and_reset(&mut self) -> BufferStats {
+        mem::take(&mut self.stats)
+    }
+
+    pub(crate) fn available_bytes(&self) -> usize {
+        let (head, tail) = self.head_tail();
+        head.wrapping_sub(tail)
+    }
+
+    pub(crate) fn readable(&self) -> bool {
+        let (head, tail) = self.head_tail();
+        head != tail
+    }
+
+    pub(crate) fn read_events(
+        &mut self,
+        buffers: &mut [BytesMut],
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_while(buffers, || true)
+    }
+
+    pub(crate) fn read_events_while(
+        &mut self,
+        buffers: &mut [BytesMut],
+        should_continue: impl Fn() -> bool,
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_inner(buffers, should_continue, 0, |_| {}, |_| {})
+    }
+
+    pub(crate) fn read_events_while_interval(
+        &mut self,
+        buffers: &mut [BytesMut],
+        budget_check_interval: usize,
+        should_continue: impl Fn() -> bool,
+    ) -> Result<Events, PerfBufferError> {
+        let interval = budget_check_interval.max(1);
+        let records = Cell::new(0usize);
+        self.read_events_inner(
+            buffers,
+            || {
+                let n = records.get();
+                records.set(n + 1);
+                n % interval != 0 || should_continue()
+            },
+            0,
+            |_| {},
+            |_| {},
+        )
+    }
+
+    pub(crate) fn read_events_min_size(
+        &mut self,
+        buffers: &mut [BytesMut],
+        min_sample_size: usize,
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_inner(buffers, || true, min_sample_size, |_| {}, |_| {})
+    }
+
+    pub(crate) fn read_events_tee(
+        &mut self,
+        buffers: &mut [BytesMut],
+        tee: &Sender<Record<'static>>,
+    ) -> Result<Events, PerfBufferError> {
+        let events = self.read_events(buffers)?;
+        // a disconnected receiver only stops the forwarding
+        if events.lost > 0 {
+            let _: Result<(), SendError<_>> = tee.send(Record::Lost(events.lost as u64));
+        }
+        for buf in events.filled(buffers) {
+            let _: Result<(), SendError<_>> = tee.send(Record::Sample(Cow::Owned(buf.to_vec())));
+        }
+        Ok(events)
+    }
+
+    pub(crate) fn read_events_throttled(
+        &mut self,
+        buffers: &mut [BytesMut],
+        on_throttle: impl FnMut(Throttle),
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_inner(buffers, || true, 0, on_throttle, |_| {})
+    }
+
+    pub(crate) fn read_events_lost(
+        &mut self,
+        buffers: &mut [BytesMut],
+        on_lost: impl FnMut(Lost),
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_inner(buffers, || true, 0, |_| {}, on_lost)
+    }
+
+    pub(crate) fn drain(
+        &mut self,
+        pool: &mut Vec<BytesMut>,
+        mut f: impl FnMut(&BytesMut),
+    ) -> Result<Events, PerfBufferError> {
+        if pool.is_empty() {
+            pool.push(BytesMut::new());
+        }
+
+        let mut total = Events::new(0, 0);
+        // every read consumes at least one record, so this stops once the ring is empty
+        while self.readable() {
+            let events = self.read_events(pool)?;
+            events.filled(pool).iter().for_each(&mut f);
+            total += events;
+        }
+        Ok(total)
+    }
+
+    fn read_events_inner(
+        &mut self,
+        buffers: &mut [BytesMut],
+        should_continue: impl Fn() -> bool,
+        min_sample_size: usize,
+        mut on_throttle: impl FnMut(Throttle),
+        mut on_lost: impl FnMut(Lost),
+    ) -> Result<Events, PerfBufferError> {
+        if buffers.is_empty() {
+            return Err(PerfBufferError::NoBuffers);
+        }
+        self.check_raw_data()?;
+
+        let mut events = Events::new(0, 0);
+        let mut buf_n = 0;
+
+        self.consume(|this, event_start, event| {
+            if buf_n == buffers.len() || !should_continue() {
+                return Ok(ControlFlow::Break(()));
+            }
+
+            let record = this.record_kind(event_start, event);
+            match record {
+                RecordKind::Sample => {
+                    let (sample_start, sample_size) = this.raw_data(event_start)?;
+                    if sample_size < min_sample_size {
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                    let buf = &mut buffers[buf_n];
+                    if this.zero_on_reuse {
+                        zero_capacity(buf);
+                    }
+                    buf.clear();
+                    buf.reserve(sample_size);
+                    unsafe { buf.set_len(sample_size) };
+
+                    this.fill_buf(sample_start, buf);
+
+                    buf_n += 1;
+                }
+                RecordKind::Lost(lost) => on_lost(lost),
+                RecordKind::Throttle(throttle) => on_throttle(throttle),
+                RecordKind::Unthrottle | RecordKind::Other => {}
+            }
+            events.count(&record);
+            Ok::<_, PerfBufferError>(ControlFlow::Continue(()))
+        })?;
+
+        Ok(events)
+    }
+
+    pub(crate) fn read_samples(
+        &mut self,
+        samples: &mut [Sample],
+    ) -> Result<Events, PerfBufferError> {
+        if samples.is_empty() {
+            return Err(PerfBufferError::NoBuffers);
+        }
+
+        let mut events = Events::new(0, 0);
+        let mut sample_n = 0;
+
+        self.consume(|this, event_start, event| {
+            if sample_n == samples.len() {
+                return Ok(ControlFlow::Break(()));
+            }
+
+            let record = this.record_kind(event_start, event);
+            if let RecordKind::Sample = record {
+                this.read_sample(event_start, &mut samples[sample_n])?;
+                sample_n += 1;
+            }
+            events.count(&record);
+            Ok::<_, PerfBufferError>(ControlFlow::Continue(()))
+        })?;
+
+        Ok(events)
+    }
+
+    pub(crate) fn read_events_split<I: IntoIterator<Item = Range<usize>>>(
+        &mut self,
+        mut split: impl FnMut(&[u8]) -> I,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<Events, PerfBufferError> {
+        self.consume_samples(|payload| {
+            for range in split(payload) {
+                f(&payload[range]);
+            }
+            Ok::<_, PerfBufferError>(Sampled::Read)
+        })
+    }
+
+    pub(crate) fn read_events_borrowed(
+        &mut self,
+        f: impl FnMut(&[u8]),
+    ) -> Result<Events, PerfBufferError> {
+        self.read_events_split(|payload| iter::once(0..payload.len()), f)
+    }
+
+    pub(crate) fn read_events_decoded<E: From<PerfBufferError>>(
+        &mut self,
+        mut decode: impl FnMut(&[u8]) -> Result<Vec<u8>, E>,
+        mut f: impl FnMut(Vec<u8>),
+    ) -> Result<Events, E> {
+        // the sample that failed to decode is consumed, so that it doesn't block the buffer
+        self.consume_samples(|payload| {
+            f(decode(payload)?);
+            Ok(Sampled::Read)
+        })
+    }
+
+    pub(crate) fn read_events_coalesced(
+        &mut self,
+        eq: impl Fn(&[u8], &[u8]) -> bool,
+    ) -> Result<(Coalesced, Events), PerfBufferError> {
+        let mut coalesced: Coalesced = Vec::new();
+        let events = self.consume_samples(|payload| {
+            match coalesced.last_mut() {
+                Some((last, count)) if eq(last, payload) => *count += 1,
+                _ => coalesced.push((payload.to_vec(), 1)),
+            }
+            Ok::<_, PerfBufferError>(Sampled::Read)
+        })?;
+
+        Ok((coalesced, events))
+    }
+
+    pub(crate) fn read_events_batched(
+        &mut self,
+        out: &mut Vec<u8>,
+        ranges: &mut Vec<Range<usize>>,
+    ) -> Result<Events, PerfBufferError> {
+        self.consume_samples(|payload| {
+            let start = out.len();
+            out.extend_from_slice(payload);
+            ranges.push(start..out.len());
+            Ok::<_, PerfBufferError>(Sampled::Read)
+        })
+    }
+
+    pub(crate) fn for_each_sample(
+        &mut self,
+        mut f: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<Events, PerfBufferError> {
+        // the sample that requested the stop has been processed, so it's consumed too
+        let mut events = self.consume_samples(|payload| {
+            Ok::<_, PerfBufferError>(match f(payload) {
+                ControlFlow::Continue(()) => Sampled::Read,
+                ControlFlow::Break(()) => Sampled::Last,
+            })
+        })?;
+
+        events.remaining = self.pending_records();
+        Ok(events)
+    }
+
+    pub(crate) fn discard_events(&mut self) -> Result<Events, PerfBufferError> {
+        let mut events = Events::new(0, 0);
+
+        self.consume(|this, event_start, event| {
+            events.count(&this.record_kind(event_start, event));
+            Ok::<_, PerfBufferError>(ControlFlow::Continue(()))
+        })?;
+
+        Ok(events)
+    }
+
+    // Consumes the records like consume(), calling `f` with the raw data of each sample, and
+    // returns the counts of the consumed records. The raw data is borrowed from the ring unless
+    // it wraps around its end, in which case it's copied into the scratch buffer.
+    //
+    // Returning an error from `f` consumes the sample and stops the walk, like with consume().
+    pub(super) fn consume_samples<E: From<PerfBufferError>>(
+        &mut self,
+        mut f: impl FnMut(&[u8]) -> Result<Sampled, E>,
+    ) -> Result<Events, E> {
+        self.check_raw_data()?;
+        let mut events = Events::new(0, 0);
+        let mut scratch = mem::take(&mut self.scratch);
+        let mut stop = false;
+
+        let result = self.consume(|this, event_start, event| {
+            if stop {
+                return Ok(ControlFlow::Break(()));
+            }
+            let record = this.record_kind(event_start, event);
+            if let RecordKind::Sample = record {
+                let (sample_start, sample_size) = this.raw_data(event_start)?;
+                match f(this.data_slice(sample_start, sample_size, &mut scratch))? {
+                    Sampled::Read => {}
+                    Sampled::Last => stop = true,
+                    Sampled::Left => return Ok(ControlFlow::Break(())),
+                }
+            }
+            events.count(&record);
+            Ok::<_, E>(ControlFlow::Continue(()))
+        });
+        self.scratch = scratch;
+        result?;
+
+        Ok(events)
+    }
+
+    // Decodes the type of the record of `event` starting at `event_start`.
+    pub(super) fn record_kind(&self, event_start: usize, event: &perf_event_header) -> RecordKind {
+        match event.type_ {
+            x if x == PERF_RECORD_SAMPLE as u32 => RecordKind::Sample,
+            x if x == PERF_RECORD_LOST as u32 => RecordKind::Lost(self.read_lost(event_start)),
+            x if x == PERF_RECORD_THROTTLE as u32 => {
+                RecordKind::Throttle(self.read_throttle(event_start))
+            }
+            x if x == PERF_RECORD_UNTHROTTLE as u32 => RecordKind::Unthrottle,
+            _ => RecordKind::Other,
+        }
+    }
+
+    // Fails if the samples have no raw data, for the readers that return it.
+    pub(super) fn check_raw_data(&self) -> Result<(), PerfBufferError> {
+        if self.sample_type.contains(SampleType::RAW) {
+            Ok(())
+        } else {
+            Err(PerfBufferError::NoRawData)
+        }
+    }
+
+    // Returns true if the record of `event` starting at `tail` fits in the records written up to
+    // `head`. A zero size in particular would never advance the read position.
+    pub(super) fn valid_event_size(head: usize, tail: usize, event: &perf_event_header) -> bool {
+        let size = event.size as usize;
+        size >= HEADER_SIZE && size <= head.wrapping_sub(tail)
+    }
+
+    // Walks the records between data_tail and data_head, calling `f` with the offset and header of
+    // each record. Returning `ControlFlow::Break` stops the walk, leaving the current record in the
+    // ring. Returning an error stops the walk too, but consumes the current record, so that a
+    // record that can't be decoded doesn't block the ring. data_tail is always written back to
+    // reflect the consumed records.
+    //
+    // A record with an invalid size stops the walk with PerfBufferError::InvalidEventSize. It and
+    // the following records are discarded, since the start of the next record can't be found.
+    pub(super) fn consume<E: From<PerfBufferError>>(
+        &mut self,
+        mut f: impl FnMut(&Self, usize, &perf_event_header) -> Result<ControlFlow<()>, E>,
+    ) -> Result<(), E> {
+        if self.backward {
+            return self.consume_backward(f);
+        }
+
+        let (head, mut tail) = self.head_tail();
+        let result = loop {
+            if head == tail {
+                break Ok(());
+            }
+
+            let event_start = tail % self.size;
+            let event = self.header_at(event_start);
+            if !Self::valid_event_size(head, tail, &event) {
+                tail = head;
+                break Err(PerfBufferError::InvalidEventSize {
+                    offset: event_start,
+                    size: event.size,
+                }
+                .into());
+            }
+
+            let result = f(self, event_start, &event);
+            if let Ok(ControlFlow::Break(())) = result {
+                break Ok(());
+            }
+            self.account(event_start, &event);
+            tail = tail.wrapping_add(event.size as usize);
+            if let Err(e) = result {
+                break Err(e);
+            }
+        };
+
+        self.set_tail(tail);
+
+        result
+    }
+
+    // Like consume(), for rings written backward. The unread records start at data_head, newest
+    // first, so they're collected and then passed to `f` in reverse.
+    //
+    // A record with an invalid size ends the walk. The newer records are still passed to `f`, and
+    // the invalid record and the older ones are discarded.
+    fn consume_backward<E: From<PerfBufferError>>(
+        &mut self,
+        mut f: impl FnMut(&Self, usize, &perf_event_header) -> Result<ControlFlow<()>, E>,
+    ) -> Result<(), E> {
+        let (end, head) = self.head_tail();
+        let mut starts = Vec::new();
+        let mut invalid = None;
+        let mut pos = head;
+        while pos.wrapping_sub(head) < end.wrapping_sub(head) {
+            let event = self.header_at(pos % self.size);
+            if !Self::valid_event_size(end, pos, &event) {
+                invalid = Some(PerfBufferError::InvalidEventSize {
+                    offset: pos % self.size,
+                    size: event.size,
+                });
+                break;
+            }
+            starts.push(pos);
+            pos = pos.wrapping_add(event.size as usize);
+        }
+
+        // the records that aren't consumed are the newest ones, right after data_head
+        let mut unread_end = head;
+        let mut result = invalid.map_or(Ok(()), |e| Err(e.into()));
+        for start in starts.into_iter().rev() {
+            let event_start = start % self.size;
+            let event = self.header_at(event_start);
+            match f(self, event_start, &event) {
+                Ok(ControlFlow::Continue(())) => {}
+                Ok(ControlFlow::Break(())) => {
+                    unread_end = start.wrapping_add(event.size as usize);
+                    break;
+                }
+                Err(e) => {
+                    self.account(event_start, &event);
+                    unread_end = start;
+                    result = Err(e);
+                    break;
+                }
+            }
+            self.account(event_start, &event);
+        }
+
+        self.set_tail(unread_end);
+
+        result
+    }
+
+    // Adds a consumed record to the lifetime counters.
+    pub(super) fn account(&mut self, event_start: usize, event: &perf_event_header) {
+        match self.record_kind(event_start, event) {
+            RecordKind::Sample => self.stats.read = self.stats.read.saturating_add(1),
+            RecordKind::Lost(lost) => self.stats.lost = self.stats.lost.saturating_add(lost.count),
+            RecordKind::Throttle(_) | RecordKind::Unthrottle | RecordKind::Other => {}
+        }
+        self.stats.bytes = self.stats.bytes.saturating_add(u64::from(event.size));
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    // Identifies the ring by the address it's mapped at, which no other live ring shares.
+    pub(super) fn id(&self) -> usize {
+        self.buf.load(Ordering::SeqCst) as usize
+    }
+
+    // Returns data_head and data_tail.
+    //
+    // For rings written backward, returns the end and the start of the unread records instead,
+    // so that walking from the second to the first visits them all, newest first.
+    pub(super) fn head_tail(&self) -> (usize, usize) {
+        let header = self.buf.load(Ordering::SeqCst);
+        let head = unsafe { (*header).data_head } as usize;
+        if let (true, Some(tail)) = (self.backward, self.read_only_tail) {
+            return (self.backward_end(head, tail), head);
+        }
+        let tail = match self.read_only_tail {
+            // The kernel overwrites read only rings. Once the reader is more than a ring behind,
+            // the oldest records are gone and the next record boundary is unknown, so reading
+            // starts over from data_head.
+            Some(tail) if head.wrapping_sub(tail) > self.size => head,
+            Some(tail) => tail,
+            None => unsafe { (*header).data_tail as usize },
+        };
+        (head, tail)
+    }
+
+    // Returns the end of the unread records of a ring written backward, which start at
+    // `head` and end at the read position `tail`. When the reader is more than a ring behind,
+    // the oldest records have been overwritten and the unread records end at the last record
+    // that fits in the ring. Never written space is zeroed, which ends the records too.
+    fn backward_end(&self, head: usize, tail: usize) -> usize {
+        let unread = tail.wrapping_sub(head).min(self.size);
+        let mut end = head;
+        while end.wrapping_sub(head) < unread {
+            let event = self.header_at(end % self.size);
+            let next = end.wrapping_add(event.size as usize);
+            if event.size == 0 || next.wrapping_sub(head) > unread {
+                break;
+            }
+            end = next;
+        }
+        end
+    }
+
+    // Writes data_tail back, releasing the records before it to the kernel.
+    //
+    // Positions are truncated to usize, which is fine for indexing since the size of the ring
+    // is a power of two, and for distances since they're computed with wrapping arithmetic. On
+    // 32 bit targets data_tail is advanced by the distance read rather than overwritten, so that
+    // its upper bits stay in sync with data_head once more than 4GiB have been written.
+    pub(super) fn set_tail(&mut self, tail: usize) {
+        if let Some(read_only_tail) = &mut self.read_only_tail {
+            *read_only_tail = tail;
+            return;
+        }
+        let header = self.buf.load(Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        unsafe {
+            let data_tail = (*header).data_tail;
+            (*header).data_tail =
+                data_tail.wrapping_add(tail.wrapping_sub(data_tail as usize) as u64);
+        }
+    }
+
+    // Reads the header of the record starting at `event_start`. Records are 8 byte aligned so
+    // headers never wrap around.
+    pub(super) fn header_at(&self, event_start: usize) -> perf_event_header {
+        let base = self.buf.load(Ordering::SeqCst) as usize + self.page_size;
+        let header =
+            unsafe { ptr::read_unaligned((base + event_start) as *const perf_event_header) };
+        let endianness = self.endianness;
+        perf_event_header {
+            type_: endianness.read_u32(header.type_),
+            misc: endianness.read_u16(header.misc),
+            size: endianness.read_u16(header.size),
+        }
+    }
+
+    // Formats the fields of the control page, one per line.
+    pub(crate) fn dump_page(&self) -> String {
+        let page = unsafe { ptr::read_volatile(self.buf.load(Ordering::SeqCst)) };
+        let capabilities = unsafe { page.__bindgen_anon_1.capabilities };
+        let mut out = String::new();
+        for (name, value) in [
+            ("version", u64::from(page.version)),
+            ("compat_version", u64::from(page.compat_version)),
+            ("lock", u64::from(page.lock)),
+            ("index", u64::from(page.index)),
+            ("offset", page.offset as u64),
+            ("time_enabled", page.time_enabled),
+            ("time_running", page.time_running),
+            ("capabilities", capabilities),
+            ("pmc_width", u64::from(page.pmc_width)),
+            ("time_shift", u64::from(page.time_shift)),
+            ("time_mult", u64::from(page.time_mult)),
+            ("time_offset", page.time_offset),
+            ("time_zero", page.time_zero),
+            ("size", u64::from(page.size)),
+            ("time_cycles", page.time_cycles),
+            ("time_mask", page.time_mask),
+            ("data_head", page.data_head),
+            ("data_tail", page.data_tail),
+            ("data_offset", page.data_offset),
+            ("data_size", page.data_size),
+            ("aux_head", page.aux_head),
+            ("aux_tail", page.aux_tail),
+            ("aux_offset", page.aux_offset),
+            ("aux_size", page.aux_size),
+        ] {
+            let _: fmt::Result = writeln!(out, "{name}: {value:#x}");
+        }
+        out
+    }
+
+    // Returns time_enabled / time_running from the control page, or 1.0 when the event has
+    // either run the whole time or not run at all.
+    pub(crate) fn scaling_factor(&self) -> f64 {
+        let header = self.buf.load(Ordering::SeqCst);
+        let (enabled, running) = unsafe {
+            (
+                ptr::read_volatile(ptr::addr_of!((*header).time_enabled)),
+                ptr::read_volatile(ptr::addr_of!((*header).time_running)),
+            )
+        };
+        if running == 0 || running >= enabled {
+            return 1.0;
+        }
+        enabled as f64 / running as f64
+    }
+
+    pub(crate) fn is_multiplexed(&self) -> bool {
+        self.scaling_factor() > 1.0
+    }
+
+    // Counts the samples between data_tail and data_head without consuming them.
+    pub(crate) fn pending_records(&self) -> usize {
+        self.pending_headers()
+            .filter(|event| event.type_ == PERF_RECORD_SAMPLE as u32)
+            .count()
+    }
+
+    pub(crate) fn peek_events(&self) -> usize {
+        self.pending_headers().count()
+    }
+
+    // Walks the headers of the records between data_tail and data_head without consuming them.
+    fn pending_headers(&self) -> impl Iterator<Item = perf_event_header> + '_ {
+        let (head, mut tail) = self.head_tail();
+        iter::from_fn(move || {
+            if tail == head {
+                return None;
+            }
+            let event = self.header_at(tail % self.size);
+            if !Self::valid_event_size(head, tail, &event) {
+                return None;
+            }
+            tail = tail.wrapping_add(event.size as usize);
+            Some(event)
+        })
+    }
+
+    // Copies the records between data_tail and data_head without consuming them.
+    pub(super) fn pending_data(&self) -> Vec<u8> {
+        let (head, tail) = self.head_tail();
+        let mut data = vec![0; head.wrapping_sub(tail)];
+        if !data.is_empty() {
+            self.fill_buf(tail, &mut data);
+        }
+        data
+    }
+
+    // Copies `out_buf.len()` bytes starting at `start_off`, handling wrap around.
+    pub(super) fn fill_buf(&self, start_off: usize, out_buf: &mut [u8]) {
+        // Safety: u8 and MaybeUninit<u8> have the same layout, and fill_uninit() only writes
+        // initialized bytes.
+        self.fill_uninit(start_off, unsafe {
+            &mut *(out_buf as *mut [u8] as *mut [MaybeUninit<u8>])
+        })
+    }
+
+    // Like fill_buf(), but writes to possibly uninitialized memory.
+    pub(super) fn fill_uninit(&self, start_off: usize, out_buf: &mut [MaybeUninit<u8>]) {
+        let base = self.buf.load(Ordering::SeqCst) as usize + self.page_size;
+        let len = out_buf.len();
+        let dst = out_buf.as_mut_ptr() as *mut u8;
+        // an empty copy would otherwise look like one wrapping around the whole ring
+        if len == 0 {
+            return;
+        }
+        assert!(len <= self.size);
+
+        let end = (start_off + len) % self.size;
+        let start = start_off % self.size;
+
+        if start < end {
+            unsafe { ptr::copy_nonoverlapping((base + start) as *const u8, dst, len) };
+        } else {
+            let size = self.size - start;
+            unsafe {
+                ptr::copy_nonoverlapping((base + start) as *const u8, dst, size);
+                ptr::copy_nonoverlapping(base as *const u8, dst.add(size), len - size);
+            }
+        }
+    }
+
+    // Returns the `len` bytes starting at `start_off`. The ring is borrowed directly unless the
+    // data wraps around, in which case it's copied into `scratch`.
+    pub(super) fn data_slice<'a>(
+        &'a self,
+        start_off: usize,
+        len: usize,
+        scratch: &'a mut Vec<u8>,
+    ) -> &'a [u8] {
+        let base = self.buf.load(Ordering::SeqCst) as usize + self.page_size;
+        let start = start_off % self.size;
+        if start + len <= self.size {
+            return unsafe { slice::from_raw_parts((base + start) as *const u8, len) };
+        }
+        scratch.clear();
+        scratch.reserve(len);
+        self.fill_uninit(start, &mut scratch.spare_capacity_mut()[..len]);
+        // Safety: fill_uninit() initialized the first `len` bytes.
+        unsafe { scratch.set_len(len) };
+        scratch
+    }
+
+    // Like data_slice(), but returns an owned copy when the data wraps around.
+    pub(super) fn data_cow(&self, start_off: usize, len: usize) -> Cow<'_, [u8]> {
+        let start = start_off % self.size;
+        if start + len <= self.size {
+            let base = self.buf.load(Ordering::SeqCst) as usize + self.page_size;
+            return Cow::Borrowed(unsafe {
+                slice::from_raw_parts((base + start) as *const u8, len)
+            });
+        }
+        let mut data = vec![0; len];
+        self.fill_buf(start, &mut data);
+        Cow::Owned(data)
+    }
+
+    pub(super) fn read_u32(&self, start_off: usize) -> u32 {
+        let mut value = [0u8; size_of::<u32>()];
+        self.fill_buf(start_off, &mut value);
+        self.endianness.read_u32_bytes(value)
+    }
+
+    pub(super) fn read_u64(&self, start_off: usize) -> u64 {
+        let mut value = [0u8; size_of::<u64>()];
+        self.fill_buf(start_off, &mut value);
+        self.endianness.read_u64_bytes(value)
+    }
+
+    // Decodes a PERF_RECORD_THROTTLE record.
+    fn read_throttle(&self, event_start: usize) -> Throttle {
+        let time = event_start + HEADER_SIZE;
+        Throttle {
+            time: self.read_u64(time),
+            id: self.read_u64(time + size_of::<u64>()),
+        }
+    }
+
+    // Decodes a PERF_RECORD_LOST record.
+    fn read_lost(&self, event_start: usize) -> Lost {
+        let id = event_start + HEADER_SIZE;
+        Lost {
+            id: self.read_u64(id),
+            count: self.read_u64(id + size_of::<u64>()),
+        }
+    }
+}
+
+impl AsRawFd for PerfBuffer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_fd().as_raw_fd()
+    }
+}
+
+impl AsFd for PerfBuffer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+// Maps the ring of the perf event `fd`: a metadata page followed by `size` bytes of data.
+fn mmap_ring(
+    fd: BorrowedFd<'_>,
+    size: usize,
+    page_size: usize,
+    read_only: bool,
+) -> Result<*mut perf_event_mmap_page, PerfBufferError> {
+    let prot = if read_only {
+        PROT_READ
+    } else {
+        PROT_READ | PROT_WRITE
+    };
+    let buf = unsafe { mmap(ptr::null_mut(), size + page_size, prot, MAP_SHARED, fd, 0) };
+    if buf == MAP_FAILED {
+        let io_error = io::Error::last_os_error();
+        return Err(match memlock_limit() {
+            Some(limit) if io_error.raw_os_error() == Some(EPERM) => {
+                PerfBufferError::MemlockLimitExceeded {
+                    size: size + page_size,
+                    limit,
+                }
+            }
+            _ => PerfBufferError::MMapError { io_error },
+        });
+    }
+    Ok(buf as *mut perf_event_mmap_page)
+}
+
+impl Drop for PerfBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            if let EventFd::Owned(fd) = &self.fd {
+                let _: SysResult<_> = perf_event_ioctl(fd.as_fd(), PERF_EVENT_IOC_DISABLE, 0);
+            }
+            if self.closed.is_none() {
+                munmap(
+                    self.ring.buf.load(Ordering::SeqCst) as *mut c_void,
+                    self.ring.size + self.ring.page_size,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_int, fmt::Debug, mem};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::{
+        generated::{
+            perf_event_mmap_page,
+            perf_hw_id::PERF_COUNT_HW_CPU_CYCLES,
+            perf_type_id::{PERF_TYPE_HARDWARE, PERF_TYPE_RAW},
+            PERF_FLAG_FD_CLOEXEC, PERF_FLAG_FD_OUTPUT,
+        },
+        maps::perf::{BranchEntry, ReadValues, Records},
+        sys::{override_syscall, Syscall, TEST_MMAP_RET},
+        PERF_EVENT_IOC_ID,
+    };
+
+    #[derive(Debug)]
+    #[repr(C)]
+    struct RawSample {
+        header: perf_event_header,
+        size: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Debug)]
+    struct LostSamples {
+        header: perf_event_header,
+        id: u64,
+        count: u64,
+    }
+
+    const PAGE_SIZE: usize = 4096;
+    union MMappedBuf {
+        mmap_page: perf_event_mmap_page,
+        data: [u8; PAGE_SIZE * 2],
+    }
+
+    fn fake_mmap(buf: &MMappedBuf) {
+        override_syscall(|call| match call {
+            Syscall::PerfEventOpen { .. } | Syscall::PerfEventIoctl { .. } => Ok(42),
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = buf as *const _ as *mut _);
+    }
+
+    #[test]
+    fn test_invalid_page_count() {
+        assert_matches!(
+            PerfBuffer::open(1, PAGE_SIZE, 0, &PerfEventConfig::new()),
+            Err(PerfBufferError::InvalidPageCount { .. })
+        );
+        assert_matches!(
+            PerfBuffer::open(1, PAGE_SIZE, 3, &PerfEventConfig::new()),
+            Err(PerfBufferError::InvalidPageCount { .. })
+        );
+        assert_matches!(
+            PerfBuffer::open(1, PAGE_SIZE, 5, &PerfEventConfig::new()),
+            Err(PerfBufferError::InvalidPageCount { .. })
+        );
+        assert_eq!(
+            PerfBufferError::InvalidPageCount { page_count: 3 }.to_string(),
+            "invalid page count 3, the value must be a power of two number of data pages, \
+             excluding the metadata page"
+        );
+    }
+
+    #[test]
+    fn test_config_raw_event() {
+        let mut config = PerfEventConfig::new();
+        config
+            .event(PerfTypeId::Raw, 0x1a2)
+            .config1(0x3)
+            .config2(0x4);
+        let attr = config.attr;
+        assert_eq!(attr.type_, PERF_TYPE_RAW as u32);
+        assert_eq!(attr.config, 0x1a2);
+        assert_eq!(unsafe { attr.__bindgen_anon_3.config1 }, 0x3);
+        assert_eq!(unsafe { attr.__bindgen_anon_4.config2 }, 0x4);
+    }
+
+    #[test]
+    fn test_config_from_attr() {
+        let mut attr = *PerfEventConfig::new().attr();
+        attr.type_ = PERF_TYPE_HARDWARE as u32;
+        attr.config = PERF_COUNT_HW_CPU_CYCLES as u64;
+        attr.__bindgen_anon_1.sample_freq = 99;
+        attr.set_freq(1);
+        attr.sample_type = (SampleType::TID | SampleType::RAW).bits();
+
+        let config = PerfEventConfig::from_attr(attr);
+        assert_eq!(config.attr().type_, PERF_TYPE_HARDWARE as u32);
+        assert_eq!(config.attr().config, PERF_COUNT_HW_CPU_CYCLES as u64);
+        assert_eq!(config.attr().freq(), 1);
+        assert_eq!(config.get_sample_type(), SampleType::TID | SampleType::RAW);
+    }
+
+    #[test]
+    fn test_config_clockid() {
+        let mut config = PerfEventConfig::new();
+        assert_eq!(config.attr.use_clockid(), 0);
+        config.clockid(libc::CLOCK_MONOTONIC);
+        assert_eq!(config.attr.use_clockid(), 1);
+        assert_eq!(config.attr.clockid, libc::CLOCK_MONOTONIC);
+    }
+
+    #[test]
+    fn test_config_sampling_and_watermark() {
+        let mut config = PerfEventConfig::new();
+        config.sample_freq(99).wakeup_watermark(4096);
+        assert_eq!(config.attr.freq(), 1);
+        assert_eq!(unsafe { config.attr.__bindgen_anon_1.sample_freq }, 99);
+        assert_eq!(config.attr.watermark(), 1);
+        assert_eq!(
+            unsafe { config.attr.__bindgen_anon_2.wakeup_watermark },
+            4096
+        );
+
+        config.sample_period(1000);
+        assert_eq!(config.attr.freq(), 0);
+        assert_eq!(unsafe { config.attr.__bindgen_anon_1.sample_period }, 1000);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_enabled_scope() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        thread_local! {
+            static REQUESTS: std::cell::RefCell<Vec<c_int>> = Default::default();
+        }
+        override_syscall(|call| match call {
+            Syscall::PerfEventIoctl { request, .. } => {
+                REQUESTS.with(|requests| requests.borrow_mut().push(request));
+                Ok(0)
+            }
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        let requests = || REQUESTS.with(|requests| requests.borrow().clone());
+
+        {
+            let _guard = buf.enabled_scope().unwrap();
+            assert_eq!(requests(), [PERF_EVENT_IOC_ENABLE]);
+        }
+        assert_eq!(requests(), [PERF_EVENT_IOC_ENABLE, PERF_EVENT_IOC_DISABLE]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_borrowed_fd() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf =
+            PerfBuffer::from_fd(EventFd::Borrowed(42), PAGE_SIZE, 1, &PerfEventConfig::new())
+                .unwrap();
+        assert_eq!(buf.as_raw_fd(), 42);
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let mut out_bufs = [BytesMut::new()];
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, next as u64);
+
+        // the event belongs to the caller, so it isn't disabled on drop
+        override_syscall(|call| panic!("unexpected syscall: {:?}", call));
+        drop(buf);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_disable_enable() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        thread_local! {
+            static REQUESTS: std::cell::RefCell<Vec<c_int>> = Default::default();
+        }
+        override_syscall(|call| match call {
+            Syscall::PerfEventIoctl { request, .. } => {
+                REQUESTS.with(|requests| requests.borrow_mut().push(request));
+                Ok(0)
+            }
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        let requests = || REQUESTS.with(|requests| requests.borrow().clone());
+
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        buf.disable().unwrap();
+        assert_eq!(requests(), [PERF_EVENT_IOC_DISABLE]);
+
+        // the samples written before disabling can still be read
+        let mut out_bufs = [BytesMut::new()];
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+
+        buf.enable().unwrap();
+        assert_eq!(requests(), [PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE]);
+
+        override_syscall(|_| Err((-1, io::Error::from_raw_os_error(libc::EBADF))));
+        assert_matches!(
+            buf.disable(),
+            Err(PerfBufferError::PerfEventDisableError { io_error }) if io_error.raw_os_error() == Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dump_page() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        mmapped_buf.mmap_page.version = 1;
+        mmapped_buf.mmap_page.data_head = 0x40;
+        let dump = buf.dump_page();
+        assert_eq!(dump.lines().count(), 24);
+        assert!(dump.contains("version: 0x1\n"));
+        assert!(dump.contains("data_head: 0x40\n"));
+        assert!(dump.contains("data_tail: 0x0\n"));
+        // dumping doesn't consume anything
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_incompatible_ring_version() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        mmapped_buf.mmap_page.compat_version = SUPPORTED_RING_VERSION + 1;
+        fake_mmap(&mmapped_buf);
+        assert_matches!(
+            PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()),
+            Err(PerfBufferError::IncompatibleRingVersion {
+                kernel_version,
+                supported: SUPPORTED_RING_VERSION,
+            }) if kernel_version == SUPPORTED_RING_VERSION + 1
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_scaling_factor() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        // never ran
+        assert_eq!(buf.scaling_factor(), 1.0);
+        assert!(!buf.is_multiplexed());
+
+        mmapped_buf.mmap_page.time_enabled = 100;
+        mmapped_buf.mmap_page.time_running = 100;
+        assert_eq!(buf.scaling_factor(), 1.0);
+        assert!(!buf.is_multiplexed());
+
+        mmapped_buf.mmap_page.time_running = 25;
+        assert_eq!(buf.scaling_factor(), 4.0);
+        assert!(buf.is_multiplexed());
+        assert_eq!(unsafe { mmapped_buf.mmap_page.time_running }, 25);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_refresh() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        override_syscall(|call| match call {
+            Syscall::PerfEventIoctl { request, arg, .. } => {
+                if request == PERF_EVENT_IOC_REFRESH {
+                    assert_eq!(arg, 3);
+                }
+                Ok(0)
+            }
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        buf.refresh(3).unwrap();
+        assert_eq!(
+            buf.refresh(u32::MAX).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_event_id() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        override_syscall(|call| match call {
+            Syscall::PerfEventIoctlPtr { request, arg, .. } => {
+                assert_eq!(request, PERF_EVENT_IOC_ID);
+                unsafe { *(arg as *mut u64) = 1234 };
+                Ok(0)
+            }
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        assert_eq!(buf.event_id().unwrap(), 1234);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_for_thread() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        override_syscall(|call| match call {
+            Syscall::PerfEventOpen { pid, cpu, .. } => {
+                assert_eq!(pid, 1234);
+                assert_eq!(cpu, -1);
+                Ok(42)
+            }
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        PerfBuffer::open_for(1234, -1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_output() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        override_syscall(|call| match call {
+            Syscall::PerfEventOpen {
+                cpu, group, flags, ..
+            } => {
+                assert_eq!(cpu, 2);
+                assert_eq!(group, 42);
+                assert_eq!(flags, PERF_FLAG_FD_CLOEXEC | PERF_FLAG_FD_OUTPUT);
+                Ok(43)
+            }
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall: {:?}", call),
+        });
+        let fd = buf.open_output(2, &PerfEventConfig::new()).unwrap();
+        assert_eq!(fd.as_raw_fd(), 43);
+        // the fd isn't a real file descriptor
+        mem::forget(fd);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_no_out_bufs() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        assert_matches!(buf.read_events(&mut []), Err(PerfBufferError::NoBuffers))
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_no_events() {
+        let mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        let out_buf = BytesMut::with_capacity(4);
+        assert_eq!(buf.read_events(&mut [out_buf]).unwrap(), Events::new(0, 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_first_lost() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+
+        let evt = LostSamples {
+            header: perf_event_header {
+                type_: PERF_RECORD_LOST as u32,
+                misc: 0,
+                size: size_of::<LostSamples>() as u16,
+            },
+            id: 1,
+            count: 0xCAFEBABE,
+        };
+        write(&mut mmapped_buf, 0, evt);
+
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        let out_buf = BytesMut::with_capacity(0);
+        let events = buf.read_events(&mut [out_buf]).unwrap();
+        assert_eq!(events.lost, 0xCAFEBABE);
+    }
+
+    #[repr(C)]
+    #[derive(Debug)]
+    struct PerfSample<T: Debug> {
+        s_hdr: RawSample,
+        value: T,
+    }
+
+    fn write<T: Debug>(mmapped_buf: &mut MMappedBuf, offset: usize, value: T) -> usize {
+        let dst = (mmapped_buf as *const _ as usize + PAGE_SIZE + offset) as *const PerfSample<T>
+            as *mut T;
+        unsafe {
+            ptr::write_unaligned(dst, value);
+            mmapped_buf.mmap_page.data_head = (offset + size_of::<T>()) as u64;
+            mmapped_buf.mmap_page.data_head as usize
+        }
+    }
+
+    fn write_sample<T: Debug>(mmapped_buf: &mut MMappedBuf, offset: usize, value: T) -> usize {
+        let sample = PerfSample {
+            s_hdr: RawSample {
+                header: perf_event_header {
+                    type_: PERF_RECORD_SAMPLE as u32,
+                    misc: 0,
+                    size: size_of::<PerfSample<T>>() as u16,
+                },
+                size: size_of::<T>() as u32,
+            },
+            value,
+        };
+        write(mmapped_buf, offset, sample)
+    }
+
+    fn u32_from_buf(buf: &[u8]) -> u32 {
+        u32::from_ne_bytes(buf[..4].try_into().unwrap())
+    }
+
+    fn u64_from_buf(buf: &[u8]) -> u64 {
+        u64::from_ne_bytes(buf[..8].try_into().unwrap())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_first_sample() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_many_with_many_reads() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xBADCAFE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pending_records() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        assert_eq!(buf.pending_records(), 0);
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write(
+            &mut mmapped_buf,
+            next,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 1,
+            },
+        );
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        // lost records aren't counted, and counting doesn't consume anything
+        assert_eq!(buf.pending_records(), 2);
+        assert_eq!(buf.pending_records(), 2);
+        assert_eq!(buf.peek_events(), 3);
+        assert_eq!(buf.peek_events(), 3);
+
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+        buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(buf.pending_records(), 1);
+        assert_eq!(buf.peek_events(), 2);
+
+        // peeking left everything for the next reads
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 1));
+        assert_eq!(buf.peek_events(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_resize() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        assert!(buf.readable());
+
+        // offset the new ring so that it's never page aligned, and never unmapped
+        let mut mem = vec![0u64; 3 * PAGE_SIZE / 8 + 1];
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = mem[1..].as_mut_ptr().cast());
+        buf.resize(2).unwrap();
+        assert_eq!(buf.size(), 2 * PAGE_SIZE);
+        // the events of the old ring are gone
+        assert!(!buf.readable());
+
+        assert_matches!(
+            buf.resize(3),
+            Err(PerfBufferError::InvalidPageCount { page_count: 3 })
+        );
+        assert_eq!(buf.size(), 2 * PAGE_SIZE);
+
+        // when the memory can't be mapped again, the buffer is closed and reads as empty
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = MAP_FAILED);
+        assert_matches!(buf.resize(4), Err(PerfBufferError::Closed { .. }));
+        assert_eq!(buf.size(), PAGE_SIZE);
+        assert!(!buf.readable());
+        let mut out_bufs = [BytesMut::new()];
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(0, 0));
+
+        // and resizing it again reopens it
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        buf.resize(1).unwrap();
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+    }
+
+    #[test]
+    fn test_events_sum() {
+        let mut throttled = Events::new(1, 0);
+        throttled.throttled = 1;
+        let total: Events = [Events::new(2, 1), throttled, Events::new(0, 4)]
+            .into_iter()
+            .sum();
+        assert_eq!((total.read, total.lost, total.throttled), (3, 5, 1));
+
+        let mut events = Events::new(1, 1);
+        events += Events::new(2, 0);
+        assert_eq!(events, Events::new(3, 1));
+        assert_eq!(events + Events::new(0, 0), Events::new(3, 1));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_only() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config.read_only(true);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        // the read position is tracked by the buffer, data_tail is never written
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xBADCAFE);
+        assert!(!buf.readable());
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, 0);
+
+        // a reader that fell more than a ring behind starts over from data_head
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE * 3) as u64;
+        assert!(!buf.readable());
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(0, 0));
+        let next = write_sample(&mut mmapped_buf, 0, 0xDEADBEEFu32);
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE * 3 + next) as u64;
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xDEADBEEF);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_overwrite() {
+        const SIZE: usize = size_of::<PerfSample<u32>>();
+        // writes samples backward from `head` like the kernel does, returning the new head
+        fn write_backward(
+            mmapped_buf: &mut MMappedBuf,
+            mut head: usize,
+            values: Range<u32>,
+        ) -> usize {
+            for value in values {
+                head = head.wrapping_sub(SIZE);
+                write_sample(mmapped_buf, head % PAGE_SIZE, value);
+            }
+            mmapped_buf.mmap_page.data_head = head as u64;
+            head
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config.overwrite(true);
+        assert_eq!(config.attr().write_backward(), 1);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        // samples are read oldest first, and the newest one is left in the ring
+        let head = write_backward(&mut mmapped_buf, 0, 0..3);
+        let mut out_bufs = [BytesMut::new(), BytesMut::new()];
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(2, 0));
+        assert_eq!(
+            [u32_from_buf(&out_bufs[0]), u32_from_buf(&out_bufs[1])],
+            [0, 1]
+        );
+        assert_eq!(buf.pending_records(), 1);
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 2);
+        assert!(!buf.readable());
+
+        // once the ring wraps, only the newest samples that fit are left
+        let records = PAGE_SIZE / SIZE;
+        let head = write_backward(&mut mmapped_buf, head, 3..4 + records as u32);
+        let mut values = Vec::new();
+        let events = buf
+            .read_events_borrowed(|data| values.push(u32_from_buf(data)))
+            .unwrap();
+        assert_eq!(events.read, records);
+        assert_eq!(values, (4..4 + records as u32).collect::<Vec<_>>());
+
+        // records are iterated newest first, and committing releases the older ones too
+        let head = write_backward(&mut mmapped_buf, head, 100..103);
+        let mut records = Records::new(&buf);
+        assert_eq!(
+            records.next().unwrap().unwrap(),
+            Record::Sample(Cow::Owned(102u32.to_ne_bytes().to_vec()))
+        );
+        let cursor = records.cursor();
+        buf.commit(cursor);
+        assert!(!buf.readable());
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, 0);
+
+        // a record smaller than its header ends the walk, the newer records are still read
+        let corrupted = write_backward(&mut mmapped_buf, head, 200..201);
+        write(&mut mmapped_buf, corrupted % PAGE_SIZE + 6, 4u16);
+        write_backward(&mut mmapped_buf, corrupted, 201..203);
+        values.clear();
+        assert_matches!(
+            buf.read_events_borrowed(|data| values.push(u32_from_buf(data))),
+            Err(PerfBufferError::InvalidEventSize { offset, size: 4 }) if offset == corrupted % PAGE_SIZE
+        );
+        assert_eq!(values, [201, 202]);
+        assert!(!buf.readable());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_drain() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let mut next = 0;
+        for value in 0..5u32 {
+            next = write_sample(&mut mmapped_buf, next, value);
+        }
+        write(
+            &mut mmapped_buf,
+            next,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 3,
+            },
+        );
+
+        // the pool is reused for as many reads as needed to empty the ring
+        let mut pool = vec![BytesMut::new(), BytesMut::new()];
+        let mut values = Vec::new();
+        let events = buf
+            .drain(&mut pool, |data| values.push(u32_from_buf(data)))
+            .unwrap();
+        assert_eq!(events, Events::new(5, 3));
+        assert_eq!(values, [0, 1, 2, 3, 4]);
+        assert_eq!(pool.len(), 2);
+        assert!(!buf.readable());
+
+        // an empty pool gets a buffer
+        let mut pool = Vec::new();
+        write_sample(&mut mmapped_buf, 0, 5u32);
+        mmapped_buf.mmap_page.data_tail = 0;
+        values.clear();
+        let events = buf
+            .drain(&mut pool, |data| values.push(u32_from_buf(data)))
+            .unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(values, [5]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            size_of::<PerfSample<u32>>() as u64
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_available_bytes() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        assert_eq!(buf.available_bytes(), 0);
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let end = write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+        assert_eq!(buf.available_bytes(), end);
+
+        // the out buffers fill up before the ring is empty
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+        buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(buf.available_bytes(), end - next);
+        buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(buf.available_bytes(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_many_with_one_read() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        let mut out_bufs = (0..3)
+            .map(|_| BytesMut::with_capacity(4))
+            .collect::<Vec<_>>();
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        assert_eq!(u32_from_buf(&out_bufs[1]), 0xBADCAFE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_discard_events() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write(
+            &mut mmapped_buf,
+            next,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 3,
+            },
+        );
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        assert_eq!(buf.discard_events().unwrap(), Events::new(2, 3));
+        assert!(!buf.readable());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_zero_on_reuse() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        buf.set_zero_on_reuse(true);
+
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+
+        let mut out_bufs = [BytesMut::from(&[0xAA; 64][..])];
+        let capacity = out_bufs[0].capacity();
+        buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(&out_bufs[0][..], 0xCAFEBABEu32.to_ne_bytes());
+        // the capacity was initialized by the zeroing
+        unsafe { out_bufs[0].set_len(capacity) };
+        assert!(out_bufs[0][4..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_stats_and_reset() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let lost_start = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write(
+            &mut mmapped_buf,
+            lost_start,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 3,
+            },
+        );
+        let end = write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        // stops at the lost record, once the only buffer is filled
+        let mut out_bufs = [BytesMut::new()];
+        buf.read_events(&mut out_bufs).unwrap();
+        let stats = BufferStats {
+            read: 1,
+            lost: 0,
+            bytes: lost_start as u64,
+        };
+        assert_eq!(buf.stats(), stats);
+        assert_eq!(buf.stats_and_reset(), stats);
+        assert_eq!(buf.stats(), BufferStats::default());
+
+        buf.discard_events().unwrap();
+        assert_eq!(
+            buf.stats_and_reset(),
+            BufferStats {
+                read: 1,
+                lost: 3,
+                bytes: (end - lost_start) as u64,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_lost_since_start_saturates() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let mut next = 0;
+        for count in [u64::MAX - 1, 5] {
+            next = write(
+                &mut mmapped_buf,
+                next,
+                LostSamples {
+                    header: perf_event_header {
+                        type_: PERF_RECORD_LOST as u32,
+                        misc: 0,
+                        size: size_of::<LostSamples>() as u16,
+                    },
+                    id: 1,
+                    count,
+                },
+            );
+        }
+
+        let events = buf.discard_events().unwrap();
+        assert_eq!(events.lost, usize::MAX);
+        assert_eq!(buf.stats().lost, u64::MAX);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, next as u64);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_callchain_sample() {
+        #[repr(C)]
+        #[derive(Debug)]
+        struct CallchainSample {
+            header: perf_event_header,
+            nr: u64,
+            ips: [u64; 2],
+            size: u32,
+            value: u32,
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config.sample_type(SampleType::CALLCHAIN | SampleType::RAW);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        let sample = || CallchainSample {
+            header: perf_event_header {
+                type_: PERF_RECORD_SAMPLE as u32,
+                misc: 0,
+                size: size_of::<CallchainSample>() as u16,
+            },
+            nr: 2,
+            ips: [0xdead, 0xbeef],
+            size: size_of::<u32>() as u32,
+            value: 0xCAFEBABE,
+        };
+        let next = write(&mut mmapped_buf, 0, sample());
+        write(&mut mmapped_buf, next, sample());
+
+        let mut samples = [Sample::default()];
+        let events = buf.read_samples(&mut samples).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(samples[0].callchain, [0xdead, 0xbeef]);
+        assert_eq!(u32_from_buf(&samples[0].data), 0xCAFEBABE);
+
+        // read_events() skips over the call chain
+        let mut out_bufs = [BytesMut::with_capacity(4)];
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_timed_sample() {
+        #[repr(C)]
+        #[derive(Debug)]
+        struct TimedSample {
+            header: perf_event_header,
+            pid: u32,
+            tid: u32,
+            time: u64,
+            cpu: u32,
+            res: u32,
+            size: u32,
+            value: u32,
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config.sample_type(SampleType::TID | SampleType::TIME | SampleType::CPU | SampleType::RAW);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        write(
+            &mut mmapped_buf,
+            0,
+            TimedSample {
+                header: perf_event_header {
+                    type_: PERF_RECORD_SAMPLE as u32,
+                    misc: 0,
+                    size: size_of::<TimedSample>() as u16,
+                },
+                pid: 41,
+                tid: 42,
+                time: 123456,
+                cpu: 3,
+                res: 0,
+                size: size_of::<u32>() as u32,
+                value: 0xCAFEBABE,
+            },
+        );
+
+        let mut samples = [Sample::default()];
+        let events = buf.read_samples(&mut samples).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!((samples[0].pid, samples[0].tid), (41, 42));
+        assert_eq!(samples[0].time, 123456);
+        assert_eq!(samples[0].cpu, 3);
+        assert_eq!(u32_from_buf(&samples[0].data), 0xCAFEBABE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_no_raw_data() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config.sample_type(SampleType::TIME);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        // the time would be read as the size of the raw data
+        let header = perf_event_header {
+            type_: PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            size: (size_of::<perf_event_header>() + size_of::<u64>()) as u16,
+        };
+        let next = write(&mut mmapped_buf, 0, header);
+        write(&mut mmapped_buf, next, u64::MAX);
+
+        let mut out_bufs = [BytesMut::new()];
+        assert_matches!(
+            buf.read_events(&mut out_bufs),
+            Err(PerfBufferError::NoRawData)
+        );
+        assert_matches!(
+            buf.read_events_borrowed(|_| panic!("unexpected sample")),
+            Err(PerfBufferError::NoRawData)
+        );
+        let mut region = [MaybeUninit::uninit(); 64];
+        assert_matches!(
+            buf.read_events_framed(&mut region),
+            Err(PerfBufferError::NoRawData)
+        );
+        let mut records = Records::new(&buf);
+        assert_matches!(records.next(), Some(Err(PerfBufferError::NoRawData)));
+        assert!(records.next().is_none());
+        let cursor = records.cursor();
+        buf.commit(cursor);
+
+        // nothing was consumed, and the sample can still be decoded
+        let mut samples = [Sample::default()];
+        assert_eq!(buf.read_samples(&mut samples).unwrap(), Events::new(1, 0));
+        assert_eq!(samples[0].time, u64::MAX);
+        assert!(samples[0].data.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_group_sample() {
+        #[repr(C)]
+        #[derive(Debug)]
+        struct GroupSample {
+            header: perf_event_header,
+            time: u64,
+            nr: u64,
+            time_enabled: u64,
+            time_running: u64,
+            values: [[u64; 2]; 2],
+            size: u32,
+            value: u32,
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config
+            .sample_type(SampleType::TIME | SampleType::READ | SampleType::RAW)
+            .read_format(
+                ReadFormat::GROUP
+                    | ReadFormat::ID
+                    | ReadFormat::TOTAL_TIME_ENABLED
+                    | ReadFormat::TOTAL_TIME_RUNNING,
+            );
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        write(
+            &mut mmapped_buf,
+            0,
+            GroupSample {
+                header: perf_event_header {
+                    type_: PERF_RECORD_SAMPLE as u32,
+                    misc: 0,
+                    size: size_of::<GroupSample>() as u16,
+                },
+                time: 1234,
+                nr: 2,
+                time_enabled: 100,
+                time_running: 50,
+                values: [[7, 1], [9, 2]],
+                size: size_of::<u32>() as u32,
+                value: 0xCAFEBABE,
+            },
+        );
+
+        let mut samples = [Sample::default()];
+        let events = buf.read_samples(&mut samples).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(
+            samples[0].read,
+            ReadValues {
+                time_enabled: 100,
+                time_running: 50,
+                values: vec![(1, 7), (2, 9)],
+            }
+        );
+        assert_eq!(u32_from_buf(&samples[0].data), 0xCAFEBABE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_branch_stack_sample() {
+        #[repr(C)]
+        #[derive(Debug)]
+        struct BranchStackSample {
+            header: perf_event_header,
+            size: u32,
+            value: u32,
+            bnr: u64,
+            hw_idx: u64,
+            entries: [BranchEntry; 2],
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut config = PerfEventConfig::new();
+        config
+            .sample_type(SampleType::RAW | SampleType::BRANCH_STACK)
+            .branch_sample_type(BranchSampleType::ANY | BranchSampleType::HW_INDEX);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &config).unwrap();
+
+        let entries = [
+            BranchEntry {
+                from: 0x1000,
+                to: 0x2000,
+                flags: 1 | (42 << 4),
+            },
+            BranchEntry {
+                from: 0x3000,
+                to: 0x4000,
+                flags: 1 << 1,
+            },
+        ];
+        write(
+            &mut mmapped_buf,
+            0,
+            BranchStackSample {
+                header: perf_event_header {
+                    type_: PERF_RECORD_SAMPLE as u32,
+                    misc: 0,
+                    size: size_of::<BranchStackSample>() as u16,
+                },
+                size: size_of::<u32>() as u32,
+                value: 0xCAFEBABE,
+                bnr: 2,
+                hw_idx: 7,
+                entries,
+            },
+        );
+
+        let mut samples = [Sample::default()];
+        let events = buf.read_samples(&mut samples).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&samples[0].data), 0xCAFEBABE);
+        assert_eq!(samples[0].branch_stack, entries);
+        assert!(samples[0].branch_stack[0].mispredicted());
+        assert_eq!(samples[0].branch_stack[0].cycles(), 42);
+        assert!(samples[0].branch_stack[1].predicted());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_throttled() {
+        #[repr(C)]
+        #[derive(Debug)]
+        struct ThrottleRecord {
+            header: perf_event_header,
+            time: u64,
+            id: u64,
+            stream_id: u64,
+        }
+
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write(
+            &mut mmapped_buf,
+            0,
+            ThrottleRecord {
+                header: perf_event_header {
+                    type_: PERF_RECORD_THROTTLE as u32,
+                    misc: 0,
+                    size: size_of::<ThrottleRecord>() as u16,
+                },
+                time: 1234,
+                id: 5,
+                stream_id: 5,
+            },
+        );
+        let next = write_sample(&mut mmapped_buf, next, 0xCAFEBABEu32);
+        let end = write(
+            &mut mmapped_buf,
+            next,
+            ThrottleRecord {
+                header: perf_event_header {
+                    type_: PERF_RECORD_UNTHROTTLE as u32,
+                    misc: 0,
+                    size: size_of::<ThrottleRecord>() as u16,
+                },
+                time: 2345,
+                id: 5,
+                stream_id: 5,
+            },
+        );
+
+        let mut throttles = Vec::new();
+        let mut out_bufs = [BytesMut::with_capacity(4), BytesMut::new()];
+        let events = buf
+            .read_events_throttled(&mut out_bufs, |throttle| throttles.push(throttle))
+            .unwrap();
+        assert_eq!(
+            (
+                events.read,
+                events.lost,
+                events.throttled,
+                events.unthrottled
+            ),
+            (1, 0, 1, 1)
+        );
+        assert_eq!(throttles, [Throttle { time: 1234, id: 5 }]);
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+
+        // the other readers count the same records
+        mmapped_buf.mmap_page.data_tail = 0;
+        let mut samples = Vec::new();
+        let events = buf
+            .read_events_borrowed(|payload| samples.push(payload.to_vec()))
+            .unwrap();
+        assert_eq!(
+            (
+                events.read,
+                events.lost,
+                events.throttled,
+                events.unthrottled
+            ),
+            (1, 0, 1, 1)
+        );
+        assert_eq!(samples.len(), 1);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, end as u64);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_lost() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let lost = |id, count| LostSamples {
+            header: perf_event_header {
+                type_: PERF_RECORD_LOST as u32,
+                misc: 0,
+                size: size_of::<LostSamples>() as u16,
+            },
+            id,
+            count,
+        };
+        let next = write(&mut mmapped_buf, 0, lost(3, 10));
+        let next = write_sample(&mut mmapped_buf, next, 0xCAFEBABEu32);
+        write(&mut mmapped_buf, next, lost(7, 2));
+
+        let mut losses = Vec::new();
+        let mut out_bufs = [BytesMut::with_capacity(4), BytesMut::with_capacity(4)];
+        let events = buf
+            .read_events_lost(&mut out_bufs, |lost| losses.push(lost))
+            .unwrap();
+        assert_eq!(events, Events::new(1, 12));
+        assert_eq!(
+            losses,
+            [Lost { id: 3, count: 10 }, Lost { id: 7, count: 2 }]
+        );
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_records() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write(
+            &mut mmapped_buf,
+            next,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 3,
+            },
+        );
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        let mut records = Records::new(&buf);
+        assert_eq!(
+            records.next().unwrap().unwrap(),
+            Record::Sample(Cow::Borrowed(&0xCAFEBABEu32.to_ne_bytes()))
+        );
+        // read only methods can be called while iterating
+        assert!(buf.readable());
+        assert_eq!(records.next().unwrap().unwrap(), Record::Lost(3));
+        let cursor = records.cursor();
+        assert_eq!(
+            records.next().unwrap().unwrap(),
+            Record::Sample(Cow::Borrowed(&0xBADCAFEu32.to_ne_bytes()))
+        );
+        assert!(records.next().is_none());
+
+        // nothing is consumed until commit() is called
+        let mut out_bufs = [BytesMut::new()];
+        buf.commit(cursor);
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xBADCAFE);
+
+        // stale cursors are ignored
+        buf.commit(cursor);
+        assert!(!buf.readable());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_commit_other_buffer() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+        let mut other_mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&other_mmapped_buf);
+        let other = PerfBuffer::open(2, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        write_sample(&mut other_mmapped_buf, 0, 0xBADCAFEu32);
+        let mut records = Records::new(&other);
+        assert!(records.next().is_some());
+
+        // the cursor is at the same position in both rings, but only belongs to `other`
+        buf.commit(records.cursor());
+        let mut out_bufs = [BytesMut::new()];
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_no_out_bufs() {
-        let mmapped_buf = MMappedBuf {
+    fn test_read_events_min_size() {
+        let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
-        assert_matches!(buf.read_events(&mut []), Err(PerfBufferError::NoBuffers))
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write_sample(&mut mmapped_buf, next, [0xBADCAFEu32, 0xBADCAFE]);
+        write_sample(&mut mmapped_buf, next, 0xBAADF00Du32);
+
+        let mut out_bufs = [BytesMut::new(), BytesMut::new()];
+        let events = buf.read_events_min_size(&mut out_bufs, 8).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u64_from_buf(&out_bufs[0]), 0x0BADCAFE0BADCAFE);
+        assert!(!buf.readable());
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_no_events() {
-        let mmapped_buf = MMappedBuf {
+    fn test_read_events_tee() {
+        let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
-        let out_buf = BytesMut::with_capacity(4);
+        let next = write(
+            &mut mmapped_buf,
+            0,
+            LostSamples {
+                header: perf_event_header {
+                    type_: PERF_RECORD_LOST as u32,
+                    misc: 0,
+                    size: size_of::<LostSamples>() as u16,
+                },
+                id: 1,
+                count: 2,
+            },
+        );
+        write_sample(&mut mmapped_buf, next, 0xCAFEBABEu32);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut out_bufs = [BytesMut::new()];
+        let events = buf.read_events_tee(&mut out_bufs, &tx).unwrap();
+        assert_eq!(events, Events::new(1, 2));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        drop(tx);
         assert_eq!(
-            buf.read_events(&mut [out_buf]).unwrap(),
-            Events { read: 0, lost: 0 }
+            rx.iter().collect::<Vec<_>>(),
+            [
+                Record::Lost(2),
+                Record::Sample(Cow::Owned(0xCAFEBABEu32.to_ne_bytes().to_vec()))
+            ]
         );
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_read_first_lost() {
+    fn test_read_events_while() {
         let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let evt = LostSamples {
-            header: perf_event_header {
-                type_: PERF_RECORD_LOST as u32,
-                misc: 0,
-                size: mem::size_of::<LostSamples>() as u16,
-            },
-            id: 1,
-            count: 0xCAFEBABE,
-        };
-        write(&mut mmapped_buf, 0, evt);
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
 
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
-        let out_buf = BytesMut::with_capacity(0);
-        let events = buf.read_events(&mut [out_buf]).unwrap();
-        assert_eq!(events.lost, 0xCAFEBABE);
-    }
+        // stop after the first record
+        let mut out_bufs = (0..3)
+            .map(|_| BytesMut::with_capacity(4))
+            .collect::<Vec<_>>();
+        let remaining = Cell::new(1usize);
+        let events = buf
+            .read_events_while(&mut out_bufs, || {
+                remaining.replace(remaining.get().saturating_sub(1)) > 0
+            })
+            .unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        assert!(buf.readable());
 
-    #[repr(C)]
-    #[derive(Debug)]
-    struct PerfSample<T: Debug> {
-        s_hdr: Sample,
-        value: T,
+        let events = buf.read_events_while(&mut out_bufs, || false).unwrap();
+        assert_eq!(events, Events::new(0, 0));
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xBADCAFE);
     }
 
-    fn write<T: Debug>(mmapped_buf: &mut MMappedBuf, offset: usize, value: T) -> usize {
-        let dst = (mmapped_buf as *const _ as usize + PAGE_SIZE + offset) as *const PerfSample<T>
-            as *mut T;
-        unsafe {
-            ptr::write_unaligned(dst, value);
-            mmapped_buf.mmap_page.data_head = (offset + mem::size_of::<T>()) as u64;
-            mmapped_buf.mmap_page.data_head as usize
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_while_interval() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let mut next = 0;
+        for value in 0..5u32 {
+            next = write_sample(&mut mmapped_buf, next, value);
         }
+
+        // the budget is exhausted after the first check, but only checked again at the third
+        // record
+        let mut out_bufs = (0..5)
+            .map(|_| BytesMut::with_capacity(4))
+            .collect::<Vec<_>>();
+        let checks = Cell::new(0usize);
+        let events = buf
+            .read_events_while_interval(&mut out_bufs, 2, || {
+                checks.set(checks.get() + 1);
+                checks.get() == 1
+            })
+            .unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(checks.get(), 2);
+        assert_eq!(u32_from_buf(&out_bufs[1]), 1);
     }
 
-    fn write_sample<T: Debug>(mmapped_buf: &mut MMappedBuf, offset: usize, value: T) -> usize {
-        let sample = PerfSample {
-            s_hdr: Sample {
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_split() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        // the value of the second sample wraps around the end of the ring
+        let sample = |value: u32| PerfSample {
+            s_hdr: RawSample {
                 header: perf_event_header {
                     type_: PERF_RECORD_SAMPLE as u32,
                     misc: 0,
-                    size: mem::size_of::<PerfSample<T>>() as u16,
+                    size: size_of::<PerfSample<[u32; 2]>>() as u16,
                 },
-                size: mem::size_of::<T>() as u32,
+                size: size_of::<[u32; 2]>() as u32,
             },
             value,
         };
-        write(mmapped_buf, offset, sample)
+        let offset = PAGE_SIZE - size_of::<PerfSample<[u32; 2]>>() - 16;
+        mmapped_buf.mmap_page.data_tail = offset as u64;
+        let next = write(&mut mmapped_buf, offset, sample(0x0101_0101));
+        let next = write(&mut mmapped_buf, next, 0x0202_0202u32);
+        write(&mut mmapped_buf, next, sample(0x0303_0303));
+        write(&mut mmapped_buf, 0, 0x0404_0404u32);
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE + 4) as u64;
+
+        let mut values = Vec::new();
+        let events = buf
+            .read_events_split(
+                |payload| (0..payload.len()).step_by(4).map(|i| i..i + 4),
+                |sample| values.push(u32::from_ne_bytes(sample.try_into().unwrap())),
+            )
+            .unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(values, [0x0101_0101, 0x0202_0202, 0x0303_0303, 0x0404_0404]);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (PAGE_SIZE + 4) as u64
+        );
     }
 
-    fn u32_from_buf(buf: &[u8]) -> u32 {
-        u32::from_ne_bytes(buf[..4].try_into().unwrap())
-    }
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_borrowed() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-    fn u64_from_buf(buf: &[u8]) -> u64 {
-        u64::from_ne_bytes(buf[..8].try_into().unwrap())
+        // the second sample wraps around the end of the ring, so it's the only one copied
+        let sample = |value: u32| PerfSample {
+            s_hdr: RawSample {
+                header: perf_event_header {
+                    type_: PERF_RECORD_SAMPLE as u32,
+                    misc: 0,
+                    size: size_of::<PerfSample<[u32; 2]>>() as u16,
+                },
+                size: size_of::<[u32; 2]>() as u32,
+            },
+            value,
+        };
+        let offset = PAGE_SIZE - size_of::<PerfSample<[u32; 2]>>() - 16;
+        mmapped_buf.mmap_page.data_tail = offset as u64;
+        let next = write(&mut mmapped_buf, offset, sample(0x0101_0101));
+        let next = write(&mut mmapped_buf, next, 0x0202_0202u32);
+        write(&mut mmapped_buf, next, sample(0x0303_0303));
+        write(&mut mmapped_buf, 0, 0x0404_0404u32);
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE + 4) as u64;
+
+        let mut samples = Vec::new();
+        let events = buf
+            .read_events_borrowed(|data| samples.push(data.to_vec()))
+            .unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(
+            samples,
+            [
+                [0x0101_0101u32.to_ne_bytes(), 0x0202_0202u32.to_ne_bytes()].concat(),
+                [0x0303_0303u32.to_ne_bytes(), 0x0404_0404u32.to_ne_bytes()].concat(),
+            ]
+        );
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (PAGE_SIZE + 4) as u64
+        );
+
+        // the scratch buffer is kept for the next wrapped sample
+        let scratch = buf.scratch.as_ptr();
+        assert!(buf.scratch.capacity() >= size_of::<[u32; 2]>());
+        mmapped_buf.mmap_page.data_tail = next as u64;
+        write(&mut mmapped_buf, next, sample(0x0505_0505));
+        write(&mut mmapped_buf, 0, 0x0606_0606u32);
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE + 4) as u64;
+        samples.clear();
+        assert_eq!(
+            buf.read_events_borrowed(|data| samples.push(data.to_vec()))
+                .unwrap(),
+            Events::new(1, 0)
+        );
+        assert_eq!(
+            samples,
+            [[0x0505_0505u32.to_ne_bytes(), 0x0606_0606u32.to_ne_bytes()].concat()]
+        );
+        assert_eq!(buf.scratch.as_ptr(), scratch);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (PAGE_SIZE + 4) as u64
+        );
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_read_first_sample() {
+    fn test_read_events_decoded() {
         let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
-
-        write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let mut out_bufs = [BytesMut::with_capacity(4)];
+        let next = write_sample(&mut mmapped_buf, 0, 0x0101_0101u32);
+        let next = write_sample(&mut mmapped_buf, next, 0u32);
+        write_sample(&mut mmapped_buf, next, 0x0202_0202u32);
 
-        let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
-        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        // "decompress" by repeating the payload, zeroes are corrupted payloads
+        let decode = |data: &[u8]| {
+            if u32_from_buf(data) == 0 {
+                return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+            }
+            Ok::<_, PerfBufferError>(data.repeat(2))
+        };
+        let mut decoded = Vec::new();
+        assert_matches!(
+            buf.read_events_decoded(decode, |data| decoded.push(data)),
+            Err(PerfBufferError::IOError(e)) if e.kind() == io::ErrorKind::InvalidData
+        );
+        assert_eq!(decoded, [0x0101_0101u32.to_ne_bytes().repeat(2)]);
+
+        // the corrupted sample was consumed
+        decoded.clear();
+        let events = buf
+            .read_events_decoded(decode, |data| decoded.push(data))
+            .unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(decoded, [0x0202_0202u32.to_ne_bytes().repeat(2)]);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, unsafe {
+            mmapped_buf.mmap_page.data_head
+        });
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_read_many_with_many_reads() {
+    fn test_read_events_coalesced() {
         let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
-        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+        let mut next = 0;
+        for value in [1u32, 1, 1, 2, 1, 1] {
+            next = write_sample(&mut mmapped_buf, next, value);
+        }
 
-        let mut out_bufs = [BytesMut::with_capacity(4)];
+        let (coalesced, events) = buf.read_events_coalesced(|a, b| a == b).unwrap();
+        assert_eq!(events, Events::new(6, 0));
+        assert_eq!(
+            coalesced,
+            [
+                (1u32.to_ne_bytes().to_vec(), 3),
+                (2u32.to_ne_bytes().to_vec(), 1),
+                (1u32.to_ne_bytes().to_vec(), 2),
+            ]
+        );
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, next as u64);
+    }
 
-        let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
-        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_events_batched() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
-        assert_eq!(u32_from_buf(&out_bufs[0]), 0xBADCAFE);
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let next = write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+
+        // samples are appended after the existing data
+        let mut out = b"prefix".to_vec();
+        let mut ranges = vec![0..3, 3..6];
+        let events = buf.read_events_batched(&mut out, &mut ranges).unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(ranges, [0..3, 3..6, 6..10, 10..14]);
+        assert_eq!(&out[..6], b"prefix");
+        assert_eq!(u32_from_buf(&out[ranges[2].clone()]), 0xCAFEBABE);
+        assert_eq!(u32_from_buf(&out[ranges[3].clone()]), 0xBADCAFE);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, next as u64);
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn test_read_many_with_one_read() {
+    fn test_for_each_sample() {
         let mut mmapped_buf = MMappedBuf {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
         let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
-        write_sample(&mut mmapped_buf, next, 0xBADCAFEu32);
+        let marker_end = write_sample(&mut mmapped_buf, next, 0xFFFFFFFFu32);
+        let next = write_sample(&mut mmapped_buf, marker_end, 0xBADCAFEu32);
+        write_sample(&mut mmapped_buf, next, 0xDEADBEEFu32);
+
+        let mut values = Vec::new();
+        let events = buf
+            .for_each_sample(|data| {
+                let value = u32::from_ne_bytes(data.try_into().unwrap());
+                values.push(value);
+                if value == 0xFFFFFFFF {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert_eq!(values, [0xCAFEBABE, 0xFFFFFFFF]);
+        assert_eq!(events.read, 2);
+        assert_eq!(events.remaining, 2);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            marker_end as u64
+        );
+    }
 
-        let mut out_bufs = (0..3)
-            .map(|_| BytesMut::with_capacity(4))
+    #[test]
+    fn test_events_par_chunks() {
+        let mut out_bufs = (0..5u8)
+            .map(|i| BytesMut::from(&[i][..]))
             .collect::<Vec<_>>();
-
-        let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 2 });
-        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
-        assert_eq!(u32_from_buf(&out_bufs[1]), 0xBADCAFE);
+        let events = Events::new(4, 0);
+        events.par_chunks(&mut out_bufs, 3, |chunk| {
+            for buf in chunk {
+                buf[0] += 10;
+            }
+        });
+        assert_eq!(
+            out_bufs.iter().map(|buf| buf[0]).collect::<Vec<_>>(),
+            [10, 11, 12, 13, 4]
+        );
     }
 
     #[test]
@@ -502,16 +3590,16 @@ mod tests {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
-        let offset = PAGE_SIZE - mem::size_of::<PerfSample<u32>>();
+        let offset = PAGE_SIZE - size_of::<PerfSample<u32>>();
         mmapped_buf.mmap_page.data_tail = offset as u64;
         write_sample(&mut mmapped_buf, offset, 0xCAFEBABEu32);
 
         let mut out_bufs = [BytesMut::with_capacity(4)];
 
         let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
+        assert_eq!(events, Events::new(1, 0));
         assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
     }
 
@@ -522,26 +3610,220 @@ mod tests {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
         let header = perf_event_header {
             type_: PERF_RECORD_SAMPLE as u32,
             misc: 0,
-            size: mem::size_of::<PerfSample<u64>>() as u16,
+            size: size_of::<PerfSample<u64>>() as u16,
         };
 
-        let offset = PAGE_SIZE - mem::size_of::<perf_event_header>() - 2;
+        let offset = PAGE_SIZE - size_of::<perf_event_header>() - 2;
         mmapped_buf.mmap_page.data_tail = offset as u64;
         write(&mut mmapped_buf, offset, header);
         write(&mut mmapped_buf, PAGE_SIZE - 2, 0x0004u16);
         write(&mut mmapped_buf, 0, 0x0000u16);
         write(&mut mmapped_buf, 2, 0xBAADCAFEu32);
+        mmapped_buf.mmap_page.data_head = (offset + size_of::<PerfSample<u64>>()) as u64;
 
         let mut out_bufs = [BytesMut::with_capacity(8)];
 
         let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
+        assert_eq!(events, Events::new(1, 0));
         assert_eq!(u32_from_buf(&out_bufs[0]), 0xBAADCAFE);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (offset + size_of::<PerfSample<u64>>()) as u64
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_invalid_event_size() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let next = write_sample(&mut mmapped_buf, 0, 0xCAFEBABEu32);
+        let end = write(
+            &mut mmapped_buf,
+            next,
+            perf_event_header {
+                type_: PERF_RECORD_SAMPLE as u32,
+                misc: 0,
+                size: 0,
+            },
+        );
+
+        // walking the pending records stops at the invalid one
+        assert_eq!(buf.peek_events(), 1);
+
+        let mut out_bufs = [BytesMut::new(), BytesMut::new()];
+        assert_matches!(
+            buf.read_events(&mut out_bufs),
+            Err(PerfBufferError::InvalidEventSize { offset, size: 0 }) if offset == next
+        );
+        // the ring was released up to data_head, so that the next reads don't fail again
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, end as u64);
+        assert!(!buf.readable());
+
+        // a record that extends past data_head is invalid too
+        let header = perf_event_header {
+            type_: PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            size: 64,
+        };
+        let end = write(&mut mmapped_buf, end, header);
+        assert_matches!(
+            buf.read_events(&mut out_bufs),
+            Err(PerfBufferError::InvalidEventSize { size: 64, .. })
+        );
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, end as u64);
+        assert_eq!(buf.read_events(&mut out_bufs).unwrap(), Events::new(0, 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_invalid_event_size_reported_by_all_readers() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let corrupt = |mmapped_buf: &mut MMappedBuf| {
+            let tail = unsafe { mmapped_buf.mmap_page.data_tail } as usize;
+            let next = write_sample(mmapped_buf, tail, 0xCAFEBABEu32);
+            let header = perf_event_header {
+                type_: PERF_RECORD_SAMPLE as u32,
+                misc: 0,
+                size: 0,
+            };
+            write(mmapped_buf, next, header);
+            next
+        };
+
+        let next = corrupt(&mut mmapped_buf);
+        assert_matches!(
+            buf.discard_events(),
+            Err(PerfBufferError::InvalidEventSize { offset, size: 0 }) if offset == next
+        );
+        assert!(!buf.readable());
+
+        corrupt(&mut mmapped_buf);
+        let mut samples = 0;
+        assert_matches!(
+            buf.read_events_borrowed(|_| samples += 1),
+            Err(PerfBufferError::InvalidEventSize { size: 0, .. })
+        );
+        assert_eq!(samples, 1);
+        assert!(!buf.readable());
+
+        corrupt(&mut mmapped_buf);
+        let mut region = [MaybeUninit::uninit(); 64];
+        assert_matches!(
+            buf.read_events_framed(&mut region),
+            Err(PerfBufferError::InvalidEventSize { size: 0, .. })
+        );
+        assert!(!buf.readable());
+
+        // iterating stops at the invalid record, and committing releases it
+        corrupt(&mut mmapped_buf);
+        let mut records = Records::new(&buf);
+        assert_matches!(records.next(), Some(Ok(Record::Sample(_))));
+        assert_matches!(
+            records.next(),
+            Some(Err(PerfBufferError::InvalidEventSize { size: 0, .. }))
+        );
+        assert!(records.next().is_none());
+        let cursor = records.cursor();
+        buf.commit(cursor);
+        assert!(!buf.readable());
+        assert_eq!(buf.stats().read, 4);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_counter_wrap() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        // the record starts 8 bytes before the counters wrap around, at the end of the ring
+        let tail = u64::MAX - 7;
+        let header = perf_event_header {
+            type_: PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            size: size_of::<PerfSample<u32>>() as u16,
+        };
+        write(&mut mmapped_buf, PAGE_SIZE - 8, header);
+        write(&mut mmapped_buf, 0, 4u32);
+        write(&mut mmapped_buf, 4, 0xCAFEBABEu32);
+        mmapped_buf.mmap_page.data_tail = tail;
+        mmapped_buf.mmap_page.data_head = tail.wrapping_add(16);
+
+        assert_eq!(buf.available_bytes(), 16);
+        let mut out_bufs = [BytesMut::new()];
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(u32_from_buf(&out_bufs[0]), 0xCAFEBABE);
+        assert_eq!(unsafe { mmapped_buf.mmap_page.data_tail }, 8);
+        assert!(!buf.readable());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_wrapping_sample_fills_capacity() {
+        let mut mmapped_buf = MMappedBuf {
+            data: [0; PAGE_SIZE * 2],
+        };
+        fake_mmap(&mmapped_buf);
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
+
+        let data = *b"0123456789abcdef";
+        let header = perf_event_header {
+            type_: PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            size: (size_of::<perf_event_header>() + size_of::<u32>() + data.len()) as u16,
+        };
+
+        // the data starts 4 bytes before the end of the ring and wraps to the start
+        let offset = PAGE_SIZE - 16;
+        mmapped_buf.mmap_page.data_tail = offset as u64;
+        write(&mut mmapped_buf, offset, header);
+        write(&mut mmapped_buf, PAGE_SIZE - 8, data.len() as u32);
+        write(
+            &mut mmapped_buf,
+            PAGE_SIZE - 4,
+            <[u8; 4]>::try_from(&data[..4]).unwrap(),
+        );
+        write(
+            &mut mmapped_buf,
+            0,
+            <[u8; 12]>::try_from(&data[4..]).unwrap(),
+        );
+        mmapped_buf.mmap_page.data_head = (PAGE_SIZE + 12) as u64;
+
+        // a buffer whose capacity is exactly the sample size, followed by memory that must not
+        // be written to
+        let mut out_buf = BytesMut::from(&[0xAA; 32][..]);
+        let guard = out_buf.split_off(data.len());
+        out_buf.clear();
+        assert_eq!(out_buf.capacity(), data.len());
+        let mut out_bufs = [out_buf];
+
+        let events = buf.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(1, 0));
+        assert_eq!(&out_bufs[0][..], data);
+        assert!(guard.iter().all(|&b| b == 0xAA));
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (PAGE_SIZE + 12) as u64
+        );
     }
 
     #[test]
@@ -551,29 +3833,34 @@ mod tests {
             data: [0; PAGE_SIZE * 2],
         };
         fake_mmap(&mmapped_buf);
-        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1).unwrap();
+        let mut buf = PerfBuffer::open(1, PAGE_SIZE, 1, &PerfEventConfig::new()).unwrap();
 
         let sample = PerfSample {
-            s_hdr: Sample {
+            s_hdr: RawSample {
                 header: perf_event_header {
                     type_: PERF_RECORD_SAMPLE as u32,
                     misc: 0,
-                    size: mem::size_of::<PerfSample<u64>>() as u16,
+                    size: size_of::<PerfSample<u64>>() as u16,
                 },
-                size: mem::size_of::<u64>() as u32,
+                size: size_of::<u64>() as u32,
             },
             value: 0xCAFEBABEu32,
         };
 
-        let offset = PAGE_SIZE - mem::size_of::<PerfSample<u32>>();
+        let offset = PAGE_SIZE - size_of::<PerfSample<u32>>();
         mmapped_buf.mmap_page.data_tail = offset as u64;
         write(&mut mmapped_buf, offset, sample);
         write(&mut mmapped_buf, 0, 0xBAADCAFEu32);
+        mmapped_buf.mmap_page.data_head = (offset + size_of::<PerfSample<u64>>()) as u64;
 
         let mut out_bufs = [BytesMut::with_capacity(8)];
 
         let events = buf.read_events(&mut out_bufs).unwrap();
-        assert_eq!(events, Events { lost: 0, read: 1 });
+        assert_eq!(events, Events::new(1, 0));
         assert_eq!(u64_from_buf(&out_bufs[0]), 0xBAADCAFECAFEBABE);
+        assert_eq!(
+            unsafe { mmapped_buf.mmap_page.data_tail },
+            (offset + size_of::<PerfSample<u64>>()) as u64
+        );
     }
 }
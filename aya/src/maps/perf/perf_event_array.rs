@@ -3,21 +3,33 @@
 //! [`perf`]: https://perf.wiki.kernel.org/index.php/Main_Page.
 use std::{
     borrow::{Borrow, BorrowMut},
-    ops::Deref,
-    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    collections::{btree_map::Entry, BTreeMap},
+    io,
+    mem::MaybeUninit,
+    ops::{ControlFlow, Deref, Range},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
     path::Path,
-    sync::Arc,
+    sync::{mpsc::Sender, Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
 };
 
 use bytes::BytesMut;
+use libc::{c_int, pid_t, E2BIG, EAGAIN, EBUSY, ENODEV};
+use log::warn;
 
 use crate::{
     maps::{
-        perf::{Events, PerfBuffer, PerfBufferError},
+        perf::{
+            wakeup::wait_readable, BufferStats, Coalesced, EnableGuard, Events, FramedEvents, Lost,
+            PerfBuffer, PerfBufferError, PerfEventConfig, Record, RecordCursor, Records, Sample,
+            SampleType, Throttle, TimedEvents,
+        },
         MapData, MapError, PinError,
     },
-    sys::bpf_map_update_elem,
-    util::page_size,
+    programs::perf_event::PerfTypeId,
+    sys::{bpf_map_delete_elem, bpf_map_update_elem, SysResult},
+    util::{online_cpus, page_size},
 };
 
 /// A ring buffer that can receive events from eBPF programs.
@@ -27,17 +39,202 @@ use crate::{
 ///
 /// See the [`PerfEventArray` documentation](PerfEventArray) for an overview of how to use
 /// perf buffers.
+///
+/// # Threading
+///
+/// Buffers are [`Send`] when `T` is [`Send`] and [`Sync`], so the usual pattern of one reader
+/// thread per CPU works by moving each buffer to its thread. No `unsafe impl` is needed: the
+/// mapped memory is only referenced through an atomic pointer, and the memory and the file
+/// descriptor are owned by the buffer alone and released when it's dropped. Reading takes
+/// `&mut self`, which guarantees that a single thread consumes the events of a buffer at a time,
+/// as the kernel protocol of the ring requires.
 pub struct PerfEventArrayBuffer<T> {
-    _map: Arc<T>,
+    map: Arc<T>,
     buf: PerfBuffer,
+    index: u32,
+    open_buffers: OpenBuffers,
 }
 
+// The number of live buffers for each index of a PerfEventArray.
+type OpenBuffers = Arc<Mutex<BTreeMap<u32, usize>>>;
+
 impl<T: BorrowMut<MapData>> PerfEventArrayBuffer<T> {
+    /// Returns the index of the perf event array the buffer is stored at.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
     /// Returns true if the buffer contains events that haven't been read.
     pub fn readable(&self) -> bool {
         self.buf.readable()
     }
 
+    /// Replaces the memory of the buffer with a new region of `page_count` pages.
+    ///
+    /// This is meant to grow buffers that lose samples, without the race of dropping the buffer
+    /// and opening a new one. The perf event stays open, so the buffer stays stored in the perf
+    /// event array and its file descriptor stays valid. The event is disabled while the memory
+    /// is replaced, and samples sent in the meantime are dropped. The events that haven't been
+    /// read yet are discarded with the old memory, so read them first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PerfBufferError::InvalidPageCount`] if `page_count` isn't a power of two, in
+    /// which case the buffer is left untouched. If the new region can't be mapped, the error is
+    /// returned and the buffer gets a new empty region of the previous size. If that can't be
+    /// mapped either, [`PerfBufferError::Closed`] is returned and the buffer stays empty, with
+    /// its event disabled, until it's resized again.
+    pub fn resize(&mut self, page_count: usize) -> Result<(), PerfBufferError> {
+        self.buf.resize(page_count)
+    }
+
+    /// Returns the number of bytes of records in the buffer that haven't been read.
+    ///
+    /// This is cheap to compute, since it only reads the positions of the ring. After
+    /// [`read_events`](Self::read_events), a non zero value means that the read stopped because
+    /// all the output buffers were filled rather than because the buffer was drained, so it can
+    /// be called again right away instead of waiting for new events. See
+    /// [`pending_records`](Self::pending_records) for the exact number of samples.
+    pub fn available_bytes(&self) -> usize {
+        self.buf.available_bytes()
+    }
+
+    /// Returns the number of samples in the buffer that haven't been read.
+    ///
+    /// The headers of all the pending records are walked to count the samples, without copying
+    /// their data, so the count is exact. Lost and other non sample records aren't counted. This
+    /// can be used to size the output buffers of [`read_events`](Self::read_events) before a
+    /// drain. More samples can arrive in the meantime, the count is only a snapshot.
+    pub fn pending_records(&self) -> usize {
+        self.buf.pending_records()
+    }
+
+    /// Returns the number of records in the buffer that haven't been read, without consuming
+    /// them.
+    ///
+    /// Unlike [`pending_records`](Self::pending_records), all the records are counted, including
+    /// lost and other non sample records. Only the record headers are read: no data is copied
+    /// and the read position isn't moved, so a later [`read_events`](Self::read_events) still
+    /// returns all the events. This is meant for metrics and monitoring of the queue depth.
+    pub fn peek_events(&self) -> usize {
+        self.buf.peek_events()
+    }
+
+    /// Returns the fields included in the samples of this buffer.
+    ///
+    /// This is the [`SampleType`] the buffer was opened with, see
+    /// [`PerfEventArray::open_with_config`]. Generic readers can use it to find out which fields
+    /// are present in the samples returned by [`read_samples`](Self::read_samples). The raw
+    /// bitmask is available with [`SampleType::bits`].
+    pub fn sample_type(&self) -> SampleType {
+        self.buf.sample_type
+    }
+
+    /// Opens a perf event on the given CPU whose samples are written to this buffer.
+    ///
+    /// The output is redirected at open time with `PERF_FLAG_FD_OUTPUT`, so the new event never
+    /// delivers samples to a ring of its own. The returned file descriptor must be kept open for
+    /// as long as the event should keep producing samples.
+    ///
+    /// Note that some kernels reject `PERF_FLAG_FD_OUTPUT`, in which case
+    /// [`PerfBufferError::OpenError`] is returned.
+    pub fn open_output(
+        &self,
+        cpu_id: u32,
+        config: &PerfEventConfig,
+    ) -> Result<OwnedFd, PerfBufferError> {
+        self.buf.open_output(cpu_id, config)
+    }
+
+    /// Enables the perf event of the buffer, with the `PERF_EVENT_IOC_ENABLE` ioctl.
+    ///
+    /// Buffers are enabled when opened, this resumes an event paused with
+    /// [`disable`](Self::disable).
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::PerfEventEnableError`] is returned when the event can't be enabled.
+    pub fn enable(&self) -> Result<(), PerfBufferError> {
+        self.buf.enable()
+    }
+
+    /// Pauses the perf event of the buffer, with the `PERF_EVENT_IOC_DISABLE` ioctl.
+    ///
+    /// No new samples are written to the buffer while the event is disabled, for example
+    /// during a configuration reload, but the buffer stays mapped and the samples it already
+    /// holds can still be read. Call [`enable`](Self::enable) to resume.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::PerfEventDisableError`] is returned when the event can't be disabled.
+    pub fn disable(&self) -> Result<(), PerfBufferError> {
+        self.buf.disable()
+    }
+
+    /// Enables the perf event of the buffer until the returned guard is dropped.
+    ///
+    /// Buffers are enabled when opened, this is meant for scoped profiling windows where events
+    /// should only be collected while the guard is alive. The event is disabled on drop, even on
+    /// early returns and panics.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::PerfEventEnableError`] is returned when the event can't be enabled.
+    pub fn enabled_scope(&self) -> Result<EnableGuard<'_>, PerfBufferError> {
+        self.buf.enabled_scope()
+    }
+
+    /// Returns the id the kernel assigned to the perf event of the buffer.
+    ///
+    /// This is the id obtained with the `PERF_EVENT_IOC_ID` ioctl, and the one reported in
+    /// samples and read values when [`SampleType::ID`](crate::maps::perf::SampleType::ID) or
+    /// [`ReadFormat::ID`](crate::maps::perf::ReadFormat::ID) are requested. It can be used to
+    /// correlate the buffer with the ids used by other perf tooling.
+    pub fn event_id(&self) -> io::Result<u64> {
+        self.buf.event_id()
+    }
+
+    /// Resets the count of the perf event of the buffer to zero, with the
+    /// `PERF_EVENT_IOC_RESET` ioctl.
+    pub fn reset(&self) -> io::Result<()> {
+        self.buf.reset()
+    }
+
+    /// Enables the perf event of the buffer for `count` overflows, after which it's disabled
+    /// again, with the `PERF_EVENT_IOC_REFRESH` ioctl.
+    ///
+    /// This is meant for bounded captures of sampling events, like PMU events opened with
+    /// [`PerfEventConfig::event`]: the event is armed for exactly `count` samples, then the
+    /// kernel disables it and signals `POLLHUP` on the fd, so no timer is needed to stop the
+    /// capture. Calling `refresh()` again re-arms the event for another `count` overflows. The
+    /// kernel doesn't support refreshing inherited events.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] when `count` exceeds `i32::MAX`, and the error of
+    /// the ioctl when it fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::{
+    ///     maps::{perf::PerfEventConfig, PerfEventArray},
+    ///     programs::perf_event::PerfTypeId,
+    /// };
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut config = PerfEventConfig::new();
+    /// config.event(PerfTypeId::Raw, 0x3c);
+    /// let buf = perf_array.open_with_config(0, None, &config)?;
+    /// // collect 100 samples then stop
+    /// buf.refresh(100)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn refresh(&self, count: u32) -> io::Result<()> {
+        self.buf.refresh(count)
+    }
+
     /// Reads events from the buffer.
     ///
     /// This method reads events into the provided slice of buffers, filling
@@ -50,10 +247,783 @@ impl<T: BorrowMut<MapData>> PerfEventArrayBuffer<T> {
     ///
     /// # Errors
     ///
-    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty,
+    /// [`PerfBufferError::NoRawData`] when the buffer wasn't opened with [`SampleType::RAW`], and
+    /// [`PerfBufferError::InvalidEventSize`] when the ring is corrupted.
     pub fn read_events(&mut self, out_bufs: &mut [BytesMut]) -> Result<Events, PerfBufferError> {
         self.buf.read_events(out_bufs)
     }
+
+    /// Reads events from the buffer as long as `should_continue` returns true.
+    ///
+    /// This works like [`read_events`](Self::read_events), but calls `should_continue` before
+    /// each event and stops as soon as it returns false, for example because the downstream queue
+    /// is full. The events that aren't read stay in the ring until the next read. Once the ring
+    /// fills up the kernel drops new events, which are then reported as lost.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    pub fn read_events_while(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        should_continue: impl Fn() -> bool,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_while(out_bufs, should_continue)
+    }
+
+    /// Reads events from the buffer as long as `should_continue` returns true, checking it every
+    /// `budget_check_interval` records.
+    ///
+    /// This works like [`read_events_while`](Self::read_events_while), but only calls
+    /// `should_continue` before the first record and then once every `budget_check_interval`
+    /// records, which trades the precision of the budget for less overhead at high event rates.
+    /// An interval of 0 is treated as 1.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    ///
+    /// # Examples
+    ///
+    /// Drain for at most a millisecond, checking the clock every 1024 records:
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use aya::maps::PerfEventArray;
+    /// use bytes::BytesMut;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut out_bufs = (0..4096)
+    ///     .map(|_| BytesMut::with_capacity(64))
+    ///     .collect::<Vec<_>>();
+    /// let deadline = Instant::now() + Duration::from_millis(1);
+    /// let events = buf.read_events_while_interval(&mut out_bufs, 1024, || Instant::now() < deadline)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_while_interval(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        budget_check_interval: usize,
+        should_continue: impl Fn() -> bool,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf
+            .read_events_while_interval(out_bufs, budget_check_interval, should_continue)
+    }
+
+    /// Reads events from the buffer, skipping the ones smaller than `min_sample_size` bytes.
+    ///
+    /// This works like [`read_events`](Self::read_events), but events with less than
+    /// `min_sample_size` bytes of data, like heartbeats, are consumed without being copied and
+    /// aren't counted in [`Events::read`].
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    pub fn read_events_min_size(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        min_sample_size: usize,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_min_size(out_bufs, min_sample_size)
+    }
+
+    /// Waits up to `timeout` for events, then reads them.
+    ///
+    /// If the buffer has no pending events, this blocks until the kernel signals the buffer's
+    /// file descriptor or `timeout` elapses, forever if `timeout` is `None`, and then works like
+    /// [`read_events`](Self::read_events). Interrupted waits are resumed with the remaining time.
+    /// When the timeout elapses without events, the returned [`Events`] are empty.
+    ///
+    /// The kernel signals the file descriptor according to the wakeup settings of the perf
+    /// event, so events may be pending for a while before the wait returns.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use std::time::Duration;
+    ///
+    /// use aya::maps::PerfEventArray;
+    /// use bytes::BytesMut;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut out_bufs = [BytesMut::with_capacity(1024)];
+    /// loop {
+    ///     let events = buf.read_events_blocking(&mut out_bufs, Some(Duration::from_secs(1)))?;
+    ///     if events.read == 0 {
+    ///         println!("no events in the last second");
+    ///     }
+    ///     for data in events.filled(&mut out_bufs) {
+    ///         println!("{data:?}");
+    ///     }
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_blocking(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        timeout: Option<Duration>,
+    ) -> Result<Events, PerfBufferError> {
+        match self.read_events_timeout(out_bufs, timeout)? {
+            TimedEvents::TimedOut => Ok(Events::new(0, 0)),
+            TimedEvents::Read(events) => Ok(events),
+        }
+    }
+
+    /// Waits up to `timeout` for events, then reads them, telling a timeout apart from a read.
+    ///
+    /// This works like [`read_events_blocking`](Self::read_events_blocking), but returns
+    /// [`TimedEvents::TimedOut`] when `timeout` elapses before the buffer becomes readable. A
+    /// read can still return no samples, for example when the buffer only had lost records.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    ///
+    /// # Examples
+    ///
+    /// Check a shutdown flag at least every 100ms:
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use std::{
+    ///     sync::atomic::{AtomicBool, Ordering},
+    ///     time::Duration,
+    /// };
+    ///
+    /// use aya::maps::{perf::TimedEvents, PerfEventArray};
+    /// use bytes::BytesMut;
+    ///
+    /// static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut out_bufs = [BytesMut::with_capacity(1024)];
+    /// while !SHUTDOWN.load(Ordering::Relaxed) {
+    ///     match buf.read_events_timeout(&mut out_bufs, Some(Duration::from_millis(100)))? {
+    ///         TimedEvents::TimedOut => continue,
+    ///         TimedEvents::Read(events) => {
+    ///             for data in events.filled(&mut out_bufs) {
+    ///                 println!("{data:?}");
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_timeout(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        timeout: Option<Duration>,
+    ) -> Result<TimedEvents, PerfBufferError> {
+        if out_bufs.is_empty() {
+            return Err(PerfBufferError::NoBuffers);
+        }
+        if !self.buf.readable() && !wait_readable(self.buf.as_fd(), timeout)? {
+            return Ok(TimedEvents::TimedOut);
+        }
+        self.buf.read_events(out_bufs).map(TimedEvents::Read)
+    }
+
+    /// Reads events from the buffer and forwards a copy of them to `tee`.
+    ///
+    /// This works like [`read_events`](Self::read_events), and additionally sends each event
+    /// read to `tee`, for example to feed another tool from a monitoring proxy. When the kernel
+    /// reports lost events, a [`Record::Lost`] marker is sent before the samples. Forwarding stops
+    /// silently if the receiver has been dropped.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    pub fn read_events_tee(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        tee: &Sender<Record<'static>>,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_tee(out_bufs, tee)
+    }
+
+    /// Reads events from the buffer, calling `on_throttle` for each throttle record.
+    ///
+    /// This works like [`read_events`](Self::read_events), but also reports the
+    /// `PERF_RECORD_THROTTLE` records the kernel emits when a sampling event produces too many
+    /// samples. Profilers can react to them by lowering their sampling frequency.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    pub fn read_events_throttled(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        on_throttle: impl FnMut(Throttle),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_throttled(out_bufs, on_throttle)
+    }
+
+    /// Reads events from the buffer, calling `on_lost` for each lost record.
+    ///
+    /// This works like [`read_events`](Self::read_events), but also reports the id of the perf
+    /// event each `PERF_RECORD_LOST` record is for, along with its count, so that losses can be
+    /// attributed to specific streams. [`Events::lost`] is still the total of the counts.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `out_bufs` is empty.
+    pub fn read_events_lost(
+        &mut self,
+        out_bufs: &mut [BytesMut],
+        on_lost: impl FnMut(Lost),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_lost(out_bufs, on_lost)
+    }
+
+    /// Reads events until the buffer is empty, calling `f` with each sample.
+    ///
+    /// This calls [`read_events`](Self::read_events) with the buffers of `pool` as many times as
+    /// needed to empty the buffer, so that no events are left behind until the next wakeup. The
+    /// buffers are reused by every read, and a buffer is added to `pool` if it's empty. Returns
+    /// the totals of all the reads.
+    ///
+    /// The events sent while draining are read too, so under a constant stream of events this
+    /// only returns once the reader catches up. Use
+    /// [`read_events_while`](Self::read_events_while) to bound the time spent reading.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    /// use bytes::BytesMut;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut pool = vec![BytesMut::with_capacity(1024); 16];
+    /// let events = buf.drain(&mut pool, |data| println!("{data:?}"))?;
+    /// println!("read {} events, lost {}", events.read, events.lost);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn drain(
+        &mut self,
+        pool: &mut Vec<BytesMut>,
+        f: impl FnMut(&BytesMut),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.drain(pool, f)
+    }
+
+    /// Returns an iterator over the records in the buffer that doesn't consume them.
+    ///
+    /// Unlike the `read_*` methods, this only borrows the buffer immutably, so other read only
+    /// methods can be called while iterating. The records stay in the buffer until they're
+    /// released with [`commit`](Self::commit).
+    ///
+    /// The iterator returns [`PerfBufferError::InvalidEventSize`] and stops when the ring is
+    /// corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::{perf::Record, PerfEventArray};
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    ///
+    /// let mut records = buf.records();
+    /// for record in &mut records {
+    ///     match record? {
+    ///         Record::Sample(data) => println!("{data:?}"),
+    ///         Record::Lost(count) => println!("lost {count} events"),
+    ///     }
+    /// }
+    /// let cursor = records.cursor();
+    /// buf.commit(cursor);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn records(&self) -> Records<'_> {
+        Records::new(&self.buf)
+    }
+
+    /// Releases the records read with [`records`](Self::records) up to `cursor`.
+    ///
+    /// Cursors that are behind the records already released are ignored, and so are the cursors
+    /// of other buffers.
+    pub fn commit(&mut self, cursor: RecordCursor) {
+        self.buf.commit(cursor)
+    }
+
+    /// Reads events into `fresh` and hands the buffers back.
+    ///
+    /// This works like [`read_events`](Self::read_events), but takes ownership of the output
+    /// buffers for the duration of the read. It's meant for double buffering schemes where one
+    /// set of buffers is drained while another is being processed, so that neither side ever
+    /// touches a buffer that is being written to.
+    ///
+    /// Returns the buffers along with the number of events read and lost. Only the first
+    /// `events.read` buffers are filled, see [`Events::filled`].
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `fresh` is empty.
+    pub fn read_events_swap(
+        &mut self,
+        mut fresh: Vec<BytesMut>,
+    ) -> Result<(Vec<BytesMut>, Events), PerfBufferError> {
+        let events = self.buf.read_events(&mut fresh)?;
+        Ok((fresh, events))
+    }
+
+    /// Calls `f` with the data of each sample in the buffer, until `f` returns
+    /// [`ControlFlow::Break`].
+    ///
+    /// The sample for which `f` returned `Break` is consumed, the following ones are left in the
+    /// buffer for the next pass, and [`Events::remaining`] is set to the number of samples left.
+    /// Sample data is borrowed straight from the ring unless it wraps around its end.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// Process the samples up to a marker, then yield:
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use std::ops::ControlFlow;
+    ///
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let events = buf.for_each_sample(|data| {
+    ///     if data == b"MARK" {
+    ///         return ControlFlow::Break(());
+    ///     }
+    ///     println!("{data:?}");
+    ///     ControlFlow::Continue(())
+    /// })?;
+    /// println!("{} samples left for the next pass", events.remaining);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn for_each_sample(
+        &mut self,
+        f: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.for_each_sample(f)
+    }
+
+    /// Reads events into a fixed, caller-provided memory region.
+    ///
+    /// This is meant for contexts where all the memory is allocated up front: the data of each
+    /// sample is written to `region` as a native endian `u32` length followed by the data, with
+    /// no allocation. Reading stops when the next sample doesn't fit in what's left of the region,
+    /// leaving it and the following events in the buffer for the next read. The region should be
+    /// sized for the largest sample the eBPF program sends.
+    ///
+    /// The returned [`FramedEvents`] holds the number of events read and lost, and the written
+    /// part of the region, which can be decoded with [`FramedEvents::frames`].
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    /// [`PerfBufferError::RegionTooSmall`] is returned when the next sample doesn't fit in the
+    /// whole region, in which case it's left in the buffer and can be read with a larger region.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use std::mem::MaybeUninit;
+    ///
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut region = [MaybeUninit::uninit(); 4096];
+    /// let framed = buf.read_events_framed(&mut region)?;
+    /// for data in framed.frames() {
+    ///     println!("{data:?}");
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_framed<'a>(
+        &mut self,
+        region: &'a mut [MaybeUninit<u8>],
+    ) -> Result<FramedEvents<'a>, PerfBufferError> {
+        self.buf.read_events_framed(region)
+    }
+
+    /// Reads all the events in the buffer, calling `f` with the data of each sample without
+    /// copying it.
+    ///
+    /// The slice passed to `f` points straight into the ring, unless the sample wraps around the
+    /// end of the ring, in which case it's copied into a scratch buffer owned by the buffer first.
+    /// The scratch buffer grows to the largest wrapped sample and is reused by later reads. The
+    /// slice is only valid for the duration of the call: the space is released to the kernel, to
+    /// be overwritten by new events, once `f` returns. Data that must outlive the call has to be
+    /// copied.
+    ///
+    /// This avoids an allocation and a copy per sample when only a few fields of each sample are
+    /// needed.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let mut total = 0u64;
+    /// let events = buf.read_events_borrowed(|data| {
+    ///     // only keep the first field of each sample
+    ///     total += u64::from(u32::from_ne_bytes(data[..4].try_into().unwrap()));
+    /// })?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_borrowed(
+        &mut self,
+        f: impl FnMut(&[u8]),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_borrowed(f)
+    }
+
+    /// Reads all the events in the buffer, transforming the data of each sample with `decode`.
+    ///
+    /// This is meant for eBPF programs that send encoded payloads, for example compressed ones to
+    /// fit more events in the ring. The data of each sample is passed to `decode`, borrowed
+    /// straight from the ring unless it wraps around its end, and `f` is called with the decoded
+    /// data. The ring mechanics are handled the same way as [`read_events`](Self::read_events).
+    ///
+    /// # Errors
+    ///
+    /// When `decode` fails, reading stops and its error is returned. The samples before it have
+    /// been passed to `f`. The sample that failed to decode is consumed, so that a corrupted
+    /// payload doesn't block the buffer, and the following ones are left for the next read.
+    ///
+    /// The error type of `decode` must convert from [`PerfBufferError`], which is returned as
+    /// [`PerfBufferError::InvalidEventSize`] when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// # fn decompress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> { Ok(data.to_vec()) }
+    /// use aya::maps::{perf::PerfBufferError, PerfEventArray};
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let events = buf.read_events_decoded(
+    ///     |data| decompress(data).map_err(PerfBufferError::from),
+    ///     |data| println!("{data:?}"),
+    /// )?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_decoded<E: From<PerfBufferError>>(
+        &mut self,
+        decode: impl FnMut(&[u8]) -> Result<Vec<u8>, E>,
+        f: impl FnMut(Vec<u8>),
+    ) -> Result<Events, E> {
+        self.buf.read_events_decoded(decode, f)
+    }
+
+    /// Reads all the events in the buffer, collapsing runs of identical consecutive samples.
+    ///
+    /// Consecutive samples for which `eq` returns true are returned once, along with the number of
+    /// times they were repeated. `eq` is called with the first sample of the current run and the
+    /// next sample. This reduces the volume of data to process downstream for eBPF programs that
+    /// send bursts of repeated events, without materializing the bursts first.
+    ///
+    /// Returns the coalesced samples, in order, and the number of events read and lost.
+    /// [`Events::read`] counts every sample, including the repeats.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let (samples, _) = buf.read_events_coalesced(|a, b| a == b)?;
+    /// for (data, count) in samples {
+    ///     println!("{data:?} x{count}");
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_coalesced(
+        &mut self,
+        eq: impl Fn(&[u8], &[u8]) -> bool,
+    ) -> Result<(Coalesced, Events), PerfBufferError> {
+        self.buf.read_events_coalesced(eq)
+    }
+
+    /// Reads all the events in the buffer, appending the data of the samples to a single
+    /// buffer.
+    ///
+    /// The data of each sample is appended to `out`, and its range in `out` is pushed to
+    /// `ranges`. Neither is cleared first, so both can be reused across reads, or accumulate
+    /// the samples of several buffers. This suits consumers that process or re-serialize the
+    /// samples as a batch, and saves an allocation per sample compared to
+    /// [`read_events`](Self::read_events).
+    ///
+    /// Returns the number of events read and lost.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// let (mut out, mut ranges) = (Vec::new(), Vec::new());
+    /// buf.read_events_batched(&mut out, &mut ranges)?;
+    /// for range in ranges.drain(..) {
+    ///     println!("{:?}", &out[range]);
+    /// }
+    /// out.clear();
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_batched(
+        &mut self,
+        out: &mut Vec<u8>,
+        ranges: &mut Vec<Range<usize>>,
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_batched(out, ranges)
+    }
+
+    /// Reads all the events in the buffer, splitting each one into logical samples.
+    ///
+    /// This is meant for eBPF programs that batch several logical samples in a single event. The
+    /// payload of each event is passed to `split`, which returns the byte ranges of the logical
+    /// samples it contains, and `f` is called with each of them. Payloads are borrowed straight
+    /// from the ring unless they wrap around its end.
+    ///
+    /// Returns the number of events (not logical samples) read and the number of events lost.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut buf = perf_array.open(0, None)?;
+    /// // the eBPF program sends batches of 16 byte records
+    /// let events = buf.read_events_split(
+    ///     |payload| (0..payload.len() / 16).map(|i| i * 16..(i + 1) * 16),
+    ///     |record| println!("{record:?}"),
+    /// )?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_events_split<I: IntoIterator<Item = Range<usize>>>(
+        &mut self,
+        split: impl FnMut(&[u8]) -> I,
+        f: impl FnMut(&[u8]),
+    ) -> Result<Events, PerfBufferError> {
+        self.buf.read_events_split(split, f)
+    }
+
+    /// Reads samples from the buffer.
+    ///
+    /// This works like [`read_events`](Self::read_events), but decodes each sample according to
+    /// the [`SampleType`] the buffer was opened with, see
+    /// [`PerfEventArray::open_with_config`].
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::NoBuffers`] is returned when `samples` is empty.
+    pub fn read_samples(&mut self, samples: &mut [Sample]) -> Result<Events, PerfBufferError> {
+        self.buf.read_samples(samples)
+    }
+
+    /// Writes the events that haven't been read yet to `writer`, without consuming them.
+    ///
+    /// The dump also records the sample format of the buffer, so that it can be decoded later
+    /// with [`ReplaySource`](crate::maps::perf::ReplaySource), for example to investigate a
+    /// problematic buffer offline.
+    pub fn dump_ring(&self, writer: impl io::Write) -> io::Result<()> {
+        self.buf.dump(writer)
+    }
+
+    /// Sets whether output buffers are zeroed before being filled.
+    ///
+    /// Output buffers are reused across reads, so the bytes of a long sample stay in the spare
+    /// capacity of its buffer when a shorter sample is read into it next. When samples carry
+    /// sensitive data, enabling this overwrites the whole capacity of each buffer with zeros
+    /// before filling it, in [`read_events`](Self::read_events) and its variants, and for the raw
+    /// data of [`read_samples`](Self::read_samples). Disabled by default.
+    pub fn set_zero_on_reuse(&mut self, zero_on_reuse: bool) {
+        self.buf.set_zero_on_reuse(zero_on_reuse)
+    }
+
+    /// Moves the buffer to another index of the perf event array.
+    ///
+    /// This is meant for keeping a stable set of buffers across CPU hotplug, when the index a
+    /// buffer was opened at no longer matches the CPU it should receive events for. The buffer
+    /// is first drained like with [`drain`](Self::drain), calling `f` with the samples sent to
+    /// the old index, and the totals of the drain are returned. It's then stored at `new_index`
+    /// before its old index is cleared, so events sent to either index during the move end up in
+    /// the buffer and none are lost. The mmap is kept.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::IndexOutOfBounds`] is returned when `new_index` is out of the bounds of
+    /// the perf event array, and [`PerfBufferError::IndexAlreadyOccupied`] when another buffer is
+    /// open at `new_index`. The buffer is left at its old index in both cases, drained in the
+    /// latter.
+    pub fn reassign(
+        &mut self,
+        new_index: u32,
+        pool: &mut Vec<BytesMut>,
+        f: impl FnMut(&BytesMut),
+    ) -> Result<Events, PerfBufferError> {
+        let map_data: &MapData = self.map.deref().borrow();
+        let max_entries = check_bounds(map_data, new_index)?;
+        let events = self.buf.drain(pool, f)?;
+        if new_index == self.index {
+            return Ok(events);
+        }
+
+        let map_fd = map_data.fd().as_fd();
+        let mut open_buffers = self
+            .open_buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if open_buffers.contains_key(&new_index) {
+            return Err(PerfBufferError::IndexAlreadyOccupied { index: new_index });
+        }
+        store_buffer(map_fd, new_index, max_entries, self.buf.as_raw_fd())?;
+        // the old index is only cleared once no other buffer is open at it
+        if release_index(&mut open_buffers, self.index) {
+            let _: SysResult<_> = bpf_map_delete_elem(map_fd, &self.index);
+        }
+        *open_buffers.entry(new_index).or_default() += 1;
+        self.index = new_index;
+        Ok(events)
+    }
+
+    /// Returns the lifetime counters of the buffer.
+    ///
+    /// The counters are monotonic unless reset with
+    /// [`stats_and_reset`](Self::stats_and_reset).
+    pub fn stats(&self) -> BufferStats {
+        self.buf.stats()
+    }
+
+    /// Returns the number of events the kernel reported as lost since the buffer was opened, or
+    /// since the counters were last reset with [`stats_and_reset`](Self::stats_and_reset).
+    ///
+    /// Unlike [`Events::lost`], which only covers a single read, this is a running total that
+    /// saturates at `u64::MAX`, suitable for exporting a dropped samples metric. It's a
+    /// shorthand for the `lost` field of [`stats`](Self::stats).
+    pub fn lost_since_start(&self) -> u64 {
+        self.buf.stats().lost
+    }
+
+    /// Returns the lifetime counters of the buffer and resets them to zero.
+    ///
+    /// Records are only counted while the buffer is borrowed mutably, so no record can be
+    /// counted between the snapshot and the reset. This is meant for reset-per-scrape metrics
+    /// that need clean deltas.
+    pub fn stats_and_reset(&mut self) -> BufferStats {
+        self.buf.stats_and_reset()
+    }
+
+    /// Returns the factor by which the counter values of the event must be scaled.
+    ///
+    /// When more events are enabled than the PMU has counters, the kernel multiplexes them and
+    /// each event only counts for part of the time it's enabled. The factor is
+    /// `time_enabled / time_running`, as reported in the control page of the buffer. It's 1.0
+    /// when the event was never multiplexed, or hasn't run yet. A factor greater than 1.0 means
+    /// that raw counter values only cover part of the time, and that scaled values are
+    /// estimates.
+    pub fn scaling_factor(&self) -> f64 {
+        self.buf.scaling_factor()
+    }
+
+    /// Returns true if the event was multiplexed, see
+    /// [`scaling_factor`](Self::scaling_factor).
+    pub fn is_multiplexed(&self) -> bool {
+        self.buf.is_multiplexed()
+    }
+
+    /// Formats all the fields of the control page of the buffer, for bug reports.
+    ///
+    /// The control page is the `perf_event_mmap_page` the kernel shares with user space. The
+    /// returned string has one `name: value` line per field, in hexadecimal, including the
+    /// positions of the data and aux areas and the time conversion fields. This gives a
+    /// complete snapshot of the state reported by the kernel when diagnosing a misbehaving
+    /// buffer.
+    pub fn dump_page(&self) -> String {
+        self.buf.dump_page()
+    }
+
+    /// Discards all the events in the buffer without copying them.
+    ///
+    /// This is meant for consumers that only need loss accounting: samples are
+    /// skipped over without being read, while lost events are still counted.
+    ///
+    /// Returns the number of events discarded and the number of events lost.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::InvalidEventSize`] is returned when the ring is corrupted.
+    pub fn discard_events(&mut self) -> Result<Events, PerfBufferError> {
+        self.buf.discard_events()
+    }
+}
+
+impl<T> Drop for PerfEventArrayBuffer<T> {
+    fn drop(&mut self) {
+        let mut open_buffers = self
+            .open_buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        release_index(&mut open_buffers, self.index);
+    }
+}
+
+// Decrements the number of buffers open at `index`, returning true if it was the last one.
+fn release_index(open_buffers: &mut BTreeMap<u32, usize>, index: u32) -> bool {
+    match open_buffers.entry(index) {
+        Entry::Occupied(mut entry) => {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+                true
+            } else {
+                false
+            }
+        }
+        Entry::Vacant(_) => false,
+    }
 }
 
 impl<T: BorrowMut<MapData>> AsFd for PerfEventArrayBuffer<T> {
@@ -62,6 +1032,9 @@ impl<T: BorrowMut<MapData>> AsFd for PerfEventArrayBuffer<T> {
     }
 }
 
+/// The returned fd is a genuine perf event fd. It can be passed to other libraries expecting one,
+/// and used with the standard perf ioctls and with `read()`, as described in
+/// `perf_event_open(2)`. It stays owned by the buffer and is closed when the buffer is dropped.
 impl<T: BorrowMut<MapData>> AsRawFd for PerfEventArrayBuffer<T> {
     fn as_raw_fd(&self) -> RawFd {
         self.buf.as_raw_fd()
@@ -165,6 +1138,9 @@ impl<T: BorrowMut<MapData>> AsRawFd for PerfEventArrayBuffer<T> {
 pub struct PerfEventArray<T> {
     map: Arc<T>,
     page_size: usize,
+    default_page_count: usize,
+    page_counts: BTreeMap<u32, usize>,
+    open_buffers: OpenBuffers,
 }
 
 impl<T: Borrow<MapData>> PerfEventArray<T> {
@@ -172,9 +1148,57 @@ impl<T: Borrow<MapData>> PerfEventArray<T> {
         Ok(Self {
             map: Arc::new(map),
             page_size: page_size(),
+            default_page_count: 2,
+            page_counts: BTreeMap::new(),
+            open_buffers: Default::default(),
         })
     }
 
+    /// Sets the page count of the buffers opened without an explicit page count.
+    ///
+    /// The page count is used when `None` is passed to [`open`](PerfEventArray::open) and the
+    /// other opening methods, for the indexes without an override set with
+    /// [`set_page_count`](Self::set_page_count). The default is 2. It must be a power of two,
+    /// which is checked when opening a buffer, before any syscall.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// // CPU 2 handles the busy network queue
+    /// perf_array.set_default_page_count(8).set_page_count(2, 256);
+    /// let (buffers, _) = perf_array.open_all(None, false)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_default_page_count(&mut self, page_count: usize) -> &mut Self {
+        self.default_page_count = page_count;
+        self
+    }
+
+    /// Sets the page count of the buffer opened without an explicit page count at `index`.
+    ///
+    /// This overrides the [default page count](Self::set_default_page_count) for a single index,
+    /// usually a CPU id.
+    pub fn set_page_count(&mut self, index: u32, page_count: usize) -> &mut Self {
+        self.page_counts.insert(index, page_count);
+        self
+    }
+
+    /// Returns the indexes, usually CPU ids, that currently have open buffers.
+    ///
+    /// Buffers are tracked from [`open`](Self::open) until the returned [`PerfEventArrayBuffer`]
+    /// is dropped. The indexes are returned in ascending order.
+    pub fn open_cpus(&self) -> Vec<u32> {
+        let open_buffers = self
+            .open_buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        open_buffers.keys().copied().collect()
+    }
+
     /// Pins the map to a BPF filesystem.
     ///
     /// When a map is pinned it will remain loaded until the corresponding file
@@ -183,28 +1207,671 @@ impl<T: Borrow<MapData>> PerfEventArray<T> {
         let data: &MapData = self.map.deref().borrow();
         data.pin(path)
     }
+
+    /// Returns true if a perf buffer with the given page count can be opened.
+    ///
+    /// This opens a single buffer on the first online CPU and immediately closes it, without
+    /// touching the map. It can be used to check that `page_count` is valid and fits within
+    /// `RLIMIT_MEMLOCK` before opening a buffer for every CPU.
+    pub fn can_open(&self, page_count: usize) -> bool {
+        let cpu_id = online_cpus()
+            .ok()
+            .and_then(|cpus| cpus.first().copied())
+            .unwrap_or(0);
+        PerfBuffer::open(
+            cpu_id,
+            self.page_size,
+            page_count,
+            &PerfEventConfig::default(),
+        )
+        .is_ok()
+    }
 }
 
 impl<T: BorrowMut<MapData>> PerfEventArray<T> {
     /// Opens the perf buffer at the given index.
     ///
     /// The returned buffer will receive all the events eBPF programs send at the given index.
+    /// When `page_count` is `None`, the page count set with
+    /// [`set_page_count`](PerfEventArray::set_page_count) for the index, or else the
+    /// [default page count](PerfEventArray::set_default_page_count), is used.
+    ///
+    /// The page count is the number of data pages, and must be a power of two. The kernel maps
+    /// an extra metadata page in front of them, so a buffer takes `page_count + 1` pages of
+    /// locked memory but holds `page_count * page_size` bytes of records. A single page is
+    /// valid, but it only has room for a few large samples: when eBPF programs produce samples
+    /// faster than they're read, most of them are lost and show up in [`Events::lost`]. Use a
+    /// larger page count for bursty or large samples.
     pub fn open(
         &mut self,
         index: u32,
         page_count: Option<usize>,
     ) -> Result<PerfEventArrayBuffer<T>, PerfBufferError> {
-        // FIXME: keep track of open buffers
+        self.open_with_config(index, page_count, &PerfEventConfig::default())
+    }
+
+    /// Opens the perf buffer at the given index for a raw PMU event.
+    ///
+    /// `config`, `config1` and `config2` are the vendor specific encoding of the event. This is a
+    /// shorthand for [`open_with_config`](Self::open_with_config) with a [`PerfEventConfig`]
+    /// set up with [`PerfTypeId::Raw`].
+    pub fn open_raw_event(
+        &mut self,
+        index: u32,
+        page_count: Option<usize>,
+        config: u64,
+        config1: u64,
+        config2: u64,
+    ) -> Result<PerfEventArrayBuffer<T>, PerfBufferError> {
+        let mut perf_config = PerfEventConfig::new();
+        perf_config
+            .event(PerfTypeId::Raw, config)
+            .config1(config1)
+            .config2(config2);
+        self.open_with_config(index, page_count, &perf_config)
+    }
+
+    /// Opens the perf buffer at the given index, using the given perf event configuration.
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::IndexAlreadyOccupied`] is returned when a buffer is already open at
+    /// `index`. Only one buffer can receive the events sent at a given index, so opening a second
+    /// one would silently stop the first one from receiving events. Only the buffers opened
+    /// through this `PerfEventArray` are detected, since the kernel doesn't allow looking up the
+    /// entries of a perf event array from user space.
+    ///
+    /// Storing the buffer in the map can fail transiently with `EBUSY` or `EAGAIN` when the map
+    /// is concurrently updated. The update is retried a few times with an exponential backoff,
+    /// starting at 1ms, before the error is returned.
+    ///
+    /// # Examples
+    ///
+    /// Receive the call chain of the code that produced each event:
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::perf::{PerfEventArray, PerfEventConfig, Sample, SampleType};
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let mut config = PerfEventConfig::new();
+    /// config.sample_type(SampleType::CALLCHAIN | SampleType::RAW);
+    /// let mut buf = perf_array.open_with_config(0, None, &config)?;
+    ///
+    /// let mut samples = [Sample::default()];
+    /// let events = buf.read_samples(&mut samples)?;
+    /// for sample in &samples[..events.read] {
+    ///     println!("{:x?} {:?}", sample.callchain, sample.data);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn open_with_config(
+        &mut self,
+        index: u32,
+        page_count: Option<usize>,
+        config: &PerfEventConfig,
+    ) -> Result<PerfEventArrayBuffer<T>, PerfBufferError> {
+        self.open_inner(index, -1, index as c_int, page_count, config)
+    }
+
+    /// Opens the perf buffer at the given index for a single thread, following it across CPUs.
+    ///
+    /// The perf event is opened for the thread `tid` on any CPU, so the buffer receives the
+    /// events of the thread regardless of the core it runs on. This is meant for debugging a
+    /// single thread, for example sampling it with a PMU event configured with
+    /// [`PerfEventConfig::event`].
+    ///
+    /// The kernel only lets eBPF programs output to the event while the thread runs on the
+    /// current CPU, so programs sending events to the buffer with `bpf_perf_event_output()`
+    /// must run in the context of the thread.
+    ///
+    /// # Errors
+    ///
+    /// See [`open_with_config`](Self::open_with_config).
+    pub fn open_for_thread(
+        &mut self,
+        index: u32,
+        tid: pid_t,
+        page_count: Option<usize>,
+        config: &PerfEventConfig,
+    ) -> Result<PerfEventArrayBuffer<T>, PerfBufferError> {
+        self.open_inner(index, tid, -1, page_count, config)
+    }
 
+    fn open_inner(
+        &mut self,
+        index: u32,
+        pid: pid_t,
+        cpu: c_int,
+        page_count: Option<usize>,
+        config: &PerfEventConfig,
+    ) -> Result<PerfEventArrayBuffer<T>, PerfBufferError> {
+        let page_count = page_count
+            .or_else(|| self.page_counts.get(&index).copied())
+            .unwrap_or(self.default_page_count);
+        if !page_count.is_power_of_two() {
+            return Err(PerfBufferError::InvalidPageCount { page_count });
+        }
         let map_data: &MapData = self.map.deref().borrow();
+        let max_entries = check_bounds(map_data, index)?;
+        if self
+            .open_buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(&index)
+        {
+            return Err(PerfBufferError::IndexAlreadyOccupied { index });
+        }
         let map_fd = map_data.fd().as_fd();
-        let buf = PerfBuffer::open(index, self.page_size, page_count.unwrap_or(2))?;
-        bpf_map_update_elem(map_fd, Some(&index), &buf.as_raw_fd(), 0)
-            .map_err(|(_, io_error)| io_error)?;
+        let buf = PerfBuffer::open_for(pid, cpu, self.page_size, page_count, config)?;
+        store_buffer(map_fd, index, max_entries, buf.as_raw_fd())?;
+
+        *self
+            .open_buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(index)
+            .or_default() += 1;
 
         Ok(PerfEventArrayBuffer {
             buf,
-            _map: self.map.clone(),
+            map: self.map.clone(),
+            index,
+            open_buffers: self.open_buffers.clone(),
         })
     }
+
+    /// Opens a perf buffer for each online CPU.
+    ///
+    /// CPUs can go offline between the enumeration of the online CPUs and the opening of their
+    /// buffer. When `skip_offline` is true, CPUs for which `perf_event_open` fails with `ENODEV`
+    /// are logged and skipped instead of failing the whole operation, which gives "open as many
+    /// as possible" semantics for best effort system-wide monitoring.
+    ///
+    /// Returns the opened buffers, in the order of [`online_cpus`], and the skipped CPUs.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// let (buffers, skipped) = perf_array.open_all(None, true)?;
+    /// if !skipped.is_empty() {
+    ///     eprintln!("not monitoring offline CPUs {skipped:?}");
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn open_all(
+        &mut self,
+        page_count: Option<usize>,
+        skip_offline: bool,
+    ) -> Result<(Vec<PerfEventArrayBuffer<T>>, Vec<u32>), PerfBufferError> {
+        self.open_all_inner(|_| page_count, skip_offline)
+    }
+
+    /// Opens a perf buffer for each online CPU, and returns them paired with their CPU id.
+    ///
+    /// The online CPUs are read with [`online_cpus`]. Unlike [`open_all`](Self::open_all), any
+    /// failure to open a buffer is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// for (cpu_id, buf) in perf_array.open_all_online(None)? {
+    ///     std::thread::spawn(move || {
+    ///         // read the events of `cpu_id` from `buf`
+    ///     });
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// Pin each reader thread to the CPU it drains, so that the buffer stays local to it:
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::{maps::PerfEventArray, util::bind_current_thread_to_cpu};
+    /// use bytes::BytesMut;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
+    /// for (cpu_id, mut buf) in perf_array.open_all_online(None)? {
+    ///     std::thread::spawn(move || -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ///         bind_current_thread_to_cpu(cpu_id)?;
+    ///         let mut out_bufs = [BytesMut::with_capacity(1024)];
+    ///         loop {
+    ///             let events = buf.read_events_blocking(&mut out_bufs, None)?;
+    ///             for data in events.filled(&mut out_bufs) {
+    ///                 println!("cpu {cpu_id}: {data:?}");
+    ///             }
+    ///         }
+    ///     });
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn open_all_online(
+        &mut self,
+        page_count: Option<usize>,
+    ) -> Result<Vec<(u32, PerfEventArrayBuffer<T>)>, PerfBufferError> {
+        online_cpus()?
+            .into_iter()
+            .map(|cpu_id| Ok((cpu_id, self.open(cpu_id, page_count)?)))
+            .collect()
+    }
+
+    /// Opens a perf buffer for each online CPU, with a page count computed for each CPU.
+    ///
+    /// `page_count_for` is called with the id of each CPU and returns the page count of its
+    /// buffer, which must be a power of two. This allows sizing the buffers for asymmetric
+    /// workloads, where some CPUs produce many more events than others, for example the CPUs
+    /// handling the interrupts of a busy network card. See [`open_all`](Self::open_all) for the
+    /// meaning of `skip_offline` and of the returned values.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    /// // CPUs 0 to 3 handle the network queues
+    /// let (buffers, _) = perf_array.open_all_with(|cpu_id| if cpu_id < 4 { 64 } else { 4 }, true)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn open_all_with(
+        &mut self,
+        page_count_for: impl Fn(u32) -> usize,
+        skip_offline: bool,
+    ) -> Result<(Vec<PerfEventArrayBuffer<T>>, Vec<u32>), PerfBufferError> {
+        self.open_all_inner(|cpu_id| Some(page_count_for(cpu_id)), skip_offline)
+    }
+
+    fn open_all_inner(
+        &mut self,
+        page_count_for: impl Fn(u32) -> Option<usize>,
+        skip_offline: bool,
+    ) -> Result<(Vec<PerfEventArrayBuffer<T>>, Vec<u32>), PerfBufferError> {
+        let mut buffers = Vec::new();
+        let mut skipped = Vec::new();
+        for cpu_id in online_cpus()? {
+            match self.open(cpu_id, page_count_for(cpu_id)) {
+                Ok(buf) => buffers.push(buf),
+                Err(PerfBufferError::OpenError { io_error })
+                    if skip_offline && io_error.raw_os_error() == Some(ENODEV) =>
+                {
+                    warn!("skipping offline CPU {cpu_id}: {io_error}");
+                    skipped.push(cpu_id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((buffers, skipped))
+    }
+
+    /// Opens a perf buffer for each online CPU, using at most `total_bytes` of memory.
+    ///
+    /// The page count of each buffer is the largest power of two such that all the buffers,
+    /// including their metadata page, fit within `total_bytes`. The buffers are returned in the
+    /// order of [`online_cpus`].
+    ///
+    /// # Errors
+    ///
+    /// [`PerfBufferError::BudgetTooSmall`] is returned when `total_bytes` is too small to fit a
+    /// single page per CPU.
+    pub fn open_all_within_budget(
+        &mut self,
+        total_bytes: usize,
+    ) -> Result<Vec<PerfEventArrayBuffer<T>>, PerfBufferError> {
+        let cpus = online_cpus()?;
+        let page_count = budget_page_count(total_bytes, self.page_size, cpus.len());
+        if page_count == 0 {
+            return Err(PerfBufferError::BudgetTooSmall {
+                total_bytes,
+                cpus: cpus.len(),
+            });
+        }
+        cpus.into_iter()
+            .map(|cpu_id| self.open(cpu_id, Some(page_count)))
+            .collect()
+    }
+}
+
+impl<T: BorrowMut<MapData> + Send + Sync> PerfEventArray<T> {
+    /// Closes the given buffers in parallel.
+    ///
+    /// Closing a buffer disables its perf event, unmaps its ring and closes its file descriptor.
+    /// These are independent for each buffer, so they're spread across a few scoped threads,
+    /// which shortens shutdown when many buffers are open. Returns once all the buffers are
+    /// closed.
+    ///
+    /// Each buffer holds a reference to the map, so the map is only closed once the last
+    /// reference is dropped, whether that's the [`PerfEventArray`] or one of the buffers.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # let mut bpf = aya::Bpf::load(&[])?;
+    /// use aya::maps::PerfEventArray;
+    ///
+    /// let mut perf_array = PerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
+    /// let (buffers, _) = perf_array.open_all(None, false)?;
+    /// // ...
+    /// PerfEventArray::close_all(buffers);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn close_all(buffers: Vec<PerfEventArrayBuffer<T>>) {
+        let threads = thread::available_parallelism()
+            .map_or(1, usize::from)
+            .min(CLOSE_THREADS)
+            .min(buffers.len());
+        if threads <= 1 {
+            drop(buffers);
+            return;
+        }
+        let mut batches: Vec<Vec<_>> = (0..threads).map(|_| Vec::new()).collect();
+        for (i, buf) in buffers.into_iter().enumerate() {
+            batches[i % threads].push(buf);
+        }
+        thread::scope(|s| {
+            for batch in batches {
+                let _: thread::ScopedJoinHandle<'_, ()> = s.spawn(move || drop(batch));
+            }
+        });
+    }
+}
+
+// The maximum number of threads used by PerfEventArray::close_all().
+const CLOSE_THREADS: usize = 8;
+
+// The number of times a transient map update failure is retried, and the delay before the first
+// retry. The delay doubles with each retry.
+const UPDATE_RETRIES: u32 = 5;
+const UPDATE_BACKOFF: Duration = Duration::from_millis(1);
+
+// Stores `fd` at `index`, retrying the map update when it fails with EBUSY or EAGAIN, which can
+// happen when the map is concurrently updated by another process.
+// Fails with PerfBufferError::IndexOutOfBounds if `index` is out of the bounds of the map,
+// returning its number of entries otherwise.
+fn check_bounds(map_data: &MapData, index: u32) -> Result<u32, PerfBufferError> {
+    let max_entries = map_data.obj.max_entries();
+    if index >= max_entries {
+        return Err(PerfBufferError::IndexOutOfBounds { index, max_entries });
+    }
+    Ok(max_entries)
+}
+
+// Stores the perf event `fd` at `index` of the map.
+fn store_buffer(
+    map_fd: BorrowedFd<'_>,
+    index: u32,
+    max_entries: u32,
+    fd: RawFd,
+) -> Result<(), PerfBufferError> {
+    update_with_retries(map_fd, index, fd).map_err(|io_error| {
+        // the kernel checks the bounds against the size the map was created with
+        if io_error.raw_os_error() == Some(E2BIG) {
+            PerfBufferError::IndexOutOfBounds { index, max_entries }
+        } else {
+            io_error.into()
+        }
+    })
+}
+
+fn update_with_retries(map_fd: BorrowedFd<'_>, index: u32, fd: RawFd) -> io::Result<()> {
+    let mut retry = 0;
+    loop {
+        match bpf_map_update_elem(map_fd, Some(&index), &fd, 0) {
+            Ok(_) => return Ok(()),
+            Err((_, io_error))
+                if retry < UPDATE_RETRIES
+                    && matches!(io_error.raw_os_error(), Some(EBUSY | EAGAIN)) =>
+            {
+                thread::sleep(UPDATE_BACKOFF * 2u32.pow(retry));
+                retry += 1;
+            }
+            Err((_, io_error)) => return Err(io_error),
+        }
+    }
+}
+
+// Returns the largest power of two page count such that `buffers` buffers of that size, plus
+// their metadata page, fit within `total_bytes`. Returns 0 if not even a single page fits.
+fn budget_page_count(total_bytes: usize, page_size: usize, buffers: usize) -> usize {
+    let per_buffer = total_bytes / page_size / buffers.max(1);
+    match per_buffer.checked_sub(1) {
+        Some(pages) if pages.is_power_of_two() => pages,
+        Some(pages) if pages > 0 => pages.next_power_of_two() / 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, ffi::c_long};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::{
+        generated::{bpf_cmd, bpf_map_type::BPF_MAP_TYPE_PERF_EVENT_ARRAY},
+        maps::Map,
+        obj::{self, maps::LegacyMap, BpfSectionKind},
+        sys::{override_syscall, Syscall, TEST_MMAP_RET},
+    };
+
+    thread_local! {
+        static UPDATES: Cell<usize> = Default::default();
+    }
+
+    fn new_map() -> MapData {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_CREATE,
+                ..
+            } => Ok(1337),
+            call => panic!("unexpected syscall {:?}", call),
+        });
+        MapData::create(
+            obj::Map::Legacy(LegacyMap {
+                def: obj::maps::bpf_map_def {
+                    map_type: BPF_MAP_TYPE_PERF_EVENT_ARRAY as u32,
+                    key_size: 4,
+                    value_size: 4,
+                    max_entries: 4,
+                    ..Default::default()
+                },
+                section_index: 0,
+                section_kind: BpfSectionKind::Maps,
+                symbol_index: None,
+                data: Vec::new(),
+            }),
+            "foo",
+            None,
+        )
+        .unwrap()
+    }
+
+    fn busy_then_ok(call: Syscall<'_>) -> SysResult<c_long> {
+        match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                ..
+            } => {
+                let updates = UPDATES.with(|updates| updates.replace(updates.get() + 1));
+                if updates < 2 {
+                    Err((-1, io::Error::from_raw_os_error(EBUSY)))
+                } else {
+                    Ok(0)
+                }
+            }
+            Syscall::PerfEventOpen { .. } => Ok(42),
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall {:?}", call),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_page_counts() {
+        let mut map = Map::PerfEventArray(new_map());
+        let mut perf_array = PerfEventArray::try_from(&mut map).unwrap();
+        perf_array.set_default_page_count(1).set_page_count(1, 4);
+
+        let mut mem = vec![0u64; 5 * perf_array.page_size / 8];
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = mem.as_mut_ptr().cast());
+        UPDATES.with(|updates| updates.set(2));
+        override_syscall(busy_then_ok);
+
+        let page_size = perf_array.page_size;
+        for (index, page_count, pages) in [(0, None, 1), (1, None, 4), (2, Some(2), 2)] {
+            let buf = perf_array.open(index, page_count).unwrap();
+            assert_eq!(buf.buf.size(), pages * page_size);
+        }
+
+        // invalid page counts are rejected before any syscall
+        override_syscall(|call| panic!("unexpected syscall {:?}", call));
+        perf_array.set_page_count(3, 3);
+        assert_matches!(
+            perf_array.open(3, None).map(drop),
+            Err(PerfBufferError::InvalidPageCount { page_count: 3 })
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_out_of_bounds() {
+        let mut map = Map::PerfEventArray(new_map());
+        let mut perf_array = PerfEventArray::try_from(&mut map).unwrap();
+
+        // out of bounds indexes are rejected before any syscall
+        override_syscall(|call| panic!("unexpected syscall {:?}", call));
+        assert_matches!(
+            perf_array.open(4, None).map(drop),
+            Err(PerfBufferError::IndexOutOfBounds {
+                index: 4,
+                max_entries: 4
+            })
+        );
+
+        // and so are the updates the kernel rejects
+        let mut mem = vec![0u64; 3 * perf_array.page_size / 8];
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = mem.as_mut_ptr().cast());
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                ..
+            } => Err((-1, io::Error::from_raw_os_error(E2BIG))),
+            Syscall::PerfEventOpen { .. } => Ok(42),
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall {:?}", call),
+        });
+        assert_matches!(
+            perf_array.open(3, None).map(drop),
+            Err(PerfBufferError::IndexOutOfBounds {
+                index: 3,
+                max_entries: 4
+            })
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_retries_busy_update() {
+        let mut map = Map::PerfEventArray(new_map());
+        let mut perf_array = PerfEventArray::try_from(&mut map).unwrap();
+
+        let mut mem = vec![0u64; 2 * perf_array.page_size / 8];
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = mem.as_mut_ptr().cast());
+        override_syscall(busy_then_ok);
+
+        let buf = perf_array.open(0, Some(1)).unwrap();
+        assert_eq!(UPDATES.with(Cell::get), 3);
+        assert_eq!(perf_array.open_cpus(), [0]);
+        drop(buf);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_reassign() {
+        let mut map = Map::PerfEventArray(new_map());
+        let mut perf_array = PerfEventArray::try_from(&mut map).unwrap();
+
+        let mut mem = vec![0u64; 2 * perf_array.page_size / 8];
+        TEST_MMAP_RET.with(|ret| *ret.borrow_mut() = mem.as_mut_ptr().cast());
+        UPDATES.with(|updates| updates.set(2));
+        override_syscall(busy_then_ok);
+        let mut buf = perf_array.open(0, Some(1)).unwrap();
+        let other = perf_array.open(1, Some(1)).unwrap();
+
+        let mut pool = Vec::new();
+        assert_matches!(
+            buf.reassign(4, &mut pool, |_| {}),
+            Err(PerfBufferError::IndexOutOfBounds {
+                index: 4,
+                max_entries: 4
+            })
+        );
+        assert_matches!(
+            buf.reassign(1, &mut pool, |_| {}),
+            Err(PerfBufferError::IndexAlreadyOccupied { index: 1 })
+        );
+        assert_eq!(buf.index(), 0);
+
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM | bpf_cmd::BPF_MAP_DELETE_ELEM,
+                ..
+            } => Ok(0),
+            Syscall::PerfEventIoctl { .. } => Ok(0),
+            call => panic!("unexpected syscall {:?}", call),
+        });
+        assert_eq!(
+            buf.reassign(2, &mut pool, |_| {}).unwrap(),
+            Events::new(0, 0)
+        );
+        assert_eq!(buf.index(), 2);
+        assert_eq!(perf_array.open_cpus(), [1, 2]);
+
+        drop(other);
+        assert_eq!(perf_array.open_cpus(), [2]);
+        drop(buf);
+        assert_eq!(perf_array.open_cpus(), []);
+    }
+
+    #[test]
+    fn test_buffer_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<PerfEventArrayBuffer<MapData>>();
+        assert_send::<PerfEventArrayBuffer<&'static mut MapData>>();
+    }
+
+    #[test]
+    fn test_budget_page_count() {
+        const PAGE_SIZE: usize = 4096;
+        // 256MB over 256 CPUs is 1MB per CPU, which is 255 data pages plus the metadata page
+        assert_eq!(budget_page_count(256 << 20, PAGE_SIZE, 256), 128);
+        assert_eq!(budget_page_count(PAGE_SIZE * 3, PAGE_SIZE, 1), 2);
+        assert_eq!(budget_page_count(PAGE_SIZE * 2, PAGE_SIZE, 1), 1);
+        assert_eq!(budget_page_count(PAGE_SIZE * 2, PAGE_SIZE, 2), 0);
+        assert_eq!(budget_page_count(0, PAGE_SIZE, 4), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_all_within_budget_too_small() {
+        let mut map = Map::PerfEventArray(new_map());
+        let mut perf_array = PerfEventArray::try_from(&mut map).unwrap();
+
+        // budgets that are too small are rejected before any syscall
+        override_syscall(|call| panic!("unexpected syscall {:?}", call));
+        let cpus = online_cpus().unwrap().len();
+        assert_matches!(
+            perf_array.open_all_within_budget(perf_array.page_size).map(drop),
+            Err(PerfBufferError::BudgetTooSmall { total_bytes, cpus: n }) if total_bytes == perf_array.page_size && n == cpus
+        );
+    }
 }
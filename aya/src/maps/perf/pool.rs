@@ -0,0 +1,95 @@
+use std::ops::{Deref, DerefMut};
+
+use bytes::BytesMut;
+
+/// A set of output buffers for
+/// [`PerfEventArrayBuffer::read_events`](crate::maps::perf::PerfEventArrayBuffer::read_events).
+///
+/// Reading events grows the buffers as needed to fit the largest events, and they keep that
+/// capacity afterwards. After a burst of large events, [`trim`](SampleBufferPool::trim) can be
+/// used to release the memory, so that it tracks the recent load instead of staying pinned at
+/// the peak.
+///
+/// The pool dereferences to a slice of buffers, so it can be passed to `read_events()`
+/// directly.
+///
+/// # Examples
+///
+/// ```no_run
+/// # let mut bpf = aya::Bpf::load(&[])?;
+/// use aya::maps::{perf::SampleBufferPool, PerfEventArray};
+///
+/// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+/// let mut buf = perf_array.open(0, None)?;
+/// let mut pool = SampleBufferPool::new(16, 1024);
+///
+/// let events = buf.read_events(&mut pool)?;
+/// if events.read == 0 {
+///     // idle, release the memory grown during the last burst
+///     pool.trim(1024);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct SampleBufferPool {
+    buffers: Vec<BytesMut>,
+}
+
+impl SampleBufferPool {
+    /// Creates a pool of `count` buffers with the given initial capacity.
+    pub fn new(count: usize, capacity: usize) -> Self {
+        Self {
+            buffers: (0..count)
+                .map(|_| BytesMut::with_capacity(capacity))
+                .collect(),
+        }
+    }
+
+    /// Shrinks the buffers that have grown beyond `max_capacity` bytes back to `max_capacity`.
+    ///
+    /// The contents of the trimmed buffers are discarded. The buffers grow again as needed on the
+    /// next reads.
+    pub fn trim(&mut self, max_capacity: usize) {
+        for buf in &mut self.buffers {
+            if buf.capacity() > max_capacity {
+                *buf = BytesMut::with_capacity(max_capacity);
+            }
+        }
+    }
+
+    /// Returns the total capacity of the buffers, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.buffers.iter().map(BytesMut::capacity).sum()
+    }
+}
+
+impl Deref for SampleBufferPool {
+    type Target = [BytesMut];
+
+    fn deref(&self) -> &[BytesMut] {
+        &self.buffers
+    }
+}
+
+impl DerefMut for SampleBufferPool {
+    fn deref_mut(&mut self) -> &mut [BytesMut] {
+        &mut self.buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim() {
+        let mut pool = SampleBufferPool::new(2, 16);
+        assert_eq!(pool.len(), 2);
+        pool[0].reserve(4096);
+        assert!(pool.capacity() >= 4096 + 16);
+
+        pool.trim(16);
+        assert_eq!(pool.capacity(), 32);
+        assert!(pool.iter().all(|buf| buf.capacity() == 16));
+    }
+}
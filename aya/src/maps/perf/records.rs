@@ -0,0 +1,136 @@
+use std::borrow::Cow;
+
+use crate::maps::perf::{
+    perf_buffer::{RecordKind, Ring},
+    PerfBufferError,
+};
+
+/// A record returned by [`Records`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Record<'a> {
+    /// The data of a sample. It's borrowed from the ring unless it wraps around its end.
+    Sample(Cow<'a, [u8]>),
+    /// The number of events lost.
+    Lost(u64),
+}
+
+/// The position up to which records have been read, see [`Records::cursor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordCursor {
+    // the ring the records were read from, see Ring::id()
+    ring: usize,
+    // where the iteration started
+    start: usize,
+    // right after the last record returned
+    tail: usize,
+}
+
+/// An iterator over the records of a perf buffer that doesn't consume them.
+///
+/// Returned by
+/// [`PerfEventArrayBuffer::records`](crate::maps::perf::PerfEventArrayBuffer::records). Since
+/// it only borrows the buffer immutably, read only methods can be called while iterating. The
+/// records stay in the buffer until
+/// [`PerfEventArrayBuffer::commit`](crate::maps::perf::PerfEventArrayBuffer::commit) is called
+/// with the [`cursor`](Records::cursor) of the iterator.
+///
+/// When the ring is corrupted, the iterator returns [`PerfBufferError::InvalidEventSize`] and
+/// stops. Committing its cursor then releases the records that couldn't be read, like the `read_*`
+/// methods do. A sample that can't be decoded stops the iteration too, but is left in the buffer.
+#[derive(Debug)]
+pub struct Records<'a> {
+    ring: &'a Ring,
+    head: usize,
+    start: usize,
+    tail: usize,
+}
+
+impl<'a> Records<'a> {
+    pub(super) fn new(ring: &'a Ring) -> Self {
+        let (head, tail) = ring.head_tail();
+        Self {
+            ring,
+            head,
+            start: tail,
+            tail,
+        }
+    }
+
+    /// Returns the position right after the last record returned by the iterator.
+    pub fn cursor(&self) -> RecordCursor {
+        let Self { start, tail, .. } = *self;
+        RecordCursor {
+            ring: self.ring.id(),
+            start,
+            tail,
+        }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, PerfBufferError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ring = self.ring;
+        while self.tail != self.head {
+            let event_start = self.tail % ring.size();
+            let event = ring.header_at(event_start);
+            if !Ring::valid_event_size(self.head, self.tail, &event) {
+                self.tail = self.head;
+                return Some(Err(PerfBufferError::InvalidEventSize {
+                    offset: event_start,
+                    size: event.size,
+                }));
+            }
+            let next = self.tail.wrapping_add(event.size as usize);
+
+            let record = match ring.record_kind(event_start, &event) {
+                RecordKind::Sample => ring
+                    .raw_data(event_start)
+                    .map(|(start, size)| Record::Sample(ring.data_cow(start, size))),
+                RecordKind::Lost(lost) => Ok(Record::Lost(lost.count)),
+                RecordKind::Throttle(_) | RecordKind::Unthrottle | RecordKind::Other => {
+                    // skip the records that aren't returned
+                    self.tail = next;
+                    continue;
+                }
+            };
+            match record {
+                Ok(_) => self.tail = next,
+                // the sample stays in the ring, and the iteration stops
+                Err(_) => self.head = self.tail,
+            }
+            return Some(record);
+        }
+        None
+    }
+}
+
+impl Ring {
+    pub(super) fn commit(&mut self, cursor: RecordCursor) {
+        let RecordCursor { ring, start, tail } = cursor;
+        let (head, current) = self.head_tail();
+        // ignore cursors of other buffers, and stale cursors
+        if ring == self.id() && tail.wrapping_sub(current) <= head.wrapping_sub(current) {
+            // Rings written backward are iterated newest first, from data_head. Newer records
+            // may have been written before `start` since, and stay unread. The older records
+            // that weren't iterated over can't be kept apart, so they're released too.
+            let (mut pos, new_tail) = if self.backward {
+                (start, start)
+            } else {
+                (current, tail)
+            };
+            while pos != tail {
+                let event_start = pos % self.size();
+                let event = self.header_at(event_start);
+                // the records after an invalid one are released without being accounted
+                if !Self::valid_event_size(tail, pos, &event) {
+                    break;
+                }
+                self.account(event_start, &event);
+                pos = pos.wrapping_add(event.size as usize);
+            }
+            self.set_tail(new_tail);
+        }
+    }
+}
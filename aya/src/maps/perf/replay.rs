@@ -0,0 +1,329 @@
+use std::{
+    io::{self, Read, Write},
+    mem::size_of,
+};
+
+use bytes::BytesMut;
+use object::{Endian as _, Endianness};
+
+use crate::{
+    generated::perf_event_mmap_page,
+    maps::perf::{
+        perf_buffer::Ring, BranchSampleType, Events, PerfBufferError, ReadFormat, Sample,
+        SampleType,
+    },
+};
+
+const DUMP_MAGIC: [u8; 8] = *b"AYAPERF1";
+
+// The largest amount of record data accepted from a dump. Dumps hold the pending records of a
+// single ring, so anything larger is a corrupted length.
+const MAX_DUMP_LEN: usize = 1 << 30;
+
+/// Replays the events of a perf buffer dump.
+///
+/// Dumps are written with
+/// [`PerfEventArrayBuffer::dump_ring`](crate::maps::perf::PerfEventArrayBuffer::dump_ring) and
+/// contain the pending records of a buffer along with the sample format it was opened with.
+/// [`ReplaySource`] decodes them with the same code as live buffers, so analysis code can run
+/// identically on live and recorded data.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// use aya::maps::perf::ReplaySource;
+/// use bytes::BytesMut;
+///
+/// let mut replay = ReplaySource::new(File::open("ring.dump")?)?;
+/// let mut out_bufs = [BytesMut::with_capacity(1024)];
+/// while replay.readable() {
+///     let events = replay.read_events(&mut out_bufs)?;
+///     for buf in events.filled(&mut out_bufs) {
+///         println!("{buf:?}");
+///     }
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct ReplaySource {
+    ring: Ring,
+    _mem: Box<[u64]>,
+}
+
+impl ReplaySource {
+    /// Reads a dump.
+    pub fn new(reader: impl Read) -> io::Result<Self> {
+        Self::with_endianness(reader, Endianness::default())
+    }
+
+    /// Reads a dump captured on a host with the given byte order.
+    ///
+    /// This is meant to replay dumps captured on a machine of a different endianness. The
+    /// multi-byte fields decoded by aya, like record headers, data sizes, call chains, read
+    /// values and branch entries, are byte swapped as needed. The raw data of the samples is
+    /// returned as is, since its layout is only known by the eBPF program that produced it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] if the dump is malformed or truncated, and the error
+    /// of `reader` if reading fails.
+    pub fn with_endianness(mut reader: impl Read, endianness: Endianness) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != DUMP_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a perf buffer dump",
+            ));
+        }
+        let mut read_u64 = || {
+            let mut value = [0u8; size_of::<u64>()];
+            reader
+                .read_exact(&mut value)
+                .map(|()| endianness.read_u64_bytes(value))
+        };
+        let sample_type = SampleType::from_bits_retain(read_u64()?);
+        let read_format = ReadFormat::from_bits_retain(read_u64()?);
+        let branch_sample_type = BranchSampleType::from_bits_retain(read_u64()?);
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let len = usize::try_from(read_u64()?)
+            .ok()
+            .filter(|&len| len <= MAX_DUMP_LEN)
+            .ok_or_else(|| invalid("perf buffer dump too large"))?;
+
+        // read the records before allocating the ring, so that a corrupted length can't make us
+        // allocate more than the dump holds
+        let mut records = Vec::new();
+        reader.take(len as u64).read_to_end(&mut records)?;
+        if records.len() != len {
+            return Err(invalid("truncated perf buffer dump"));
+        }
+
+        // lay the records out like a ring that was never wrapped
+        let page_size = size_of::<perf_event_mmap_page>();
+        let size = len
+            .max(size_of::<u64>())
+            .checked_next_power_of_two()
+            .ok_or_else(|| invalid("perf buffer dump too large"))?;
+        let mut mem = vec![0u64; (page_size + size) / size_of::<u64>()].into_boxed_slice();
+        let base = mem.as_mut_ptr() as *mut u8;
+        // Safety: `mem` is `page_size + size` bytes long and `len <= size`.
+        let data = unsafe { std::slice::from_raw_parts_mut(base.add(page_size), len) };
+        data.copy_from_slice(&records);
+
+        let header = base as *mut perf_event_mmap_page;
+        // Safety: `mem` starts with a zeroed perf_event_mmap_page.
+        unsafe { (*header).data_head = len as u64 };
+        let mut ring = Ring::new(
+            header,
+            size,
+            page_size,
+            sample_type,
+            read_format,
+            branch_sample_type,
+        );
+        ring.endianness = endianness;
+        Ok(Self { ring, _mem: mem })
+    }
+
+    /// Returns true if there are events that haven't been read.
+    pub fn readable(&self) -> bool {
+        self.ring.readable()
+    }
+
+    /// Reads events, see
+    /// [`PerfEventArrayBuffer::read_events`](crate::maps::perf::PerfEventArrayBuffer::read_events).
+    pub fn read_events(&mut self, out_bufs: &mut [BytesMut]) -> Result<Events, PerfBufferError> {
+        self.ring.read_events(out_bufs)
+    }
+
+    /// Reads samples, see
+    /// [`PerfEventArrayBuffer::read_samples`](crate::maps::perf::PerfEventArrayBuffer::read_samples).
+    pub fn read_samples(&mut self, samples: &mut [Sample]) -> Result<Events, PerfBufferError> {
+        self.ring.read_samples(samples)
+    }
+
+    /// Discards all the remaining events, see
+    /// [`PerfEventArrayBuffer::discard_events`](crate::maps::perf::PerfEventArrayBuffer::discard_events).
+    pub fn discard_events(&mut self) -> Result<Events, PerfBufferError> {
+        self.ring.discard_events()
+    }
+}
+
+impl Ring {
+    // Writes the records between data_tail and data_head without consuming them.
+    pub(super) fn dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let data = self.pending_data();
+
+        writer.write_all(&DUMP_MAGIC)?;
+        for value in [
+            self.sample_type.bits(),
+            self.read_format.bits(),
+            self.branch_sample_type.bits(),
+            data.len() as u64,
+        ] {
+            writer.write_all(&value.to_ne_bytes())?;
+        }
+        writer.write_all(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::generated::{perf_event_header, perf_event_type::PERF_RECORD_SAMPLE};
+
+    fn sample(value: u32) -> Vec<u8> {
+        let header = perf_event_header {
+            type_: PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            size: 16,
+        };
+        let mut record = Vec::new();
+        record.extend_from_slice(&header.type_.to_ne_bytes());
+        record.extend_from_slice(&header.misc.to_ne_bytes());
+        record.extend_from_slice(&header.size.to_ne_bytes());
+        record.extend_from_slice(&4u32.to_ne_bytes());
+        record.extend_from_slice(&value.to_ne_bytes());
+        record
+    }
+
+    #[test]
+    fn test_replay() {
+        let records = [sample(0xCAFEBABE), sample(0xBADCAFE)].concat();
+        let mut dump = DUMP_MAGIC.to_vec();
+        for value in [SampleType::RAW.bits(), 0, 0, records.len() as u64] {
+            dump.extend_from_slice(&value.to_ne_bytes());
+        }
+        dump.extend_from_slice(&records);
+
+        let mut replay = ReplaySource::new(dump.as_slice()).unwrap();
+        // dumping doesn't consume the records
+        let mut redump = Vec::new();
+        replay.ring.dump(&mut redump).unwrap();
+        assert_eq!(redump, dump);
+
+        let mut out_bufs = [BytesMut::new(), BytesMut::new()];
+        let events = replay.read_events(&mut out_bufs).unwrap();
+        assert_eq!(events, Events::new(2, 0));
+        assert_eq!(&out_bufs[0][..], 0xCAFEBABEu32.to_ne_bytes());
+        assert_eq!(&out_bufs[1][..], 0xBADCAFEu32.to_ne_bytes());
+        assert!(!replay.readable());
+    }
+
+    #[test]
+    fn test_replay_foreign_endianness() {
+        #[cfg(target_endian = "little")]
+        let foreign = Endianness::Big;
+        #[cfg(target_endian = "big")]
+        let foreign = Endianness::Little;
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&foreign.write_u32_bytes(PERF_RECORD_SAMPLE as u32));
+        record.extend_from_slice(&foreign.write_u16_bytes(0));
+        record.extend_from_slice(&foreign.write_u16_bytes(16));
+        record.extend_from_slice(&foreign.write_u32_bytes(4));
+        record.extend_from_slice(b"data");
+        let mut dump = DUMP_MAGIC.to_vec();
+        for value in [SampleType::RAW.bits(), 0, 0, record.len() as u64] {
+            dump.extend_from_slice(&foreign.write_u64_bytes(value));
+        }
+        dump.extend_from_slice(&record);
+
+        let mut replay = ReplaySource::with_endianness(dump.as_slice(), foreign).unwrap();
+        let mut out_bufs = [BytesMut::new()];
+        assert_eq!(
+            replay.read_events(&mut out_bufs).unwrap(),
+            Events::new(1, 0)
+        );
+        // the raw data isn't swapped
+        assert_eq!(&out_bufs[0][..], b"data");
+    }
+
+    fn dump(sample_type: SampleType, records: &[u8]) -> Vec<u8> {
+        let mut dump = DUMP_MAGIC.to_vec();
+        for value in [sample_type.bits(), 0, 0, records.len() as u64] {
+            dump.extend_from_slice(&value.to_ne_bytes());
+        }
+        dump.extend_from_slice(records);
+        dump
+    }
+
+    #[test]
+    fn test_replay_invalid_lengths() {
+        // the raw data claims to be larger than its record
+        let mut corrupted = sample(0xCAFEBABE);
+        corrupted[8..12].copy_from_slice(&u32::MAX.to_ne_bytes());
+        let records = [corrupted, sample(0xBADCAFE)].concat();
+        let mut replay = ReplaySource::new(dump(SampleType::RAW, &records).as_slice()).unwrap();
+        let mut out_bufs = [BytesMut::new(), BytesMut::new()];
+        assert_matches!(
+            replay.read_events(&mut out_bufs),
+            Err(PerfBufferError::InvalidEventSize {
+                offset: 0,
+                size: 16
+            })
+        );
+        // only the corrupted sample was discarded
+        assert_eq!(
+            replay.read_events(&mut out_bufs).unwrap(),
+            Events::new(1, 0)
+        );
+        assert_eq!(&out_bufs[0][..], 0xBADCAFEu32.to_ne_bytes());
+
+        // the call chain claims more entries than the record holds
+        let mut corrupted = sample(0);
+        corrupted[8..16].copy_from_slice(&u64::MAX.to_ne_bytes());
+        let mut replay =
+            ReplaySource::new(dump(SampleType::CALLCHAIN | SampleType::RAW, &corrupted).as_slice())
+                .unwrap();
+        let mut samples = [Sample::default()];
+        assert_matches!(
+            replay.read_samples(&mut samples),
+            Err(PerfBufferError::InvalidEventSize {
+                offset: 0,
+                size: 16
+            })
+        );
+        assert!(!replay.readable());
+    }
+
+    #[test]
+    fn test_replay_invalid() {
+        assert_eq!(
+            ReplaySource::new(&b"not a dump"[..]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_replay_invalid_len() {
+        let records = sample(0xCAFEBABE);
+        let mut header = DUMP_MAGIC.to_vec();
+        for value in [SampleType::RAW.bits(), 0, 0] {
+            header.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        // a huge length is rejected before anything is allocated
+        let mut dump = header.clone();
+        dump.extend_from_slice(&u64::MAX.to_ne_bytes());
+        dump.extend_from_slice(&records);
+        assert_eq!(
+            ReplaySource::new(dump.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // so is a length larger than the records in the dump
+        let mut dump = header;
+        dump.extend_from_slice(&(records.len() as u64 + 8).to_ne_bytes());
+        dump.extend_from_slice(&records);
+        assert_eq!(
+            ReplaySource::new(dump.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
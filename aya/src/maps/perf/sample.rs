@@ -0,0 +1,474 @@
+use std::mem::size_of;
+
+use bytes::BytesMut;
+
+use crate::{
+    generated::perf_event_sample_format::{
+        PERF_SAMPLE_ADDR, PERF_SAMPLE_BRANCH_STACK, PERF_SAMPLE_CALLCHAIN, PERF_SAMPLE_CPU,
+        PERF_SAMPLE_ID, PERF_SAMPLE_IDENTIFIER, PERF_SAMPLE_IP, PERF_SAMPLE_PERIOD,
+        PERF_SAMPLE_RAW, PERF_SAMPLE_READ, PERF_SAMPLE_STREAM_ID, PERF_SAMPLE_TID,
+        PERF_SAMPLE_TIME,
+    },
+    maps::perf::{
+        perf_buffer::{zero_capacity, Ring},
+        PerfBufferError,
+    },
+};
+
+bitflags::bitflags! {
+    /// The fields included in each sample, see `perf_event_attr.sample_type`.
+    ///
+    /// Used with [`PerfEventConfig::sample_type`](crate::maps::perf::PerfEventConfig::sample_type).
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct SampleType: u64 {
+        /// PERF_SAMPLE_IDENTIFIER
+        const IDENTIFIER = PERF_SAMPLE_IDENTIFIER as u64;
+        /// PERF_SAMPLE_IP
+        const IP = PERF_SAMPLE_IP as u64;
+        /// PERF_SAMPLE_TID
+        const TID = PERF_SAMPLE_TID as u64;
+        /// PERF_SAMPLE_TIME
+        const TIME = PERF_SAMPLE_TIME as u64;
+        /// PERF_SAMPLE_ADDR
+        const ADDR = PERF_SAMPLE_ADDR as u64;
+        /// PERF_SAMPLE_ID
+        const ID = PERF_SAMPLE_ID as u64;
+        /// PERF_SAMPLE_STREAM_ID
+        const STREAM_ID = PERF_SAMPLE_STREAM_ID as u64;
+        /// PERF_SAMPLE_CPU
+        const CPU = PERF_SAMPLE_CPU as u64;
+        /// PERF_SAMPLE_PERIOD
+        const PERIOD = PERF_SAMPLE_PERIOD as u64;
+        /// PERF_SAMPLE_READ
+        ///
+        /// The layout of the values depends on
+        /// [`PerfEventConfig::read_format`](crate::maps::perf::PerfEventConfig::read_format).
+        const READ = PERF_SAMPLE_READ as u64;
+        /// PERF_SAMPLE_CALLCHAIN
+        const CALLCHAIN = PERF_SAMPLE_CALLCHAIN as u64;
+        /// PERF_SAMPLE_RAW
+        const RAW = PERF_SAMPLE_RAW as u64;
+        /// PERF_SAMPLE_BRANCH_STACK
+        ///
+        /// Requires the branches to sample to be set with
+        /// [`PerfEventConfig::branch_sample_type`](crate::maps::perf::PerfEventConfig::branch_sample_type).
+        const BRANCH_STACK = PERF_SAMPLE_BRANCH_STACK as u64;
+    }
+}
+
+bitflags::bitflags! {
+    /// The counter values included when [`SampleType::READ`] is set, see
+    /// `perf_event_attr.read_format`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ReadFormat: u64 {
+        /// PERF_FORMAT_TOTAL_TIME_ENABLED
+        const TOTAL_TIME_ENABLED = 1 << 0;
+        /// PERF_FORMAT_TOTAL_TIME_RUNNING
+        const TOTAL_TIME_RUNNING = 1 << 1;
+        /// PERF_FORMAT_ID
+        const ID = 1 << 2;
+        /// PERF_FORMAT_GROUP
+        const GROUP = 1 << 3;
+        /// PERF_FORMAT_LOST
+        const LOST = 1 << 4;
+    }
+}
+
+bitflags::bitflags! {
+    /// The branches recorded when [`SampleType::BRANCH_STACK`] is set, see
+    /// `perf_event_attr.branch_sample_type`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct BranchSampleType: u64 {
+        /// PERF_SAMPLE_BRANCH_USER
+        const USER = 1 << 0;
+        /// PERF_SAMPLE_BRANCH_KERNEL
+        const KERNEL = 1 << 1;
+        /// PERF_SAMPLE_BRANCH_HV
+        const HV = 1 << 2;
+        /// PERF_SAMPLE_BRANCH_ANY
+        const ANY = 1 << 3;
+        /// PERF_SAMPLE_BRANCH_ANY_CALL
+        const ANY_CALL = 1 << 4;
+        /// PERF_SAMPLE_BRANCH_ANY_RETURN
+        const ANY_RETURN = 1 << 5;
+        /// PERF_SAMPLE_BRANCH_IND_CALL
+        const IND_CALL = 1 << 6;
+        /// PERF_SAMPLE_BRANCH_ABORT_TX
+        const ABORT_TX = 1 << 7;
+        /// PERF_SAMPLE_BRANCH_IN_TX
+        const IN_TX = 1 << 8;
+        /// PERF_SAMPLE_BRANCH_NO_TX
+        const NO_TX = 1 << 9;
+        /// PERF_SAMPLE_BRANCH_COND
+        const COND = 1 << 10;
+        /// PERF_SAMPLE_BRANCH_CALL_STACK
+        const CALL_STACK = 1 << 11;
+        /// PERF_SAMPLE_BRANCH_IND_JUMP
+        const IND_JUMP = 1 << 12;
+        /// PERF_SAMPLE_BRANCH_CALL
+        const CALL = 1 << 13;
+        /// PERF_SAMPLE_BRANCH_NO_FLAGS
+        const NO_FLAGS = 1 << 14;
+        /// PERF_SAMPLE_BRANCH_NO_CYCLES
+        const NO_CYCLES = 1 << 15;
+        /// PERF_SAMPLE_BRANCH_TYPE_SAVE
+        const TYPE_SAVE = 1 << 16;
+        /// PERF_SAMPLE_BRANCH_HW_INDEX
+        ///
+        /// Each branch stack is then preceded by the index of the most recent branch.
+        const HW_INDEX = 1 << 17;
+    }
+}
+
+impl Default for SampleType {
+    fn default() -> Self {
+        Self::RAW
+    }
+}
+
+// The fields that precede the read values in a sample. They're all 8 bytes wide.
+const FIXED_FIELDS: SampleType = SampleType::IDENTIFIER
+    .union(SampleType::IP)
+    .union(SampleType::TID)
+    .union(SampleType::TIME)
+    .union(SampleType::ADDR)
+    .union(SampleType::ID)
+    .union(SampleType::STREAM_ID)
+    .union(SampleType::CPU)
+    .union(SampleType::PERIOD);
+
+// The fixed size fields, in the order they appear in a sample.
+const FIXED_FIELDS_ORDER: [SampleType; 9] = [
+    SampleType::IDENTIFIER,
+    SampleType::IP,
+    SampleType::TID,
+    SampleType::TIME,
+    SampleType::ADDR,
+    SampleType::ID,
+    SampleType::STREAM_ID,
+    SampleType::CPU,
+    SampleType::PERIOD,
+];
+
+/// A branch record, see `struct perf_branch_entry`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BranchEntry {
+    /// The source address of the branch.
+    pub from: u64,
+    /// The destination address of the branch.
+    pub to: u64,
+    /// The raw flags bitfield.
+    pub flags: u64,
+}
+
+impl BranchEntry {
+    /// Returns whether the branch was mispredicted.
+    pub fn mispredicted(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// Returns whether the branch was predicted correctly.
+    pub fn predicted(&self) -> bool {
+        self.flags & (1 << 1) != 0
+    }
+
+    /// Returns the number of cycles since the previous branch, or 0 if not supported.
+    pub fn cycles(&self) -> u16 {
+        (self.flags >> 4) as u16
+    }
+}
+
+/// The counter values of a sample, when [`SampleType::READ`] is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadValues {
+    /// The time the event was enabled, when [`ReadFormat::TOTAL_TIME_ENABLED`] is set.
+    pub time_enabled: u64,
+    /// The time the event was running, when [`ReadFormat::TOTAL_TIME_RUNNING`] is set.
+    ///
+    /// When the PMU is multiplexed this is less than `time_enabled`, and counter values can be
+    /// scaled by `time_enabled / time_running` to estimate their actual value.
+    pub time_running: u64,
+    /// The `(id, value)` pair of each counter. There's a single value unless
+    /// [`ReadFormat::GROUP`] is set, and ids are 0 unless [`ReadFormat::ID`] is set.
+    pub values: Vec<(u64, u64)>,
+}
+
+/// A sample read with
+/// [`PerfEventArrayBuffer::read_samples`](crate::maps::perf::PerfEventArrayBuffer::read_samples).
+///
+/// Which fields are filled in depends on the [`SampleType`] the buffer was opened with. Samples
+/// are meant to be reused across reads so that their allocations can be recycled.
+#[derive(Debug, Default)]
+pub struct Sample {
+    /// The id of the process, when [`SampleType::TID`] is set.
+    pub pid: u32,
+    /// The id of the thread, when [`SampleType::TID`] is set.
+    pub tid: u32,
+    /// The timestamp, when [`SampleType::TIME`] is set.
+    ///
+    /// To order the samples of the buffers of several CPUs by timestamp, select a clock that's
+    /// consistent across CPUs, like `CLOCK_MONOTONIC`, with
+    /// [`PerfEventConfig::clockid`](crate::maps::perf::PerfEventConfig::clockid).
+    pub time: u64,
+    /// The CPU the sample was taken on, when [`SampleType::CPU`] is set.
+    pub cpu: u32,
+    /// The counter values, when [`SampleType::READ`] is set.
+    pub read: ReadValues,
+    /// The instruction pointers of the call chain, when [`SampleType::CALLCHAIN`] is set.
+    pub callchain: Vec<u64>,
+    /// The raw data, when [`SampleType::RAW`] is set.
+    pub data: BytesMut,
+    /// The branch records, most recent first, when [`SampleType::BRANCH_STACK`] is set.
+    pub branch_stack: Vec<BranchEntry>,
+}
+
+// Reads the fields of a PERF_RECORD_SAMPLE record sequentially.
+//
+// The lengths read from the record aren't trusted, since they come from replayed dumps too: reading
+// past the end of the record fails with PerfBufferError::InvalidEventSize.
+struct SampleReader<'a> {
+    buf: &'a Ring,
+    event_start: usize,
+    event_size: u16,
+    offset: usize,
+}
+
+impl SampleReader<'_> {
+    // Advances past `len` bytes, returning the offset they start at.
+    fn advance(&mut self, len: usize) -> Result<usize, PerfBufferError> {
+        let offset = self.offset;
+        let end = self.event_start + self.event_size as usize;
+        match offset.checked_add(len) {
+            Some(next) if next <= end => {
+                self.offset = next;
+                Ok(offset)
+            }
+            _ => Err(PerfBufferError::InvalidEventSize {
+                offset: self.event_start,
+                size: self.event_size,
+            }),
+        }
+    }
+
+    // Advances past `nr` items of `size` bytes.
+    fn advance_n(&mut self, nr: u64, size: usize) -> Result<usize, PerfBufferError> {
+        let len = usize::try_from(nr)
+            .ok()
+            .and_then(|nr| nr.checked_mul(size))
+            .unwrap_or(usize::MAX);
+        self.advance(len)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), PerfBufferError> {
+        self.advance(len).map(|_| ())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, PerfBufferError> {
+        let offset = self.advance(size_of::<u32>())?;
+        Ok(self.buf.read_u32(offset))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, PerfBufferError> {
+        let offset = self.advance(size_of::<u64>())?;
+        Ok(self.buf.read_u64(offset))
+    }
+
+    // Reads a u64 if the field is present, returns 0 otherwise.
+    fn read_u64_if(&mut self, present: bool) -> Result<u64, PerfBufferError> {
+        if present {
+            self.read_u64()
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn read_u64s(&mut self, out: &mut Vec<u64>) -> Result<(), PerfBufferError> {
+        let nr = self.read_u64()?;
+        let start = self.advance_n(nr, size_of::<u64>())?;
+        out.clear();
+        out.extend((0..nr as usize).map(|i| self.buf.read_u64(start + i * size_of::<u64>())));
+        Ok(())
+    }
+
+    fn skip_u64s(&mut self) -> Result<(), PerfBufferError> {
+        let nr = self.read_u64()?;
+        self.advance_n(nr, size_of::<u64>()).map(|_| ())
+    }
+
+    fn read_values(
+        &mut self,
+        format: ReadFormat,
+        out: &mut ReadValues,
+    ) -> Result<(), PerfBufferError> {
+        out.values.clear();
+        if format.contains(ReadFormat::GROUP) {
+            let nr = self.read_u64()?;
+            out.time_enabled = self.read_u64_if(format.contains(ReadFormat::TOTAL_TIME_ENABLED))?;
+            out.time_running = self.read_u64_if(format.contains(ReadFormat::TOTAL_TIME_RUNNING))?;
+            // every value takes at least 8 bytes, so this stops at the end of the record
+            for _ in 0..nr {
+                let value = self.read_value(format)?;
+                out.values.push(value);
+            }
+        } else {
+            let value = self.read_u64()?;
+            out.time_enabled = self.read_u64_if(format.contains(ReadFormat::TOTAL_TIME_ENABLED))?;
+            out.time_running = self.read_u64_if(format.contains(ReadFormat::TOTAL_TIME_RUNNING))?;
+            let id = self.read_id_and_lost(format)?;
+            out.values.push((id, value));
+        }
+        Ok(())
+    }
+
+    // Reads a `{ value; id; lost; }` group entry.
+    fn read_value(&mut self, format: ReadFormat) -> Result<(u64, u64), PerfBufferError> {
+        let value = self.read_u64()?;
+        Ok((self.read_id_and_lost(format)?, value))
+    }
+
+    fn read_id_and_lost(&mut self, format: ReadFormat) -> Result<u64, PerfBufferError> {
+        let id = self.read_u64_if(format.contains(ReadFormat::ID))?;
+        if format.contains(ReadFormat::LOST) {
+            self.skip(size_of::<u64>())?;
+        }
+        Ok(id)
+    }
+
+    fn skip_read_values(&mut self, format: ReadFormat) -> Result<(), PerfBufferError> {
+        let times = (format & (ReadFormat::TOTAL_TIME_ENABLED | ReadFormat::TOTAL_TIME_RUNNING))
+            .bits()
+            .count_ones() as usize;
+        let per_value = (format & (ReadFormat::ID | ReadFormat::LOST))
+            .bits()
+            .count_ones() as usize
+            + 1;
+        let nr = if format.contains(ReadFormat::GROUP) {
+            self.read_u64()?
+        } else {
+            1
+        };
+        self.skip(times * size_of::<u64>())?;
+        self.advance_n(nr, per_value * size_of::<u64>()).map(|_| ())
+    }
+
+    fn read_bytes(&mut self, len: usize, out: &mut BytesMut) -> Result<(), PerfBufferError> {
+        let offset = self.advance(len)?;
+        out.clear();
+        out.reserve(len);
+        unsafe { out.set_len(len) };
+        self.buf.fill_buf(offset, out);
+        Ok(())
+    }
+}
+
+impl Ring {
+    // Returns the offset and size of the raw data of the sample starting at `event_start`.
+    pub(super) fn raw_data(&self, event_start: usize) -> Result<(usize, usize), PerfBufferError> {
+        let sample_type = self.sample_type;
+        if !sample_type.contains(SampleType::RAW) {
+            return Err(PerfBufferError::NoRawData);
+        }
+        let mut reader = self.sample_reader(event_start)?;
+        if sample_type.contains(SampleType::READ) {
+            reader.skip_read_values(self.read_format)?;
+        }
+        if sample_type.contains(SampleType::CALLCHAIN) {
+            reader.skip_u64s()?;
+        }
+        let size = reader.read_u32()? as usize;
+        Ok((reader.advance(size)?, size))
+    }
+
+    pub(super) fn read_sample(
+        &self,
+        event_start: usize,
+        sample: &mut Sample,
+    ) -> Result<(), PerfBufferError> {
+        let sample_type = self.sample_type;
+        // checks that the fixed size fields fit in the record
+        let mut reader = self.sample_reader(event_start)?;
+        (sample.pid, sample.tid) = match self.fixed_field(event_start, SampleType::TID) {
+            Some(offset) => (
+                self.read_u32(offset),
+                self.read_u32(offset + size_of::<u32>()),
+            ),
+            None => (0, 0),
+        };
+        sample.time = self
+            .fixed_field(event_start, SampleType::TIME)
+            .map(|offset| self.read_u64(offset))
+            .unwrap_or(0);
+        sample.cpu = self
+            .fixed_field(event_start, SampleType::CPU)
+            .map(|offset| self.read_u32(offset))
+            .unwrap_or(0);
+        if sample_type.contains(SampleType::READ) {
+            reader.read_values(self.read_format, &mut sample.read)?;
+        } else {
+            sample.read.time_enabled = 0;
+            sample.read.time_running = 0;
+            sample.read.values.clear();
+        }
+        if sample_type.contains(SampleType::CALLCHAIN) {
+            reader.read_u64s(&mut sample.callchain)?;
+        } else {
+            sample.callchain.clear();
+        }
+        if sample_type.contains(SampleType::RAW) {
+            let size = reader.read_u32()? as usize;
+            if self.zero_on_reuse {
+                zero_capacity(&mut sample.data);
+            }
+            reader.read_bytes(size, &mut sample.data)?;
+        } else {
+            sample.data.clear();
+        }
+        sample.branch_stack.clear();
+        if sample_type.contains(SampleType::BRANCH_STACK) {
+            let nr = reader.read_u64()?;
+            if self.branch_sample_type.contains(BranchSampleType::HW_INDEX) {
+                reader.skip(size_of::<u64>())?;
+            }
+            // from, to and flags
+            let entry_size = 3 * size_of::<u64>();
+            let start = reader.advance_n(nr, entry_size)?;
+            sample.branch_stack.extend((0..nr as usize).map(|i| {
+                let entry = start + i * entry_size;
+                BranchEntry {
+                    from: self.read_u64(entry),
+                    to: self.read_u64(entry + size_of::<u64>()),
+                    flags: self.read_u64(entry + 2 * size_of::<u64>()),
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    // Returns the offset of the fixed size `field` of the sample starting at `event_start`, if
+    // the sample has it.
+    fn fixed_field(&self, event_start: usize, field: SampleType) -> Option<usize> {
+        let sample_type = self.sample_type;
+        if !sample_type.contains(field) {
+            return None;
+        }
+        let before = FIXED_FIELDS_ORDER
+            .iter()
+            .take_while(|&&f| f != field)
+            .filter(|&&f| sample_type.contains(f))
+            .count();
+        Some(event_start + super::perf_buffer::HEADER_SIZE + before * size_of::<u64>())
+    }
+
+    // Returns a reader positioned right after the fixed size fields of the sample.
+    fn sample_reader(&self, event_start: usize) -> Result<SampleReader<'_>, PerfBufferError> {
+        let fixed = (self.sample_type & FIXED_FIELDS).bits().count_ones() as usize;
+        let mut reader = SampleReader {
+            buf: self,
+            event_start,
+            event_size: self.header_at(event_start).size,
+            offset: event_start,
+        };
+        reader.skip(super::perf_buffer::HEADER_SIZE + fixed * size_of::<u64>())?;
+        Ok(reader)
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    ffi::c_int,
+    io,
+    mem::size_of,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd as _, OwnedFd, RawFd},
+    sync::{Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use libc::{pollfd, EFD_CLOEXEC, EFD_NONBLOCK, POLLERR, POLLHUP, POLLIN, POLLNVAL};
+
+/// Coalesces the wakeups of many perf buffers into a single file descriptor.
+///
+/// When many CPUs produce events at the same time, polling every
+/// [`PerfEventArrayBuffer`](crate::maps::perf::PerfEventArrayBuffer) individually wakes the
+/// consumer once per buffer. [`CoalescedWakeup`] instead watches all the buffers from a helper
+/// thread and signals a single file descriptor once at least `min_ready` buffers have pending
+/// events, or once `max_delay` has elapsed since the first buffer became readable.
+///
+/// After being woken up, the consumer is expected to drain all the buffers and then call
+/// [`CoalescedWakeup::rearm`]. No further wakeups are delivered until then. Buffers whose event
+/// was closed or failed count as readable.
+///
+/// # Examples
+///
+/// ```no_run
+/// # #[derive(thiserror::Error, Debug)]
+/// # enum Error {
+/// #    #[error(transparent)]
+/// #    IO(#[from] std::io::Error),
+/// #    #[error(transparent)]
+/// #    Map(#[from] aya::maps::MapError),
+/// #    #[error(transparent)]
+/// #    Bpf(#[from] aya::BpfError),
+/// #    #[error(transparent)]
+/// #    PerfBuf(#[from] aya::maps::perf::PerfBufferError),
+/// # }
+/// # fn wait_readable<T: std::os::fd::AsFd>(_fd: &T) {}
+/// # let mut bpf = aya::Bpf::load(&[])?;
+/// use std::time::Duration;
+///
+/// use aya::maps::{perf::CoalescedWakeup, PerfEventArray};
+/// use aya::util::online_cpus;
+/// use bytes::BytesMut;
+///
+/// let mut perf_array = PerfEventArray::try_from(bpf.map_mut("EVENTS").unwrap())?;
+/// let mut perf_buffers = online_cpus()?
+///     .into_iter()
+///     .map(|cpu_id| perf_array.open(cpu_id, None))
+///     .collect::<Result<Vec<_>, _>>()?;
+///
+/// // wake up when a quarter of the buffers are readable, or at most 10ms after the first one
+/// let min_ready = perf_buffers.len() / 4;
+/// let wakeup = CoalescedWakeup::new(&perf_buffers, min_ready, Duration::from_millis(10))?;
+///
+/// let mut out_bufs = [BytesMut::with_capacity(1024)];
+/// loop {
+///     wait_readable(&wakeup);
+///     for buf in perf_buffers.iter_mut() {
+///         while buf.readable() {
+///             buf.read_events(&mut out_bufs)?;
+///         }
+///     }
+///     wakeup.rearm()?;
+/// }
+/// # Ok::<(), Error>(())
+/// ```
+pub struct CoalescedWakeup {
+    ready: OwnedFd,
+    rearm: OwnedFd,
+    stop: OwnedFd,
+    // the error that stopped the helper thread, reported by rearm()
+    error: Arc<Mutex<Option<io::Error>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CoalescedWakeup {
+    /// Starts watching the given buffers.
+    ///
+    /// The buffer file descriptors are duplicated, so the buffers can keep being used by the
+    /// caller. A `min_ready` of zero is treated as one.
+    pub fn new<'a, I, F>(buffers: I, min_ready: usize, max_delay: Duration) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = &'a F>,
+        F: AsFd + 'a,
+    {
+        let buffers = buffers
+            .into_iter()
+            .map(|buf| buf.as_fd().try_clone_to_owned())
+            .collect::<Result<Vec<_>, _>>()?;
+        let ready = eventfd()?;
+        let rearm = eventfd()?;
+        let stop = eventfd()?;
+        let error = Arc::new(Mutex::new(None));
+
+        let watcher = Watcher {
+            buffers,
+            ready: ready.try_clone()?,
+            rearm: rearm.try_clone()?,
+            stop: stop.try_clone()?,
+            min_ready: min_ready.max(1),
+            max_delay,
+            error: error.clone(),
+        };
+        let thread = thread::Builder::new()
+            .name("perf-wakeup".into())
+            .spawn(move || watcher.run())?;
+
+        Ok(Self {
+            ready,
+            rearm,
+            stop,
+            error,
+            thread: Some(thread),
+        })
+    }
+
+    /// Re-enables wakeups after the buffers have been drained.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error that stopped the helper thread watching the buffers. The file
+    /// descriptor is signaled when that happens, and no wakeups are delivered afterwards, so
+    /// later calls keep failing.
+    pub fn rearm(&self) -> io::Result<()> {
+        if let Some(err) = self
+            .error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            return Err(err);
+        }
+        if self.thread.as_ref().map_or(true, JoinHandle::is_finished) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the wakeup thread has stopped",
+            ));
+        }
+        eventfd_clear(self.ready.as_fd())?;
+        eventfd_signal(self.rearm.as_fd())
+    }
+}
+
+impl AsFd for CoalescedWakeup {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ready.as_fd()
+    }
+}
+
+impl AsRawFd for CoalescedWakeup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+}
+
+impl Drop for CoalescedWakeup {
+    fn drop(&mut self) {
+        let _: io::Result<()> = eventfd_signal(self.stop.as_fd());
+        if let Some(thread) = self.thread.take() {
+            let _: thread::Result<()> = thread.join();
+        }
+    }
+}
+
+/// Returns which of the given buffers have pending events, using a single `poll()` call.
+///
+/// This is cheaper than calling
+/// [`PerfEventArrayBuffer::readable`](crate::maps::perf::PerfEventArrayBuffer::readable) on many
+/// buffers when only a snapshot of their state is needed, for example to report how many CPUs
+/// have pending data. The returned vector has one entry per buffer, in order.
+pub fn poll_readable<'a, I, F>(buffers: I) -> io::Result<Vec<bool>>
+where
+    I: IntoIterator<Item = &'a F>,
+    F: AsFd + 'a,
+{
+    let mut fds = buffers
+        .into_iter()
+        .map(|buf| pollfd {
+            fd: buf.as_fd().as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    poll(&mut fds, 0)?;
+    Ok(fds.iter().map(|fd| fd.revents & POLLIN != 0).collect())
+}
+
+struct Watcher {
+    buffers: Vec<OwnedFd>,
+    ready: OwnedFd,
+    rearm: OwnedFd,
+    stop: OwnedFd,
+    min_ready: usize,
+    max_delay: Duration,
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl Watcher {
+    fn run(self) {
+        if let Err(err) = self.watch() {
+            *self.error.lock().unwrap_or_else(PoisonError::into_inner) = Some(err);
+            // wake the consumer up, so that it gets the error from rearm()
+            let _: io::Result<()> = eventfd_signal(self.ready.as_fd());
+        }
+    }
+
+    fn watch(&self) -> io::Result<()> {
+        let mut fds = self
+            .buffers
+            .iter()
+            .chain([&self.stop])
+            .map(|fd| pollfd {
+                fd: fd.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+
+        let mut first_ready: Option<Instant> = None;
+        loop {
+            let timeout = first_ready.map_or(-1, |since| {
+                timeout_ms(self.max_delay.saturating_sub(since.elapsed()))
+            });
+            poll(&mut fds, timeout)?;
+            let (stop, buffers) = fds.split_last_mut().unwrap();
+            if stop.revents != 0 {
+                return Ok(());
+            }
+
+            // stop polling the buffers that are already readable so we don't spin on them while
+            // waiting for the others. The events that were closed or failed are reported whatever
+            // `events` is, so their fds are left out entirely.
+            for fd in buffers.iter_mut() {
+                if fd.revents & (POLLIN | POLLHUP | POLLERR | POLLNVAL) != 0 {
+                    fd.fd = -1;
+                }
+            }
+            let n_ready = buffers.iter().filter(|fd| fd.fd < 0).count();
+            if n_ready == 0 {
+                continue;
+            }
+
+            let since = *first_ready.get_or_insert_with(Instant::now);
+            if n_ready < self.min_ready && since.elapsed() < self.max_delay {
+                continue;
+            }
+
+            first_ready = None;
+            eventfd_signal(self.ready.as_fd())?;
+            if self.wait_rearm()? {
+                return Ok(());
+            }
+            for (fd, buf) in buffers.iter_mut().zip(&self.buffers) {
+                fd.fd = buf.as_raw_fd();
+            }
+        }
+    }
+
+    // Waits for the consumer to rearm or for the stop signal. Returns true if stopped.
+    fn wait_rearm(&self) -> io::Result<bool> {
+        let mut fds = [&self.rearm, &self.stop].map(|fd| pollfd {
+            fd: fd.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        });
+        poll(&mut fds, -1)?;
+        let [_, stop] = fds;
+        if stop.revents != 0 {
+            return Ok(true);
+        }
+        eventfd_clear(self.rearm.as_fd())?;
+        Ok(false)
+    }
+}
+
+fn timeout_ms(timeout: Duration) -> c_int {
+    c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX)
+}
+
+pub(super) fn poll(fds: &mut [pollfd], timeout: c_int) -> io::Result<usize> {
+    loop {
+        // Safety: libc
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+// Waits until `fd` is readable or `timeout` elapses, forever if `timeout` is None. Returns true if
+// the fd is readable. Unlike poll(), the remaining time is recomputed when interrupted, so the
+// wait never exceeds `timeout`.
+pub(super) fn wait_readable(fd: BorrowedFd<'_>, timeout: Option<Duration>) -> io::Result<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut fds = [pollfd {
+        fd: fd.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    }];
+    loop {
+        let timeout = deadline.map_or(-1, |deadline| {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // round up, so that sub-millisecond waits don't spin
+            timeout_ms(remaining + Duration::from_nanos(999_999))
+        });
+        // Safety: libc
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret >= 0 {
+            return Ok(ret > 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+pub(super) fn eventfd() -> io::Result<OwnedFd> {
+    // Safety: libc
+    let fd = unsafe { libc::eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: eventfd returns a new file descriptor on success.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub(super) fn eventfd_signal(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let value = 1u64.to_ne_bytes();
+    // Safety: libc
+    let ret = unsafe { libc::write(fd.as_raw_fd(), value.as_ptr().cast(), value.len()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn eventfd_clear(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let mut value = [0u8; size_of::<u64>()];
+    // Safety: libc
+    let ret = unsafe { libc::read(fd.as_raw_fd(), value.as_mut_ptr().cast(), value.len()) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readable(fd: BorrowedFd<'_>, timeout: Duration) -> bool {
+        let mut fds = [pollfd {
+            fd: fd.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        }];
+        poll(&mut fds, timeout_ms(timeout)).unwrap() == 1
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_coalesced_wakeup() {
+        let buffers = [eventfd().unwrap(), eventfd().unwrap()];
+        let wakeup = CoalescedWakeup::new(&buffers, 2, Duration::from_secs(60)).unwrap();
+        assert!(!readable(wakeup.as_fd(), Duration::from_millis(50)));
+
+        // one ready buffer isn't enough
+        eventfd_signal(buffers[0].as_fd()).unwrap();
+        assert!(!readable(wakeup.as_fd(), Duration::from_millis(50)));
+
+        eventfd_signal(buffers[1].as_fd()).unwrap();
+        assert!(readable(wakeup.as_fd(), Duration::from_secs(5)));
+
+        // drain the buffers and rearm
+        for buf in &buffers {
+            eventfd_clear(buf.as_fd()).unwrap();
+        }
+        wakeup.rearm().unwrap();
+        assert!(!readable(wakeup.as_fd(), Duration::from_millis(50)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_coalesced_wakeup_hangup() {
+        let mut fds = [0; 2];
+        // Safety: libc
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safety: pipe returns two new file descriptors on success.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let wakeup = CoalescedWakeup::new([&read], 1, Duration::from_secs(60)).unwrap();
+        assert!(!readable(wakeup.as_fd(), Duration::from_millis(50)));
+
+        // the read end hangs up without ever becoming readable
+        drop(write);
+        assert!(readable(wakeup.as_fd(), Duration::from_secs(5)));
+        wakeup.rearm().unwrap();
+        assert!(readable(wakeup.as_fd(), Duration::from_secs(5)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_wait_readable() {
+        let fd = eventfd().unwrap();
+        let start = Instant::now();
+        assert!(!wait_readable(fd.as_fd(), Some(Duration::from_millis(20))).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        eventfd_signal(fd.as_fd()).unwrap();
+        assert!(wait_readable(fd.as_fd(), Some(Duration::ZERO)).unwrap());
+        assert!(wait_readable(fd.as_fd(), None).unwrap());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_poll_readable() {
+        let buffers = [eventfd().unwrap(), eventfd().unwrap(), eventfd().unwrap()];
+        eventfd_signal(buffers[1].as_fd()).unwrap();
+        assert_eq!(poll_readable(&buffers).unwrap(), [false, true, false]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_coalesced_wakeup_max_delay() {
+        let buffers = [eventfd().unwrap(), eventfd().unwrap()];
+        let wakeup = CoalescedWakeup::new(&buffers, 2, Duration::from_millis(10)).unwrap();
+
+        eventfd_signal(buffers[0].as_fd()).unwrap();
+        assert!(readable(wakeup.as_fd(), Duration::from_secs(5)));
+    }
+}
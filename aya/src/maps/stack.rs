@@ -61,15 +61,27 @@ impl<T: BorrowMut<MapData>, V: Pod> Stack<T, V> {
     /// Returns [`MapError::ElementNotFound`] if the stack is empty, [`MapError::SyscallError`]
     /// if `bpf_map_lookup_and_delete_elem` fails.
     pub fn pop(&mut self, flags: u64) -> Result<V, MapError> {
+        self.try_pop(flags)?.ok_or(MapError::ElementNotFound)
+    }
+
+    /// Removes the last element and returns it, or `None` if the stack is empty.
+    ///
+    /// This works like [`pop`](Self::pop), for callers that drain the stack until it's empty
+    /// and don't treat its end as an error.
+    ///
+    /// # Errors
+    ///
+    /// [`MapError::SyscallError`] if `bpf_map_lookup_and_delete_elem` fails.
+    pub fn try_pop(&mut self, flags: u64) -> Result<Option<V>, MapError> {
         let fd = self.inner.borrow().fd().as_fd();
 
-        let value = bpf_map_lookup_and_delete_elem::<u32, _>(fd, None, flags).map_err(
-            |(_, io_error)| SyscallError {
+        bpf_map_lookup_and_delete_elem::<u32, _>(fd, None, flags).map_err(|(_, io_error)| {
+            SyscallError {
                 call: "bpf_map_lookup_and_delete_elem",
                 io_error,
-            },
-        )?;
-        value.ok_or(MapError::ElementNotFound)
+            }
+            .into()
+        })
     }
 
     /// Pushes an element on the stack.
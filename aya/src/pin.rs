@@ -23,6 +23,12 @@ pub enum PinError {
         /// The source error.
         error: std::ffi::NulError,
     },
+    /// The pin path is already in use.
+    #[error("pin path `{}` already exists", path.display())]
+    AlreadyExists {
+        /// The path.
+        path: std::path::PathBuf,
+    },
     /// An error ocurred making a syscall.
     #[error(transparent)]
     SyscallError(#[from] SyscallError),
@@ -215,7 +215,7 @@ pub enum ProgramError {
 
 /// A [`Program`] file descriptor.
 #[derive(Debug)]
-pub struct ProgramFd(OwnedFd);
+pub struct ProgramFd(pub(crate) OwnedFd);
 
 impl ProgramFd {
     /// Creates a new instance that shares the same underlying file description as [`self`].
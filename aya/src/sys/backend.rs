@@ -0,0 +1,217 @@
+use std::{
+    ffi::{c_int, c_long, c_ulong},
+    io,
+    os::fd::BorrowedFd,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use libc::pid_t;
+
+use super::{native_syscall, SysResult, Syscall};
+use crate::generated::{bpf_attr, bpf_cmd, perf_event_attr};
+
+static SYSCALL_BACKEND: RwLock<Option<Arc<dyn SyscallBackend>>> = RwLock::new(None);
+
+/// A backend performing the privileged syscalls made by aya.
+///
+/// Processes running under a seccomp policy that blocks `bpf()` or `perf_event_open()` can
+/// install a backend with [`set_syscall_backend`] to route these syscalls through a privileged
+/// broker process. Every method defaults to making the syscall directly, so backends only need
+/// to implement the calls they proxy.
+///
+/// The file descriptors returned by a backend, for example by
+/// [`perf_event_open`](Self::perf_event_open), must be valid in the calling process, which
+/// usually means that the broker passes them back over a unix socket with `SCM_RIGHTS`. aya
+/// takes ownership of them.
+pub trait SyscallBackend: Send + Sync {
+    /// Calls `bpf(cmd, attr, size_of::<bpf_attr>())` and returns its result.
+    ///
+    /// # Safety
+    ///
+    /// Depending on `cmd`, `attr` contains pointers to memory of the calling process that the
+    /// kernel reads or writes. A backend proxying the call must copy that memory to and from the
+    /// broker according to the semantics of `cmd`.
+    unsafe fn bpf(&self, cmd: bpf_cmd, attr: &mut bpf_attr) -> io::Result<c_long> {
+        native_syscall(Syscall::Bpf { cmd, attr }).map_err(|(_, io_error)| io_error)
+    }
+
+    /// Calls `perf_event_open(attr, pid, cpu, group_fd, flags)` and returns the new file
+    /// descriptor.
+    fn perf_event_open(
+        &self,
+        attr: &perf_event_attr,
+        pid: pid_t,
+        cpu: c_int,
+        group_fd: c_int,
+        flags: u32,
+    ) -> io::Result<c_long> {
+        native_syscall(Syscall::PerfEventOpen {
+            attr: *attr,
+            pid,
+            cpu,
+            group: group_fd,
+            flags,
+        })
+        .map_err(|(_, io_error)| io_error)
+    }
+
+    /// Calls `ioctl(fd, request, arg)` on a perf event file descriptor and returns its result.
+    ///
+    /// # Safety
+    ///
+    /// For some requests, like `PERF_EVENT_IOC_ID`, `arg` is a pointer to memory of the calling
+    /// process that the kernel writes to.
+    unsafe fn perf_event_ioctl(
+        &self,
+        fd: BorrowedFd<'_>,
+        request: c_int,
+        arg: c_ulong,
+    ) -> io::Result<c_long> {
+        native_syscall(Syscall::PerfEventIoctlPtr {
+            fd,
+            request,
+            arg: arg as *mut _,
+        })
+        .map_err(|(_, io_error)| io_error)
+    }
+}
+
+/// Installs the backend used to make the privileged syscalls, see [`SyscallBackend`].
+///
+/// The backend replaces the previously installed one, if any, and is used by all the threads of
+/// the process. It should be installed before loading any program.
+pub fn set_syscall_backend(backend: impl SyscallBackend + 'static) {
+    *SYSCALL_BACKEND
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(backend));
+}
+
+// Makes `call` with the installed backend, or directly if there's none. The lock isn't held
+// during the call, so that a slow broker doesn't block set_syscall_backend().
+pub(super) fn backend_syscall(call: Syscall<'_>) -> SysResult<c_long> {
+    let backend = SYSCALL_BACKEND
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match backend.as_deref() {
+        Some(backend) => dispatch(backend, call),
+        None => native_syscall(call),
+    }
+}
+
+fn dispatch(backend: &dyn SyscallBackend, call: Syscall<'_>) -> SysResult<c_long> {
+    unsafe {
+        match call {
+            Syscall::Bpf { cmd, attr } => backend.bpf(cmd, attr),
+            Syscall::PerfEventOpen {
+                attr,
+                pid,
+                cpu,
+                group,
+                flags,
+            } => backend.perf_event_open(&attr, pid, cpu, group, flags),
+            Syscall::PerfEventIoctl { fd, request, arg } => {
+                backend.perf_event_ioctl(fd, request, arg as c_ulong)
+            }
+            Syscall::PerfEventIoctlPtr { fd, request, arg } => {
+                backend.perf_event_ioctl(fd, request, arg as c_ulong)
+            }
+        }
+    }
+    .map_err(|io_error| (-1, io_error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, os::fd::AsFd as _, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Broker {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl SyscallBackend for Broker {
+        unsafe fn bpf(&self, cmd: bpf_cmd, _attr: &mut bpf_attr) -> io::Result<c_long> {
+            self.calls.lock().unwrap().push(format!("bpf {cmd:?}"));
+            Ok(0)
+        }
+
+        fn perf_event_open(
+            &self,
+            _attr: &perf_event_attr,
+            pid: pid_t,
+            cpu: c_int,
+            _group_fd: c_int,
+            _flags: u32,
+        ) -> io::Result<c_long> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("perf_event_open {pid} {cpu}"));
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        }
+
+        unsafe fn perf_event_ioctl(
+            &self,
+            _fd: BorrowedFd<'_>,
+            request: c_int,
+            arg: c_ulong,
+        ) -> io::Result<c_long> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("ioctl {request} {arg}"));
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let broker = Broker::default();
+        let mut attr = unsafe { mem::zeroed::<bpf_attr>() };
+        assert_eq!(
+            dispatch(
+                &broker,
+                Syscall::Bpf {
+                    cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                    attr: &mut attr,
+                },
+            )
+            .unwrap(),
+            0
+        );
+        let (ret, io_error) = dispatch(
+            &broker,
+            Syscall::PerfEventOpen {
+                attr: unsafe { mem::zeroed() },
+                pid: -1,
+                cpu: 3,
+                group: -1,
+                flags: 0,
+            },
+        )
+        .unwrap_err();
+        assert_eq!((ret, io_error.raw_os_error()), (-1, Some(libc::EACCES)));
+        let stdin = io::stdin();
+        dispatch(
+            &broker,
+            Syscall::PerfEventIoctl {
+                fd: stdin.as_fd(),
+                request: 9216,
+                arg: 1,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            *broker.calls.lock().unwrap(),
+            [
+                "bpf BPF_MAP_UPDATE_ELEM",
+                "perf_event_open -1 3",
+                "ioctl 9216 1",
+            ]
+        );
+    }
+}
@@ -30,7 +30,7 @@ use crate::{
         copy_instructions,
     },
     sys::{syscall, SysResult, Syscall, SyscallError},
-    util::KernelVersion,
+    util::{page_size, KernelVersion},
     Btf, Pod, VerifierLogLevel, BPF_OBJ_NAME_LEN,
 };
 
@@ -290,6 +290,57 @@ pub(crate) fn bpf_map_update_elem<K: Pod, V: Pod>(
     sys_bpf(bpf_cmd::BPF_MAP_UPDATE_ELEM, &mut attr)
 }
 
+pub(crate) fn bpf_map_lookup_batch<B: Pod, K: Pod, V: Pod>(
+    fd: BorrowedFd<'_>,
+    in_batch: Option<&B>,
+    out_batch: &mut B,
+    keys: &mut [MaybeUninit<K>],
+    values: &mut [MaybeUninit<V>],
+    flags: u64,
+) -> Result<(usize, bool), (c_long, io::Error)> {
+    debug_assert_eq!(keys.len(), values.len());
+    let mut attr = unsafe { mem::zeroed::<bpf_attr>() };
+
+    let u = unsafe { &mut attr.batch };
+    u.map_fd = fd.as_raw_fd() as u32;
+    if let Some(in_batch) = in_batch {
+        u.in_batch = in_batch as *const _ as u64;
+    }
+    u.out_batch = out_batch as *mut _ as u64;
+    u.keys = keys.as_mut_ptr() as u64;
+    u.values = values.as_mut_ptr() as u64;
+    u.count = keys.len() as u32;
+    u.elem_flags = flags;
+
+    // the kernel returns ENOENT after copying the last entries of the map
+    match sys_bpf(bpf_cmd::BPF_MAP_LOOKUP_BATCH, &mut attr) {
+        Ok(_) => Ok((unsafe { attr.batch.count } as usize, false)),
+        Err((_, io_error)) if io_error.raw_os_error() == Some(ENOENT) => {
+            Ok((unsafe { attr.batch.count } as usize, true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn bpf_map_update_batch<K: Pod, V: Pod>(
+    fd: BorrowedFd<'_>,
+    keys: &[K],
+    values: &[V],
+    flags: u64,
+) -> SysResult<c_long> {
+    debug_assert_eq!(keys.len(), values.len());
+    let mut attr = unsafe { mem::zeroed::<bpf_attr>() };
+
+    let u = unsafe { &mut attr.batch };
+    u.map_fd = fd.as_raw_fd() as u32;
+    u.keys = keys.as_ptr() as u64;
+    u.values = values.as_ptr() as u64;
+    u.count = keys.len() as u32;
+    u.elem_flags = flags;
+
+    sys_bpf(bpf_cmd::BPF_MAP_UPDATE_BATCH, &mut attr)
+}
+
 pub(crate) fn bpf_map_push_elem<V: Pod>(
     fd: BorrowedFd<'_>,
     value: &V,
@@ -831,6 +882,42 @@ pub(crate) fn is_prog_id_supported(map_type: bpf_map_type) -> bool {
     unsafe { fd_sys_bpf(bpf_cmd::BPF_MAP_CREATE, &mut attr) }.is_ok()
 }
 
+pub(crate) fn is_ring_buf_supported() -> bool {
+    let mut attr = unsafe { mem::zeroed::<bpf_attr>() };
+    let u = unsafe { &mut attr.__bindgen_anon_1 };
+
+    u.map_type = bpf_map_type::BPF_MAP_TYPE_RINGBUF as u32;
+    u.max_entries = page_size() as u32;
+
+    // SAFETY: BPF_MAP_CREATE returns a new file descriptor.
+    unsafe { fd_sys_bpf(bpf_cmd::BPF_MAP_CREATE, &mut attr) }.is_ok()
+}
+
+/// Tests whether the batched map syscalls are supported, using a hash map.
+pub(crate) fn is_map_batch_supported() -> bool {
+    let mut attr = unsafe { mem::zeroed::<bpf_attr>() };
+    let u = unsafe { &mut attr.__bindgen_anon_1 };
+
+    u.map_type = bpf_map_type::BPF_MAP_TYPE_HASH as u32;
+    u.key_size = 4;
+    u.value_size = 4;
+    u.max_entries = 1;
+
+    // SAFETY: BPF_MAP_CREATE returns a new file descriptor.
+    let fd = match unsafe { fd_sys_bpf(bpf_cmd::BPF_MAP_CREATE, &mut attr) } {
+        Ok(fd) => fd,
+        Err(_) => return false,
+    };
+    let mut out_batch = 0u32;
+    let mut keys = [MaybeUninit::<u32>::uninit()];
+    let mut values = [MaybeUninit::<u32>::uninit()];
+    // Returns ENOENT for the empty map if supported, EINVAL if unsupported.
+    matches!(
+        bpf_map_lookup_batch(fd.as_fd(), None, &mut out_batch, &mut keys, &mut values, 0),
+        Ok((0, true))
+    )
+}
+
 pub(crate) fn is_btf_supported() -> bool {
     let mut btf = Btf::new();
     let name_offset = btf.add_string("int");
@@ -1139,4 +1226,97 @@ bpf_map_type::BPF_MAP_TYPE_DEVMAP_HASH`"]
     fn test_prog_id_supported_reject_types() {
         is_prog_id_supported(bpf_map_type::BPF_MAP_TYPE_HASH);
     }
+
+    #[test]
+    fn test_map_lookup_and_delete_elem() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_AND_DELETE_ELEM,
+                attr,
+            } => {
+                let u = unsafe { &attr.__bindgen_anon_2 };
+                assert_eq!(u.map_fd, 42);
+                match unsafe { *(u.key as *const u32) } {
+                    1 => {
+                        unsafe { *(u.__bindgen_anon_1.value as *mut u64) = 1234 };
+                        Ok(0)
+                    }
+                    _ => Err((-1, io::Error::from_raw_os_error(ENOENT))),
+                }
+            }
+            _ => Err((-1, io::Error::from_raw_os_error(EINVAL))),
+        });
+        let fd = unsafe { BorrowedFd::borrow_raw(42) };
+
+        assert_eq!(
+            bpf_map_lookup_and_delete_elem::<u32, u64>(fd, Some(&1), 0).unwrap(),
+            Some(1234)
+        );
+        assert_eq!(
+            bpf_map_lookup_and_delete_elem::<u32, u64>(fd, Some(&2), 0).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_map_get_next_key() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_GET_NEXT_KEY,
+                attr,
+            } => {
+                let u = unsafe { &attr.__bindgen_anon_2 };
+                // keys are 10 and 20, a null key starts from the first one
+                let next_key = match u.key {
+                    0 => 10,
+                    key => match unsafe { *(key as *const u32) } {
+                        10 => 20,
+                        _ => return Err((-1, io::Error::from_raw_os_error(ENOENT))),
+                    },
+                };
+                unsafe { *(u.__bindgen_anon_1.next_key as *mut u32) = next_key };
+                Ok(0)
+            }
+            _ => Err((-1, io::Error::from_raw_os_error(EINVAL))),
+        });
+        let fd = unsafe { BorrowedFd::borrow_raw(42) };
+
+        assert_eq!(bpf_map_get_next_key::<u32>(fd, None).unwrap(), Some(10));
+        assert_eq!(bpf_map_get_next_key(fd, Some(&10u32)).unwrap(), Some(20));
+        assert_eq!(bpf_map_get_next_key(fd, Some(&20u32)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ring_buf_supported() {
+        override_syscall(|_call| Ok(42));
+        assert!(is_ring_buf_supported());
+
+        override_syscall(|_call| Err((-1, io::Error::from_raw_os_error(EINVAL))));
+        assert!(!is_ring_buf_supported());
+    }
+
+    #[test]
+    fn test_map_batch_supported() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => {
+                // the kernel reports that no entries were copied from the empty map
+                attr.batch.count = 0;
+                Err((-1, io::Error::from_raw_os_error(ENOENT)))
+            }
+            _ => Ok(42),
+        });
+        assert!(is_map_batch_supported());
+
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                ..
+            } => Err((-1, io::Error::from_raw_os_error(EINVAL))),
+            _ => Ok(42),
+        });
+        assert!(!is_map_batch_supported());
+    }
 }
@@ -1,3 +1,4 @@
+mod backend;
 mod bpf;
 mod netlink;
 mod perf_event;
@@ -11,6 +12,7 @@ use std::{
     os::fd::{AsRawFd as _, BorrowedFd},
 };
 
+pub use backend::{set_syscall_backend, SyscallBackend};
 pub(crate) use bpf::*;
 #[cfg(test)]
 pub(crate) use fake::*;
@@ -42,6 +44,11 @@ pub(crate) enum Syscall<'a> {
         request: c_int,
         arg: c_int,
     },
+    PerfEventIoctlPtr {
+        fd: BorrowedFd<'a>,
+        request: c_int,
+        arg: *mut c_void,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -82,6 +89,12 @@ impl std::fmt::Debug for Syscall<'_> {
                 .field("request", request)
                 .field("arg", arg)
                 .finish(),
+            Self::PerfEventIoctlPtr { fd, request, arg } => f
+                .debug_struct("Syscall::PerfEventIoctlPtr")
+                .field("fd", fd)
+                .field("request", request)
+                .field("arg", arg)
+                .finish(),
         }
     }
 }
@@ -91,6 +104,11 @@ fn syscall(call: Syscall<'_>) -> SysResult<c_long> {
     return TEST_SYSCALL.with(|test_impl| unsafe { test_impl.borrow()(call) });
 
     #[cfg_attr(test, allow(unreachable_code))]
+    backend::backend_syscall(call)
+}
+
+// Makes the syscall directly, bypassing the installed backend.
+fn native_syscall(call: Syscall<'_>) -> SysResult<c_long> {
     match unsafe {
         match call {
             Syscall::Bpf { cmd, attr } => {
@@ -109,6 +127,15 @@ fn syscall(call: Syscall<'_>) -> SysResult<c_long> {
                 let int = int as c_long;
                 int
             }
+            Syscall::PerfEventIoctlPtr { fd, request, arg } => {
+                // the kernel takes the request as an unsigned int, requests with the direction
+                // bits set are negative as c_int
+                #[allow(trivial_numeric_casts)]
+                let int = libc::ioctl(fd.as_raw_fd(), request as u32 as _, arg);
+                #[allow(trivial_numeric_casts)]
+                let int = int as c_long;
+                int
+            }
         }
     } {
         ret @ 0.. => Ok(ret),
@@ -131,3 +158,13 @@ pub(crate) unsafe fn mmap(
     #[cfg(test)]
     TEST_MMAP_RET.with(|ret| *ret.borrow())
 }
+
+#[cfg_attr(test, allow(unused_variables))]
+pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) -> c_int {
+    #[cfg(not(test))]
+    return libc::munmap(addr, len);
+
+    // the memory returned by the fake mmap() belongs to the tests
+    #[cfg(test)]
+    0
+}
@@ -1,18 +1,18 @@
 use std::{
-    ffi::{c_int, c_long, CString, OsStr},
+    ffi::{c_int, c_long, c_void, CString, OsStr},
     io, mem,
-    os::fd::{BorrowedFd, FromRawFd as _, OwnedFd},
+    os::fd::{AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd},
 };
 
 use libc::pid_t;
 
 use super::{syscall, SysResult, Syscall};
-use crate::generated::{
-    perf_event_attr,
-    perf_event_sample_format::PERF_SAMPLE_RAW,
-    perf_sw_ids::PERF_COUNT_SW_BPF_OUTPUT,
-    perf_type_id::{PERF_TYPE_SOFTWARE, PERF_TYPE_TRACEPOINT},
-    PERF_FLAG_FD_CLOEXEC,
+use crate::{
+    generated::{
+        perf_event_attr, perf_event_sample_format::PERF_SAMPLE_RAW,
+        perf_type_id::PERF_TYPE_TRACEPOINT, PERF_FLAG_FD_CLOEXEC, PERF_FLAG_FD_OUTPUT,
+    },
+    PERF_EVENT_IOC_ID,
 };
 
 #[allow(clippy::too_many_arguments)]
@@ -42,19 +42,28 @@ pub(crate) fn perf_event_open(
         attr.__bindgen_anon_1.sample_period = sample_period;
     }
 
-    perf_event_sys(attr, pid, cpu, flags)
+    perf_event_sys(attr, pid, cpu, -1, flags)
 }
 
-pub(crate) fn perf_event_open_bpf(cpu: c_int) -> SysResult<OwnedFd> {
-    perf_event_open(
-        PERF_TYPE_SOFTWARE as u32,
-        PERF_COUNT_SW_BPF_OUTPUT as u64,
+pub(crate) fn perf_event_open_attr(
+    attr: perf_event_attr,
+    pid: pid_t,
+    cpu: c_int,
+) -> SysResult<OwnedFd> {
+    perf_event_sys(attr, pid, cpu, -1, PERF_FLAG_FD_CLOEXEC)
+}
+
+pub(crate) fn perf_event_open_attr_output(
+    attr: perf_event_attr,
+    cpu: c_int,
+    output: BorrowedFd<'_>,
+) -> SysResult<OwnedFd> {
+    perf_event_sys(
+        attr,
         -1,
         cpu,
-        1,
-        None,
-        true,
-        PERF_FLAG_FD_CLOEXEC,
+        output.as_raw_fd(),
+        PERF_FLAG_FD_CLOEXEC | PERF_FLAG_FD_OUTPUT,
     )
 }
 
@@ -83,7 +92,7 @@ pub(crate) fn perf_event_open_probe(
     let cpu = if pid.is_some() { -1 } else { 0 };
     let pid = pid.unwrap_or(-1);
 
-    perf_event_sys(attr, pid, cpu, PERF_FLAG_FD_CLOEXEC)
+    perf_event_sys(attr, pid, cpu, -1, PERF_FLAG_FD_CLOEXEC)
 }
 
 pub(crate) fn perf_event_open_trace_point(id: u32, pid: Option<pid_t>) -> SysResult<OwnedFd> {
@@ -96,7 +105,7 @@ pub(crate) fn perf_event_open_trace_point(id: u32, pid: Option<pid_t>) -> SysRes
     let cpu = if pid.is_some() { -1 } else { 0 };
     let pid = pid.unwrap_or(-1);
 
-    perf_event_sys(attr, pid, cpu, PERF_FLAG_FD_CLOEXEC)
+    perf_event_sys(attr, pid, cpu, -1, PERF_FLAG_FD_CLOEXEC)
 }
 
 pub(crate) fn perf_event_ioctl(
@@ -112,12 +121,28 @@ pub(crate) fn perf_event_ioctl(
     return crate::sys::TEST_SYSCALL.with(|test_impl| unsafe { test_impl.borrow()(call) });
 }
 
-fn perf_event_sys(attr: perf_event_attr, pid: pid_t, cpu: i32, flags: u32) -> SysResult<OwnedFd> {
+pub(crate) fn perf_event_ioctl_id(fd: BorrowedFd<'_>) -> SysResult<u64> {
+    let mut id = 0u64;
+    syscall(Syscall::PerfEventIoctlPtr {
+        fd,
+        request: PERF_EVENT_IOC_ID,
+        arg: &mut id as *mut u64 as *mut c_void,
+    })?;
+    Ok(id)
+}
+
+fn perf_event_sys(
+    attr: perf_event_attr,
+    pid: pid_t,
+    cpu: i32,
+    group: i32,
+    flags: u32,
+) -> SysResult<OwnedFd> {
     let fd = syscall(Syscall::PerfEventOpen {
         attr,
         pid,
         cpu,
-        group: -1,
+        group,
         flags,
     })?;
 
@@ -5,7 +5,7 @@ use std::{
     ffi::{CStr, CString},
     fs::{self, File},
     io::{self, BufRead, BufReader},
-    mem,
+    mem::{self, size_of},
     num::ParseIntError,
     slice,
     str::{FromStr, Utf8Error},
@@ -202,6 +202,32 @@ pub(crate) fn possible_cpus() -> Result<Vec<u32>, io::Error> {
     })
 }
 
+/// Pins the calling thread to `cpu`.
+///
+/// Draining a per-CPU buffer from a thread running on the same CPU keeps its pages local to
+/// the reader, see
+/// [`PerfEventArray::open_all_online`](crate::maps::PerfEventArray::open_all_online) for an
+/// example. The affinity of the other threads of the process isn't changed.
+pub fn bind_current_thread_to_cpu(cpu: u32) -> Result<(), io::Error> {
+    let cpu = cpu as usize;
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("CPU {cpu} is out of range"),
+        ));
+    }
+    // Safety: cpu_set_t is a plain bit mask, and `cpu` is within its bounds.
+    let ret = unsafe {
+        let mut set = mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 fn parse_cpu_ranges(data: &str) -> Result<Vec<u32>, ()> {
     let mut cpus = Vec::new();
     for range in data.split(',') {
@@ -391,6 +417,16 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_bind_current_thread_to_cpu_out_of_range() {
+        assert_eq!(
+            bind_current_thread_to_cpu(libc::CPU_SETSIZE as u32)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
     #[test]
     fn test_parse_online_cpus() {
         assert_eq!(parse_cpu_ranges("0").unwrap(), vec![0]);